@@ -1,12 +1,10 @@
 #![allow(clippy::derive_partial_eq_without_eq)] // Since we may not be able to implement Eq for the changes coming from Firestore protos
 
-use crate::{FirestoreDb, FirestoreError, FirestoreQueryParams, FirestoreResult};
+use crate::db::query_ordering::reject_limit_to_last;
+use crate::{FirestoreDb, FirestoreQueryParams, FirestoreResult};
 use async_trait::async_trait;
 use chrono::prelude::*;
-use futures::future::BoxFuture;
 use futures::stream::BoxStream;
-use futures::FutureExt;
-use futures::TryFutureExt;
 use futures::TryStreamExt;
 use futures::{future, StreamExt};
 use gcloud_sdk::google::firestore::v1::*;
@@ -38,6 +36,8 @@ impl From<&FirestoreAggregation> for structured_aggregation_query::Aggregation {
 #[derive(Debug, PartialEq, Clone)]
 pub enum FirestoreAggregationOperator {
     Count(FirestoreAggregationOperatorCount),
+    Sum(FirestoreAggregationOperatorSum),
+    Avg(FirestoreAggregationOperatorAvg),
 }
 
 impl From<&FirestoreAggregationOperator> for structured_aggregation_query::aggregation::Operator {
@@ -46,6 +46,12 @@ impl From<&FirestoreAggregationOperator> for structured_aggregation_query::aggre
             FirestoreAggregationOperator::Count(cnt) => {
                 structured_aggregation_query::aggregation::Operator::Count(cnt.into())
             }
+            FirestoreAggregationOperator::Sum(sum) => {
+                structured_aggregation_query::aggregation::Operator::Sum(sum.into())
+            }
+            FirestoreAggregationOperator::Avg(avg) => {
+                structured_aggregation_query::aggregation::Operator::Avg(avg.into())
+            }
         }
     }
 }
@@ -63,6 +69,36 @@ impl From<&FirestoreAggregationOperatorCount> for structured_aggregation_query::
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Builder)]
+pub struct FirestoreAggregationOperatorSum {
+    pub field_name: String,
+}
+
+impl From<&FirestoreAggregationOperatorSum> for structured_aggregation_query::aggregation::Sum {
+    fn from(sum: &FirestoreAggregationOperatorSum) -> Self {
+        structured_aggregation_query::aggregation::Sum {
+            field: Some(structured_query::FieldReference {
+                field_path: sum.field_name.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Builder)]
+pub struct FirestoreAggregationOperatorAvg {
+    pub field_name: String,
+}
+
+impl From<&FirestoreAggregationOperatorAvg> for structured_aggregation_query::aggregation::Avg {
+    fn from(avg: &FirestoreAggregationOperatorAvg) -> Self {
+        structured_aggregation_query::aggregation::Avg {
+            field: Some(structured_query::FieldReference {
+                field_path: avg.field_name.clone(),
+            }),
+        }
+    }
+}
+
 #[async_trait]
 pub trait FirestoreAggregatedQuerySupport {
     async fn aggregated_query_doc(
@@ -117,8 +153,7 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
             "/firestore/collection_name" = collection_str.as_str(),
             "/firestore/response_time" = field::Empty
         );
-        self.aggregated_query_doc_with_retries(params, 0, &span)
-            .await
+        self.aggregated_query_doc_with_retries(params, &span).await
     }
 
     async fn stream_aggregated_query_doc<'b>(
@@ -135,7 +170,7 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
         );
 
         let doc_stream = self
-            .stream_aggregated_query_doc_with_retries(params, 0, &span)
+            .stream_aggregated_query_doc_with_retries(params, &span)
             .await?;
 
         Ok(Box::pin(doc_stream.filter_map(|doc_res| {
@@ -164,7 +199,7 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
         );
 
         let doc_stream = self
-            .stream_aggregated_query_doc_with_retries(params, 0, &span)
+            .stream_aggregated_query_doc_with_retries(params, &span)
             .await?;
 
         Ok(Box::pin(doc_stream.filter_map(|doc_res| {
@@ -235,7 +270,9 @@ impl FirestoreDb {
         &self,
         params: FirestoreAggregatedQueryParams,
     ) -> FirestoreResult<tonic::Request<RunAggregationQueryRequest>> {
-        Ok(tonic::Request::new(RunAggregationQueryRequest {
+        params.query_params.validate()?;
+        reject_limit_to_last(&params.query_params, "aggregations")?;
+        Ok(self.create_request(RunAggregationQueryRequest {
             parent: params
                 .query_params
                 .parent
@@ -243,11 +280,7 @@ impl FirestoreDb {
                 .unwrap_or_else(|| self.get_documents_path())
                 .clone(),
             consistency_selector: self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?,
+                .request_consistency_selector(params.query_params.read_time)?,
             query_type: Some(run_aggregation_query_request::QueryType::StructuredAggregationQuery(
                 StructuredAggregationQuery {
                     aggregations: params.aggregations.iter().map(|agg| agg.into()).collect(),
@@ -257,129 +290,94 @@ impl FirestoreDb {
         }))
     }
 
-    fn stream_aggregated_query_doc_with_retries<'a, 'b>(
-        &'a self,
+    async fn stream_aggregated_query_doc_with_retries<'b>(
+        &self,
         params: FirestoreAggregatedQueryParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<BoxStream<'b, FirestoreResult<Option<Document>>>>> {
-        async move {
-            let query_request = self.create_aggregated_query_request(params.clone())?;
-            let begin_query_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .run_aggregation_query(query_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(query_response) => {
-                    let query_stream = query_response
-                        .into_inner()
-                        .map_ok(Self::aggregated_response_to_doc)
-                        .map_err(|e| e.into())
-                        .boxed();
+        span: &Span,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Option<Document>>>> {
+        let begin_query_utc: DateTime<Utc> = Utc::now();
+
+        let query_response = self
+            .with_retries(|| {
+                let query_request = self.create_aggregated_query_request(params.clone());
+                async move {
+                    Ok(self
+                        .client()
+                        .get()
+                        .run_aggregation_query(query_request?)
+                        .await?)
+                }
+            })
+            .await?;
 
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+        let query_stream = query_response
+            .into_inner()
+            .map_ok(Self::aggregated_response_to_doc)
+            .map_err(|e| e.into())
+            .boxed();
 
-                    span.record(
-                        "/firestore/response_time",
-                        query_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Querying stream of documents in {:?} took {}ms",
-                            params.query_params.collection_id,
-                            query_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(query_stream)
-                }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-
-                        self.stream_aggregated_query_doc_with_retries(params, retries + 1, span)
-                            .await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+
+        span.record(
+            "/firestore/response_time",
+            query_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Querying stream of documents in {:?} took {}ms",
+                params.query_params.collection_id,
+                query_duration.num_milliseconds()
+            );
+        });
+
+        Ok(query_stream)
     }
 
-    fn aggregated_query_doc_with_retries<'a>(
-        &'a self,
+    async fn aggregated_query_doc_with_retries(
+        &self,
         params: FirestoreAggregatedQueryParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<Vec<Document>>> {
-        async move {
-            let query_request = self.create_aggregated_query_request(params.clone())?;
-            let begin_query_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .run_aggregation_query(query_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(query_response) => {
-                    let query_stream = query_response
+        span: &Span,
+    ) -> FirestoreResult<Vec<Document>> {
+        let begin_query_utc: DateTime<Utc> = Utc::now();
+
+        // Errors while reading the response stream are retried as well
+        let query_result: Vec<Document> = self
+            .with_retries(|| {
+                let query_request = self.create_aggregated_query_request(params.clone());
+                async move {
+                    Ok(self
+                        .client()
+                        .get()
+                        .run_aggregation_query(query_request?)
+                        .await?
                         .into_inner()
                         .map_ok(Self::aggregated_response_to_doc)
                         .try_collect::<Vec<Option<Document>>>()
                         .await?
                         .into_iter()
                         .flatten()
-                        .collect();
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
-
-                    span.record(
-                        "/firestore/response_time",
-                        query_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Querying documents in {:?} took {}ms",
-                            params.query_params.collection_id,
-                            query_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(query_stream)
+                        .collect())
                 }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-                        self.aggregated_query_doc_with_retries(params, retries + 1, span)
-                            .await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+            })
+            .await?;
+
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+
+        span.record(
+            "/firestore/response_time",
+            query_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Querying documents in {:?} took {}ms",
+                params.query_params.collection_id,
+                query_duration.num_milliseconds()
+            );
+        });
+
+        Ok(query_result)
     }
 
     fn aggregated_response_to_doc(mut agg_res: RunAggregationQueryResponse) -> Option<Document> {
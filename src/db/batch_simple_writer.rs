@@ -1,11 +1,13 @@
+use crate::db::missing_write_result_status;
 use crate::errors::*;
 use crate::{
     FirestoreBatch, FirestoreBatchWriteResponse, FirestoreBatchWriter, FirestoreDb,
-    FirestoreResult, FirestoreWriteResult,
+    FirestoreResult, FirestoreWriteResult, FIRESTORE_MAX_WRITES_PER_REQUEST,
 };
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use gcloud_sdk::google::firestore::v1::{BatchWriteRequest, Write};
+use gcloud_sdk::google::rpc::Status;
 use rsb_derive::*;
 use std::collections::HashMap;
 use tracing::*;
@@ -13,6 +15,16 @@ use tracing::*;
 #[derive(Debug, Eq, PartialEq, Clone, Builder)]
 pub struct FirestoreSimpleBatchWriteOptions {
     retry_max_elapsed_time: Option<chrono::Duration>,
+
+    /// Larger batches are split into several requests.
+    /// The requests are not atomic together: if a later request fails, the writes of the
+    /// previous ones have already been applied, even though the whole call returns an error.
+    #[default = "FIRESTORE_MAX_WRITES_PER_REQUEST"]
+    pub max_writes_per_request: usize,
+
+    /// Retries only the failed writes with retryable errors using the retry policy of the database
+    #[default = "false"]
+    pub retry_failed_writes: bool,
 }
 
 pub struct FirestoreSimpleBatchWriter {
@@ -45,6 +57,105 @@ impl FirestoreBatchWriter for FirestoreSimpleBatchWriter {
     type WriteResult = FirestoreBatchWriteResponse;
 
     async fn write(&self, writes: Vec<Write>) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        let mut write_results = Vec::with_capacity(writes.len());
+        let mut statuses = Vec::with_capacity(writes.len());
+
+        // The chunks are written in order, so the results keep the positions of the writes
+        for chunk in writes.chunks(self.options.max_writes_per_request.max(1)) {
+            let chunk_response = if self.options.retry_failed_writes {
+                self.write_chunk_retrying_failed(chunk.to_vec()).await?
+            } else {
+                self.write_chunk(chunk.to_vec()).await?
+            };
+            write_results.extend(chunk_response.write_results);
+            statuses.extend(chunk_response.statuses);
+        }
+
+        Ok(FirestoreBatchWriteResponse::new(0, write_results, statuses))
+    }
+
+    async fn write_with_keys(
+        &self,
+        writes: Vec<Write>,
+        write_keys: Vec<Option<String>>,
+    ) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        Ok(self.write(writes).await?.with_write_keys(write_keys))
+    }
+}
+
+impl FirestoreSimpleBatchWriter {
+    async fn write_chunk_retrying_failed(
+        &self,
+        writes: Vec<Write>,
+    ) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        let mut response = self.write_chunk(writes.clone()).await?;
+        let mut retries = 0;
+
+        loop {
+            let mut retry_delay = None;
+            let failed_positions: Vec<usize> = response
+                .statuses
+                .iter()
+                .enumerate()
+                .filter(|(position, status)| {
+                    *position < writes.len()
+                        && tonic::Code::from_i32(status.code) != tonic::Code::Ok
+                })
+                .filter_map(|(position, status)| {
+                    let delay = self
+                        .db
+                        .get_retry_policy()
+                        .retry_delay(&FirestoreError::from(status.clone()), retries)?;
+                    retry_delay = retry_delay.max(Some(delay));
+                    Some(position)
+                })
+                .collect();
+
+            let retry_delay = match retry_delay {
+                Some(retry_delay) if !failed_positions.is_empty() => retry_delay,
+                _ => return Ok(response),
+            };
+
+            retries += 1;
+            warn!(
+                "[DB]: {} writes failed in a batch. Retrying them in {:?}: {}",
+                failed_positions.len(),
+                retry_delay,
+                retries
+            );
+            tokio::time::sleep(retry_delay).await;
+
+            let retry_response = self
+                .write_chunk(
+                    failed_positions
+                        .iter()
+                        .map(|position| writes[*position].clone())
+                        .collect(),
+                )
+                .await?;
+
+            // Mapping the results of the retried writes back to their positions in the batch
+            for (retry_position, position) in failed_positions.into_iter().enumerate() {
+                if let (Some(write_result), Some(target)) = (
+                    retry_response.write_results.get(retry_position),
+                    response.write_results.get_mut(position),
+                ) {
+                    *target = write_result.clone();
+                }
+                if let (Some(status), Some(target)) = (
+                    retry_response.statuses.get(retry_position),
+                    response.statuses.get_mut(position),
+                ) {
+                    *target = status.clone();
+                }
+            }
+        }
+    }
+
+    async fn write_chunk(
+        &self,
+        writes: Vec<Write>,
+    ) -> FirestoreResult<FirestoreBatchWriteResponse> {
         let backoff = backoff::ExponentialBackoffBuilder::new()
             .with_max_elapsed_time(
                 self.options
@@ -54,6 +165,7 @@ impl FirestoreBatchWriter for FirestoreSimpleBatchWriter {
             )
             .build();
 
+        let writes_count = writes.len();
         let request = BatchWriteRequest {
             database: self.db.get_database_path().to_string(),
             writes,
@@ -66,23 +178,30 @@ impl FirestoreBatchWriter for FirestoreSimpleBatchWriter {
                     .db
                     .client()
                     .get()
-                    .batch_write(request.clone())
+                    .batch_write(self.db.create_request(request.clone()))
                     .await
                     .map_err(FirestoreError::from)?;
 
                 let batch_response = response.into_inner();
 
-                let write_results: FirestoreResult<Vec<FirestoreWriteResult>> = batch_response
+                let mut write_results: Vec<FirestoreWriteResult> = batch_response
                     .write_results
                     .into_iter()
                     .map(|s| s.try_into())
-                    .collect();
+                    .collect::<FirestoreResult<Vec<FirestoreWriteResult>>>()?;
+                let mut statuses = batch_response.status;
+
+                // The writes without results are reported as failed,
+                // so the results of the next chunks keep their positions
+                statuses.resize(writes_count, Status::default());
+                for status in statuses.iter_mut().skip(write_results.len()) {
+                    if tonic::Code::from_i32(status.code) == tonic::Code::Ok {
+                        *status = missing_write_result_status();
+                    }
+                }
+                write_results.resize(writes_count, FirestoreWriteResult::new(vec![]));
 
-                Ok(FirestoreBatchWriteResponse::new(
-                    0,
-                    write_results?,
-                    batch_response.status,
-                ))
+                Ok(FirestoreBatchWriteResponse::new(0, write_results, statuses))
             }
             .map_err(firestore_err_to_backoff)
         })
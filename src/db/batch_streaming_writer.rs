@@ -1,41 +1,69 @@
+use crate::errors::*;
+use crate::timestamp_utils::from_timestamp;
 use crate::{
     FirestoreBatch, FirestoreBatchWriteResponse, FirestoreBatchWriter, FirestoreDb,
-    FirestoreResult, FirestoreWriteResult,
+    FirestoreResult, FirestoreWriteResult, FIRESTORE_MAX_WRITES_PER_REQUEST,
 };
 use async_trait::async_trait;
+use futures::future::Either;
 use futures::stream::BoxStream;
-use futures::{StreamExt, TryStreamExt};
-use gcloud_sdk::google::firestore::v1::{Write, WriteRequest};
+use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::{Write, WriteRequest, WriteResponse};
 use rsb_derive::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
-use tonic::Code;
-
-use crate::timestamp_utils::from_timestamp;
 use tracing::*;
 
 #[derive(Debug, Eq, PartialEq, Clone, Builder)]
 pub struct FirestoreStreamingBatchWriteOptions {
     #[default = "Duration::from_millis(500)"]
     pub throttle_batch_duration: Duration,
+
+    /// Larger batches are split into several requests
+    #[default = "FIRESTORE_MAX_WRITES_PER_REQUEST"]
+    pub max_writes_per_request: usize,
+
+    /// Writing waits while there are more writes not acknowledged by the server.
+    /// After a reconnect these writes are sent again, so the non-idempotent ones
+    /// (like increments or array appends) may be applied twice.
+    #[default = "FIRESTORE_MAX_WRITES_PER_REQUEST * 10"]
+    pub max_unacknowledged_writes: usize,
+
+    /// Writing waits while there are more responses not read from the responses stream,
+    /// so the responses have to be read while writing
+    #[default = "100"]
+    pub max_unread_responses: usize,
+}
+
+/// A write request sent as a part of a batch, kept until the server acknowledges it
+struct FirestoreStreamingBatchChunk {
+    batch_position: u64,
+    last_in_batch: bool,
+    writes: Vec<Write>,
+    /// The position of the request in the current stream, which has a response at the same position
+    stream_position: u64,
+    _permit: OwnedSemaphorePermit,
+}
+
+enum FirestoreStreamingBatchCommand {
+    Write(FirestoreStreamingBatchChunk),
+    Finish,
 }
 
 pub struct FirestoreStreamingBatchWriter {
     pub db: FirestoreDb,
     pub options: FirestoreStreamingBatchWriteOptions,
     pub batch_span: Span,
-    finished: Arc<AtomicBool>,
-    writer: UnboundedSender<WriteRequest>,
+    finished: AtomicBool,
+    commands: UnboundedSender<FirestoreStreamingBatchCommand>,
     thread: Option<JoinHandle<()>>,
-    last_token: Arc<RwLock<Vec<u8>>>,
-    sent_counter: Arc<AtomicU64>,
-    received_counter: Arc<AtomicU64>,
-    init_wait_reader: UnboundedReceiver<()>,
+    batch_counter: AtomicU64,
+    unacknowledged_writes: Arc<Semaphore>,
 }
 
 impl Drop for FirestoreStreamingBatchWriter {
@@ -56,203 +84,69 @@ impl FirestoreStreamingBatchWriter {
     )> {
         let batch_span = span!(Level::DEBUG, "Firestore Batch Write");
 
-        let (requests_writer, requests_receiver) = mpsc::unbounded_channel::<WriteRequest>();
-        let (responses_writer, responses_receiver) =
-            mpsc::unbounded_channel::<FirestoreResult<FirestoreBatchWriteResponse>>();
-        let (init_wait_sender, mut init_wait_reader) = mpsc::unbounded_channel::<()>();
-
-        let finished = Arc::new(AtomicBool::new(false));
-        let thread_finished = finished.clone();
-
-        let sent_counter = Arc::new(AtomicU64::new(0));
-        let thread_sent_counter = sent_counter.clone();
-
-        let received_counter = Arc::new(AtomicU64::new(0));
-        let thread_received_counter = received_counter.clone();
+        let (commands_writer, commands_receiver) =
+            mpsc::unbounded_channel::<FirestoreStreamingBatchCommand>();
+        let (responses_writer, responses_receiver) = mpsc::channel::<
+            FirestoreResult<FirestoreBatchWriteResponse>,
+        >(options.max_unread_responses.max(1));
+        let (init_wait_sender, init_wait_reader) = oneshot::channel::<FirestoreResult<()>>();
 
-        let last_token: Arc<RwLock<Vec<u8>>> = Arc::new(RwLock::new(vec![]));
-        let thread_last_token = last_token.clone();
-
-        let mut thread_db_client = db.client().get();
-        let thread_options = options.clone();
-
-        let thread = tokio::spawn(async move {
-            let stream = {
-                use tokio_stream::StreamExt;
-                tokio_stream::wrappers::UnboundedReceiverStream::new(requests_receiver)
-                    .throttle(thread_options.throttle_batch_duration)
-            };
-            match thread_db_client.write(stream).await {
-                Ok(response) => {
-                    let mut response_stream = response.into_inner().boxed();
-                    loop {
-                        let response_result = response_stream.try_next().await;
-                        let received_counter = thread_received_counter.load(Ordering::Relaxed);
-
-                        match response_result {
-                            Ok(Some(response)) => {
-                                {
-                                    let mut locked = thread_last_token.write().await;
-                                    *locked = response.stream_token;
-                                }
-
-                                if received_counter == 0 {
-                                    init_wait_sender.send(()).ok();
-                                } else {
-                                    let write_results: FirestoreResult<Vec<FirestoreWriteResult>> =
-                                        response
-                                            .write_results
-                                            .into_iter()
-                                            .map(|s| s.try_into())
-                                            .collect();
-
-                                    match write_results {
-                                        Ok(write_results) => {
-                                            responses_writer
-                                                .send(Ok(FirestoreBatchWriteResponse::new(
-                                                    received_counter - 1,
-                                                    write_results,
-                                                    vec![],
-                                                )
-                                                .opt_commit_time(
-                                                    response
-                                                        .commit_time
-                                                        .and_then(|ts| from_timestamp(ts).ok()),
-                                                )))
-                                                .ok();
-                                        }
-                                        Err(err) => {
-                                            error!(
-                                                "Batch write operation {} failed: {}",
-                                                received_counter, err
-                                            );
-                                            responses_writer.send(Err(err)).ok();
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                            Ok(None) => {
-                                responses_writer
-                                    .send(Ok(FirestoreBatchWriteResponse::new(
-                                        received_counter - 1,
-                                        vec![],
-                                        vec![],
-                                    )))
-                                    .ok();
-                                break;
-                            }
-                            Err(err) if err.code() == Code::Cancelled => {
-                                debug!("Batch write operation finished on: {}", received_counter);
-                                responses_writer
-                                    .send(Ok(FirestoreBatchWriteResponse::new(
-                                        received_counter - 1,
-                                        vec![],
-                                        vec![],
-                                    )))
-                                    .ok();
-                                break;
-                            }
-                            Err(err) => {
-                                error!(
-                                    "Batch write operation {} failed: {}",
-                                    received_counter, err
-                                );
-                                responses_writer.send(Err(err.into())).ok();
-                                break;
-                            }
-                        }
-
-                        {
-                            let _locked = thread_last_token.read().await;
-                            if thread_finished.load(Ordering::Relaxed)
-                                && thread_sent_counter.load(Ordering::Relaxed) == received_counter
-                            {
-                                init_wait_sender.send(()).ok();
-                                break;
-                            }
-                        }
-
-                        thread_received_counter.fetch_add(1, Ordering::Relaxed);
-                    }
+        let unacknowledged_writes =
+            Arc::new(Semaphore::new(options.max_unacknowledged_writes.max(1)));
 
-                    {
-                        let _locked = thread_last_token.write().await;
-                        thread_finished.store(true, Ordering::Relaxed);
-                        init_wait_sender.send(()).ok();
-                    }
-                }
-                Err(err) => {
-                    error!("Batch write operation failed: {}", err);
-                    responses_writer.send(Err(err.into())).ok();
-                }
-            }
-        });
-
-        requests_writer.send(WriteRequest {
-            database: db.get_database_path().to_string(),
-            stream_id: "".to_string(),
-            writes: vec![],
+        let thread_state = FirestoreStreamingBatchWriteState {
+            db: db.clone(),
+            options: options.clone(),
             stream_token: vec![],
-            labels: HashMap::new(),
-        })?;
-
-        init_wait_reader.recv().await;
+            sent_requests: 0,
+            received_responses: 0,
+            unacknowledged: VecDeque::new(),
+            batch_write_results: HashMap::new(),
+            completed_batches: 0,
+            finishing: false,
+            reconnects: 0,
+            responses_writer,
+            init_wait_sender: Some(init_wait_sender),
+        };
+        let thread = tokio::spawn(
+            thread_state
+                .run(commands_receiver, unacknowledged_writes.clone())
+                .instrument(batch_span.clone()),
+        );
+
+        // Waiting for the stream to be opened, or for the writer to fail
+        init_wait_reader
+            .await
+            .map_err(|_| write_stream_closed_error())??;
 
         let responses_stream =
-            tokio_stream::wrappers::UnboundedReceiverStream::new(responses_receiver).boxed();
+            tokio_stream::wrappers::ReceiverStream::new(responses_receiver).boxed();
 
         Ok((
             Self {
                 db,
                 options,
                 batch_span,
-                finished,
-                writer: requests_writer,
+                finished: AtomicBool::new(false),
+                commands: commands_writer,
                 thread: Some(thread),
-                last_token,
-                sent_counter,
-                received_counter,
-                init_wait_reader,
+                batch_counter: AtomicU64::new(0),
+                unacknowledged_writes,
             },
             responses_stream,
         ))
     }
 
     pub async fn finish(mut self) {
-        let locked = self.last_token.write().await;
-
-        if !self.finished.load(Ordering::Relaxed) {
-            self.finished.store(true, Ordering::Relaxed);
-
-            if self.sent_counter.load(Ordering::Relaxed)
-                > self.received_counter.load(Ordering::Relaxed) - 1
-            {
-                drop(locked);
-                debug!("Still waiting receiving responses for batch writes");
-                self.init_wait_reader.recv().await;
-            } else {
-                drop(locked);
-            }
-
-            self.writer
-                .send(WriteRequest {
-                    database: self.db.get_database_path().to_string(),
-                    stream_id: "".to_string(),
-                    writes: vec![],
-                    stream_token: {
-                        let locked = self.last_token.read().await;
-                        locked.clone()
-                    },
-                    labels: HashMap::new(),
-                })
+        if !self.finished.swap(true, Ordering::Relaxed) {
+            debug!("Still waiting receiving responses for batch writes");
+            self.commands
+                .send(FirestoreStreamingBatchCommand::Finish)
                 .ok();
-        } else {
-            drop(locked);
         }
 
         if let Some(thread) = self.thread.take() {
-            let _ = tokio::join!(thread);
+            let _ = thread.await;
         }
     }
 
@@ -261,18 +155,45 @@ impl FirestoreStreamingBatchWriter {
         I: IntoIterator,
         I::Item: Into<Write>,
     {
-        self.sent_counter.fetch_add(1, Ordering::Relaxed);
+        let writes: Vec<Write> = writes.into_iter().map(|write| write.into()).collect();
+        let chunks: Vec<Vec<Write>> = if writes.is_empty() {
+            vec![vec![]]
+        } else {
+            writes
+                .chunks(self.options.max_writes_per_request.max(1))
+                .map(|chunk| chunk.to_vec())
+                .collect()
+        };
+
+        let batch_position = self.batch_counter.fetch_add(1, Ordering::Relaxed);
+        let chunks_count = chunks.len();
+
+        for (index, chunk_writes) in chunks.into_iter().enumerate() {
+            // Waiting while too many writes are not acknowledged yet
+            let permits = chunk_writes
+                .len()
+                .min(self.options.max_unacknowledged_writes.max(1));
+            let permit = self
+                .unacknowledged_writes
+                .clone()
+                .acquire_many_owned(permits as u32)
+                .await
+                .map_err(|_| write_stream_closed_error())?;
+
+            self.commands
+                .send(FirestoreStreamingBatchCommand::Write(
+                    FirestoreStreamingBatchChunk {
+                        batch_position,
+                        last_in_batch: index == chunks_count - 1,
+                        writes: chunk_writes,
+                        stream_position: 0,
+                        _permit: permit,
+                    },
+                ))
+                .map_err(|_| write_stream_closed_error())?;
+        }
 
-        Ok(self.writer.send(WriteRequest {
-            database: self.db.get_database_path().to_string(),
-            stream_id: "".to_string(),
-            writes: writes.into_iter().map(|write| write.into()).collect(),
-            stream_token: {
-                let locked = self.last_token.read().await;
-                locked.clone()
-            },
-            labels: HashMap::new(),
-        })?)
+        Ok(())
     }
 
     pub fn new_batch(&self) -> FirestoreBatch<FirestoreStreamingBatchWriter> {
@@ -289,6 +210,237 @@ impl FirestoreBatchWriter for FirestoreStreamingBatchWriter {
     }
 }
 
+/// The state of the write stream, which outlives the reconnections
+struct FirestoreStreamingBatchWriteState {
+    db: FirestoreDb,
+    options: FirestoreStreamingBatchWriteOptions,
+    stream_token: Vec<u8>,
+    sent_requests: u64,
+    received_responses: u64,
+    unacknowledged: VecDeque<FirestoreStreamingBatchChunk>,
+    batch_write_results: HashMap<u64, Vec<FirestoreWriteResult>>,
+    completed_batches: u64,
+    finishing: bool,
+    reconnects: usize,
+    responses_writer: mpsc::Sender<FirestoreResult<FirestoreBatchWriteResponse>>,
+    init_wait_sender: Option<oneshot::Sender<FirestoreResult<()>>>,
+}
+
+impl FirestoreStreamingBatchWriteState {
+    async fn run(
+        mut self,
+        mut commands: UnboundedReceiver<FirestoreStreamingBatchCommand>,
+        unacknowledged_writes: Arc<Semaphore>,
+    ) {
+        loop {
+            match self.run_stream(&mut commands).await {
+                Ok(()) => break,
+                Err(err) => match self
+                    .db
+                    .get_retry_policy()
+                    .retry_delay(&err, self.reconnects)
+                {
+                    Some(delay) => {
+                        self.reconnects += 1;
+                        warn!(
+                            "Batch write stream failed with {}. Reconnecting in {:?}: {}",
+                            err, delay, self.reconnects
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        error!("Batch write operation failed: {}", err);
+                        match self.init_wait_sender.take() {
+                            Some(init_wait_sender) => init_wait_sender.send(Err(err)).ok(),
+                            None => self.responses_writer.send(Err(err)).await.ok(),
+                        };
+                        break;
+                    }
+                },
+            }
+        }
+
+        // Writing to the failed stream isn't possible anymore
+        unacknowledged_writes.close();
+    }
+
+    async fn run_stream(
+        &mut self,
+        commands: &mut UnboundedReceiver<FirestoreStreamingBatchCommand>,
+    ) -> FirestoreResult<()> {
+        let (requests_writer, requests_receiver) = mpsc::unbounded_channel::<WriteRequest>();
+        let requests_stream = {
+            use tokio_stream::StreamExt;
+            tokio_stream::wrappers::UnboundedReceiverStream::new(requests_receiver)
+                .throttle(self.options.throttle_batch_duration)
+        };
+
+        // A new stream is opened after a failure instead of resuming the previous one,
+        // so the responses are only for the requests sent to this stream
+        self.stream_token.clear();
+        self.sent_requests = 0;
+        self.received_responses = 0;
+        requests_writer.send(self.create_write_request(vec![]))?;
+
+        let mut response_stream = match self
+            .db
+            .client()
+            .get()
+            .write(self.db.create_request(requests_stream))
+            .await
+        {
+            Ok(response) => {
+                let mut response_stream = response.into_inner();
+                match response_stream.next().await {
+                    Some(Ok(handshake)) => {
+                        self.stream_token = handshake.stream_token;
+                        response_stream
+                    }
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Err(write_stream_ended_error()),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(init_wait_sender) = self.init_wait_sender.take() {
+            init_wait_sender.send(Ok(())).ok();
+        }
+
+        // Sending again the requests which weren't acknowledged before the stream broke.
+        // The server might have applied some of them already, so the non-idempotent
+        // transforms in these writes can be applied twice.
+        for index in 0..self.unacknowledged.len() {
+            let request = self.create_write_request(self.unacknowledged[index].writes.clone());
+            self.unacknowledged[index].stream_position = self.sent_requests;
+            self.sent_requests += 1;
+            requests_writer.send(request)?;
+        }
+
+        loop {
+            if self.finishing && self.unacknowledged.is_empty() {
+                requests_writer.send(self.create_write_request(vec![]))?;
+                debug!(
+                    "Batch write operation finished on: {}",
+                    self.completed_batches
+                );
+                self.responses_writer
+                    .send(Ok(FirestoreBatchWriteResponse::new(
+                        self.completed_batches,
+                        vec![],
+                        vec![],
+                    )))
+                    .await
+                    .ok();
+                return Ok(());
+            }
+
+            let response = if self.finishing {
+                response_stream.next().await
+            } else {
+                let command = commands.recv();
+                futures::pin_mut!(command);
+                match futures::future::select(command, response_stream.next()).await {
+                    Either::Left((command, _)) => {
+                        match command {
+                            Some(FirestoreStreamingBatchCommand::Write(mut chunk)) => {
+                                chunk.stream_position = self.sent_requests;
+                                self.sent_requests += 1;
+                                requests_writer
+                                    .send(self.create_write_request(chunk.writes.clone()))?;
+                                self.unacknowledged.push_back(chunk);
+                            }
+                            Some(FirestoreStreamingBatchCommand::Finish) | None => {
+                                self.finishing = true;
+                            }
+                        }
+                        continue;
+                    }
+                    Either::Right((response, _)) => response,
+                }
+            };
+
+            match response {
+                Some(Ok(response)) => self.acknowledge(response).await?,
+                Some(Err(err)) => return Err(err.into()),
+                None => return Err(write_stream_ended_error()),
+            }
+        }
+    }
+
+    async fn acknowledge(&mut self, response: WriteResponse) -> FirestoreResult<()> {
+        self.stream_token = response.stream_token;
+        self.reconnects = 0;
+
+        let write_results: Vec<FirestoreWriteResult> = response
+            .write_results
+            .into_iter()
+            .map(|s| s.try_into())
+            .collect::<FirestoreResult<Vec<FirestoreWriteResult>>>()?;
+
+        // The responses come in the order of the requests of the stream
+        let stream_position = self.received_responses;
+        self.received_responses += 1;
+        let chunk_index = self
+            .unacknowledged
+            .iter()
+            .position(|chunk| chunk.stream_position == stream_position);
+
+        // Dropping the acknowledged chunk releases its writes from the window
+        match chunk_index.and_then(|index| self.unacknowledged.remove(index)) {
+            Some(chunk) => {
+                let batch_write_results = self
+                    .batch_write_results
+                    .entry(chunk.batch_position)
+                    .or_default();
+                batch_write_results.extend(write_results);
+
+                // Responses for the chunks are merged back to a response for the batch
+                if chunk.last_in_batch {
+                    self.completed_batches += 1;
+                    let batch_response = FirestoreBatchWriteResponse::new(
+                        chunk.batch_position,
+                        self.batch_write_results
+                            .remove(&chunk.batch_position)
+                            .unwrap_or_default(),
+                        vec![],
+                    )
+                    .opt_commit_time(response.commit_time.and_then(|ts| from_timestamp(ts).ok()));
+                    self.responses_writer.send(Ok(batch_response)).await.ok();
+                }
+            }
+            None => warn!(
+                "Batch write response {} doesn't match any request",
+                stream_position
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn create_write_request(&self, writes: Vec<Write>) -> WriteRequest {
+        WriteRequest {
+            database: self.db.get_database_path().to_string(),
+            stream_id: "".to_string(),
+            writes,
+            stream_token: self.stream_token.clone(),
+            labels: HashMap::new(),
+        }
+    }
+}
+
+fn write_stream_closed_error() -> FirestoreError {
+    FirestoreError::NetworkError(FirestoreNetworkError::new(
+        FirestoreErrorPublicGenericDetails::new("SEND_STREAM_ERROR".into()),
+        "Batch write stream is closed".into(),
+    ))
+}
+
+fn write_stream_ended_error() -> FirestoreError {
+    // Closed streams are reopened the same way as after the retryable errors
+    tonic::Status::unavailable("Batch write stream was closed by the server").into()
+}
+
 impl FirestoreDb {
     pub async fn create_streaming_batch_writer<'b>(
         &self,
@@ -310,3 +462,67 @@ impl FirestoreDb {
         FirestoreStreamingBatchWriter::new(self.clone(), options).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::offline_test_db;
+    use gcloud_sdk::google::firestore::v1::WriteResult;
+
+    #[tokio::test]
+    async fn acknowledge_by_stream_position() -> FirestoreResult<()> {
+        let db = offline_test_db().await;
+        let (responses_writer, mut responses_receiver) = mpsc::channel(10);
+        let mut state = FirestoreStreamingBatchWriteState {
+            db,
+            options: FirestoreStreamingBatchWriteOptions::new(),
+            stream_token: vec![],
+            sent_requests: 2,
+            received_responses: 0,
+            unacknowledged: VecDeque::new(),
+            batch_write_results: HashMap::new(),
+            completed_batches: 0,
+            finishing: false,
+            reconnects: 0,
+            responses_writer,
+            init_wait_sender: None,
+        };
+
+        let semaphore = Arc::new(Semaphore::new(10));
+        for (stream_position, last_in_batch) in [(0, false), (1, true)] {
+            state
+                .unacknowledged
+                .push_back(FirestoreStreamingBatchChunk {
+                    batch_position: 7,
+                    last_in_batch,
+                    writes: vec![Write::default()],
+                    stream_position,
+                    _permit: semaphore.clone().acquire_owned().await.unwrap(),
+                });
+        }
+        let response = || WriteResponse {
+            stream_id: String::new(),
+            stream_token: vec![],
+            write_results: vec![WriteResult::default()],
+            commit_time: None,
+        };
+
+        state.acknowledge(response()).await?;
+        assert_eq!(state.unacknowledged.len(), 1);
+        assert_eq!(semaphore.available_permits(), 9);
+        assert!(responses_receiver.try_recv().is_err());
+
+        state.acknowledge(response()).await?;
+        let batch_response = responses_receiver.try_recv().unwrap()?;
+        assert_eq!(batch_response.position, 7);
+        assert_eq!(batch_response.write_results.len(), 2);
+        assert_eq!(semaphore.available_permits(), 10);
+
+        // A response without a request in the stream doesn't acknowledge anything
+        state.acknowledge(response()).await?;
+        assert_eq!(state.completed_batches, 1);
+        assert!(responses_receiver.try_recv().is_err());
+
+        Ok(())
+    }
+}
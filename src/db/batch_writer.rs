@@ -1,4 +1,6 @@
-use crate::db::transaction_ops::{TransformObjectOperation, UpdateObjectOperation};
+use crate::db::transaction_ops::{
+    CreateDocumentOperation, CreateObjectOperation, TransformObjectOperation, UpdateObjectOperation,
+};
 use crate::db::DeleteOperation;
 use crate::errors::FirestoreError;
 use crate::{
@@ -7,16 +9,40 @@ use crate::{
 };
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use gcloud_sdk::google::firestore::v1::Write;
+use gcloud_sdk::google::firestore::v1::{Document, Write};
 use gcloud_sdk::google::rpc::Status;
 use rsb_derive::*;
 use serde::Serialize;
 
+/// The maximum number of writes Firestore accepts in a single request
+pub const FIRESTORE_MAX_WRITES_PER_REQUEST: usize = 500;
+
 #[async_trait]
 pub trait FirestoreBatchWriter {
     type WriteResult;
 
     async fn write(&self, writes: Vec<Write>) -> FirestoreResult<Self::WriteResult>;
+
+    /// Writers supporting the keys return them with the results of the writes
+    async fn write_with_keys(
+        &self,
+        writes: Vec<Write>,
+        _write_keys: Vec<Option<String>>,
+    ) -> FirestoreResult<Self::WriteResult>
+    where
+        Self: Sync,
+    {
+        self.write(writes).await
+    }
+}
+
+/// The status of a write without a result in the response, so it isn't reported as applied
+pub(crate) fn missing_write_result_status() -> Status {
+    Status {
+        code: tonic::Code::Internal as i32,
+        message: "The response has no result for the write".to_string(),
+        details: vec![],
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Builder)]
@@ -25,6 +51,46 @@ pub struct FirestoreBatchWriteResponse {
     pub write_results: Vec<FirestoreWriteResult>,
     pub statuses: Vec<Status>,
     pub commit_time: Option<DateTime<Utc>>,
+    #[default = "Vec::new()"]
+    pub write_keys: Vec<Option<String>>,
+}
+
+/// The result of a write in a batch with the key specified when it was added
+#[derive(Debug)]
+pub struct FirestoreBatchWriteOutcome {
+    pub key: Option<String>,
+    pub result: FirestoreResult<FirestoreWriteResult>,
+}
+
+impl FirestoreBatchWriteResponse {
+    /// Returns the results in the order the writes were added to the batch
+    pub fn into_outcomes(self) -> Vec<FirestoreBatchWriteOutcome> {
+        let writes_count = self
+            .write_results
+            .len()
+            .max(self.statuses.len())
+            .max(self.write_keys.len());
+        let mut write_results = self.write_results.into_iter();
+        let mut statuses = self.statuses.into_iter();
+        let mut write_keys = self.write_keys.into_iter();
+
+        (0..writes_count)
+            .map(|_| {
+                let write_result = write_results.next();
+                let status = statuses
+                    .next()
+                    .filter(|status| tonic::Code::from_i32(status.code) != tonic::Code::Ok);
+                FirestoreBatchWriteOutcome {
+                    key: write_keys.next().flatten(),
+                    result: match (status, write_result) {
+                        (Some(status), _) => Err(status.into()),
+                        (None, Some(write_result)) => Ok(write_result),
+                        (None, None) => Err(missing_write_result_status().into()),
+                    },
+                }
+            })
+            .collect()
+    }
 }
 
 pub struct FirestoreBatch<'a, W>
@@ -34,6 +100,7 @@ where
     pub db: &'a FirestoreDb,
     pub writer: &'a W,
     pub writes: Vec<Write>,
+    write_keys: Vec<Option<String>>,
 }
 
 impl<'a, W> FirestoreBatch<'a, W>
@@ -45,6 +112,7 @@ where
             db,
             writer,
             writes: Vec::new(),
+            write_keys: Vec::new(),
         }
     }
 
@@ -57,9 +125,99 @@ where
         Ok(self)
     }
 
+    /// Adds a write with a key to find its outcome in the response
+    pub fn add_with_key<I, K>(&mut self, write: I, key: K) -> FirestoreResult<&mut Self>
+    where
+        I: TryInto<gcloud_sdk::google::firestore::v1::Write, Error = FirestoreError>,
+        K: AsRef<str>,
+    {
+        self.add(write)?;
+        self.write_keys.resize(self.writes.len() - 1, None);
+        self.write_keys.push(Some(key.as_ref().to_string()));
+        Ok(self)
+    }
+
     #[inline]
-    pub async fn write(self) -> FirestoreResult<W::WriteResult> {
-        self.writer.write(self.writes).await
+    pub async fn write(mut self) -> FirestoreResult<W::WriteResult>
+    where
+        W: Sync,
+    {
+        self.write_keys.resize(self.writes.len(), None);
+        self.writer
+            .write_with_keys(self.writes, self.write_keys)
+            .await
+    }
+
+    pub fn create_object<T, S>(
+        &mut self,
+        collection_id: &str,
+        document_id: S,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.create_object_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            obj,
+        )
+    }
+
+    pub fn create_object_at<T, S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.add(CreateObjectOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id,
+            obj,
+        })
+    }
+
+    pub fn create_document<S>(
+        &mut self,
+        collection_id: &str,
+        document_id: S,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.create_document_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            document,
+        )
+    }
+
+    pub fn create_document_at<S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.add(CreateDocumentOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id,
+            document,
+        })
     }
 
     pub fn update_object<T, S>(
@@ -185,3 +343,84 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_write_response_outcomes() {
+        let response = FirestoreBatchWriteResponse::new(
+            0,
+            vec![
+                FirestoreWriteResult::new(vec![]),
+                FirestoreWriteResult::new(vec![]),
+            ],
+            vec![
+                Status::default(),
+                Status {
+                    code: tonic::Code::Unavailable as i32,
+                    message: "unavailable".into(),
+                    details: vec![],
+                },
+            ],
+        )
+        .with_write_keys(vec![Some("first".into()), None]);
+
+        let outcomes = response.into_outcomes();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].key.as_deref(), Some("first"));
+        assert!(outcomes[0].result.is_ok());
+        assert_eq!(outcomes[1].key, None);
+        assert!(matches!(
+            &outcomes[1].result,
+            Err(FirestoreError::DatabaseError(db_err)) if db_err.retry_possible
+        ));
+    }
+
+    #[test]
+    fn batch_write_response_missing_results() {
+        let response = FirestoreBatchWriteResponse::new(
+            0,
+            vec![FirestoreWriteResult::new(vec![])],
+            vec![Status::default()],
+        )
+        .with_write_keys(vec![None, Some("second".into())]);
+
+        let outcomes = response.into_outcomes();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes[0].result.is_ok());
+        assert_eq!(outcomes[1].key.as_deref(), Some("second"));
+        assert!(matches!(
+            &outcomes[1].result,
+            Err(FirestoreError::DatabaseError(db_err)) if !db_err.retry_possible
+        ));
+    }
+
+    #[tokio::test]
+    async fn batch_write_keys() -> FirestoreResult<()> {
+        let db = crate::db::tests::offline_test_db().await;
+        let batch_writer = db.create_simple_batch_writer().await?;
+        let mut batch = batch_writer.new_batch();
+
+        batch
+            .delete_by_id("test", "first", None)?
+            .add_with_key(
+                DeleteOperation {
+                    parent: db.get_documents_path().clone(),
+                    collection_id: "test".to_string(),
+                    document_id: "second",
+                    precondition: None,
+                },
+                "second",
+            )?
+            .delete_by_id("test", "third", None)?;
+        batch.write_keys.resize(batch.writes.len(), None);
+
+        assert_eq!(
+            batch.write_keys,
+            vec![None, Some("second".to_string()), None]
+        );
+        Ok(())
+    }
+}
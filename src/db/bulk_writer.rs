@@ -0,0 +1,507 @@
+use crate::db::missing_write_result_status;
+use crate::errors::*;
+use crate::{
+    FirestoreBatch, FirestoreBatchWriteResponse, FirestoreBatchWriter, FirestoreDb,
+    FirestoreResult, FirestoreWriteResult,
+};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use gcloud_sdk::google::firestore::v1::{write, BatchWriteRequest, BatchWriteResponse, Write};
+use gcloud_sdk::google::rpc::Status;
+use rsb_derive::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::*;
+
+/// The throughput follows the 500/50/5 rule recommended for Firestore:
+/// starting at 500 operations per second and raising it by 50% every 5 minutes.
+#[derive(Debug, PartialEq, Clone, Builder)]
+pub struct FirestoreBulkWriteOptions {
+    #[default = "500"]
+    pub initial_ops_per_second: usize,
+
+    pub max_ops_per_second: Option<usize>,
+
+    #[default = "Duration::from_secs(5 * 60)"]
+    pub ramp_up_interval: Duration,
+
+    #[default = "1.5"]
+    pub ramp_up_multiplier: f64,
+
+    /// Smaller requests reduce the contention between the writes
+    #[default = "20"]
+    pub max_writes_per_request: usize,
+
+    #[default = "10"]
+    pub max_concurrent_requests: usize,
+}
+
+pub struct FirestoreBulkWriter {
+    pub db: FirestoreDb,
+    pub options: FirestoreBulkWriteOptions,
+    pub batch_span: Span,
+    rate_limiter: Mutex<FirestoreBulkWriteRateLimiter>,
+    documents_in_flight: Mutex<HashSet<String>>,
+    documents_released: Notify,
+}
+
+impl FirestoreBulkWriter {
+    pub async fn new(
+        db: FirestoreDb,
+        options: FirestoreBulkWriteOptions,
+    ) -> FirestoreResult<FirestoreBulkWriter> {
+        let batch_span = span!(Level::DEBUG, "Firestore Bulk Write");
+        let rate_limiter = FirestoreBulkWriteRateLimiter::new(&options, Instant::now());
+
+        Ok(Self {
+            db,
+            options,
+            batch_span,
+            rate_limiter: Mutex::new(rate_limiter),
+            documents_in_flight: Mutex::new(HashSet::new()),
+            documents_released: Notify::new(),
+        })
+    }
+
+    pub fn new_batch(&self) -> FirestoreBatch<'_, FirestoreBulkWriter> {
+        FirestoreBatch::new(&self.db, self)
+    }
+
+    /// The current throughput limit in operations per second
+    pub fn ops_per_second(&self) -> f64 {
+        self.lock_rate_limiter().ops_per_second
+    }
+
+    fn lock_rate_limiter(&self) -> std::sync::MutexGuard<'_, FirestoreBulkWriteRateLimiter> {
+        self.rate_limiter
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn wait_for_capacity(&self, ops: usize) {
+        loop {
+            let acquired = self.lock_rate_limiter().try_acquire(ops, Instant::now());
+            match acquired {
+                Ok(()) => return,
+                Err(delay) => tokio::time::sleep(delay).await,
+            }
+        }
+    }
+
+    async fn lock_documents(
+        &self,
+        document_names: Vec<String>,
+    ) -> FirestoreBulkWriteDocumentsLock<'_> {
+        loop {
+            // Created before checking, so releases in between aren't missed
+            let released = self.documents_released.notified();
+            {
+                let mut documents_in_flight = self
+                    .documents_in_flight
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                if document_names
+                    .iter()
+                    .all(|document_name| !documents_in_flight.contains(document_name))
+                {
+                    documents_in_flight.extend(document_names.iter().cloned());
+                    return FirestoreBulkWriteDocumentsLock {
+                        writer: self,
+                        document_names,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn release_documents(&self, document_names: &[String]) {
+        {
+            let mut documents_in_flight = self
+                .documents_in_flight
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for document_name in document_names {
+                documents_in_flight.remove(document_name);
+            }
+        }
+        self.documents_released.notify_waiters();
+    }
+
+    async fn write_positions(
+        &self,
+        writes: &[Write],
+        positions: Vec<usize>,
+    ) -> FirestoreResult<Vec<(usize, FirestoreWriteResult, Status)>> {
+        let mut results = Vec::with_capacity(positions.len());
+        let mut pending_positions = positions;
+        let mut retries = 0;
+
+        loop {
+            let document_names: Vec<String> = pending_positions
+                .iter()
+                .filter_map(|position| write_document_name(&writes[*position]))
+                .collect();
+
+            let documents_lock = self.lock_documents(document_names).await;
+
+            let request = BatchWriteRequest {
+                database: self.db.get_database_path().to_string(),
+                writes: pending_positions
+                    .iter()
+                    .map(|position| writes[*position].clone())
+                    .collect(),
+                labels: HashMap::new(),
+            };
+            let response = self
+                .db
+                .with_retries(|| {
+                    let request = self.db.create_request(request.clone());
+                    async move {
+                        // The retried requests are limited as well
+                        self.wait_for_capacity(request.get_ref().writes.len()).await;
+                        self.db
+                            .client()
+                            .get()
+                            .batch_write(request)
+                            .await
+                            .map_err(|status| {
+                                if is_throttling_code(status.code()) {
+                                    self.lock_rate_limiter().back_off(Instant::now());
+                                }
+                                FirestoreError::from(status)
+                            })
+                    }
+                })
+                .await;
+
+            drop(documents_lock);
+            let batch_response = response?.into_inner();
+
+            let (failed_positions, retry_delay) =
+                self.handle_response(pending_positions, batch_response, retries, &mut results)?;
+
+            match retry_delay {
+                Some(retry_delay) => {
+                    retries += 1;
+                    self.batch_span.in_scope(|| {
+                        warn!(
+                            "[DB]: {} bulk writes failed. Retrying them in {:?}: {}",
+                            failed_positions.len(),
+                            retry_delay,
+                            retries
+                        )
+                    });
+                    tokio::time::sleep(retry_delay).await;
+                    pending_positions = failed_positions;
+                }
+                None => return Ok(results),
+            }
+        }
+    }
+}
+
+impl FirestoreBulkWriter {
+    /// Collects the results of the successful writes and returns the failed writes to retry
+    fn handle_response(
+        &self,
+        pending_positions: Vec<usize>,
+        batch_response: BatchWriteResponse,
+        retries: usize,
+        results: &mut Vec<(usize, FirestoreWriteResult, Status)>,
+    ) -> FirestoreResult<(Vec<usize>, Option<Duration>)> {
+        let mut write_results = batch_response.write_results.into_iter();
+        let mut statuses = batch_response.status.into_iter();
+        let mut failed_positions = Vec::new();
+        let mut retry_delay = None;
+        let mut throttled = false;
+
+        for position in pending_positions {
+            let write_result = write_results
+                .next()
+                .map(|write_result| write_result.try_into())
+                .transpose()?;
+            let status = statuses.next().unwrap_or_default();
+            let code = tonic::Code::from_i32(status.code);
+
+            if code != tonic::Code::Ok {
+                throttled |= is_throttling_code(code);
+
+                // Only the failed writes are retried
+                if let Some(delay) = self
+                    .db
+                    .get_retry_policy()
+                    .retry_delay(&FirestoreError::from(status.clone()), retries)
+                {
+                    retry_delay = retry_delay.max(Some(delay));
+                    failed_positions.push(position);
+                    continue;
+                }
+            }
+
+            match write_result {
+                Some(write_result) => results.push((position, write_result, status)),
+                None => results.push((
+                    position,
+                    FirestoreWriteResult::new(vec![]),
+                    // A write without a result isn't known to be applied
+                    if code == tonic::Code::Ok {
+                        missing_write_result_status()
+                    } else {
+                        status
+                    },
+                )),
+            }
+        }
+
+        // A response is a single signal of the load, however many writes were throttled
+        if throttled {
+            self.lock_rate_limiter().back_off(Instant::now());
+        }
+
+        Ok((failed_positions, retry_delay))
+    }
+}
+
+/// Releases the documents of the writes in flight when dropped,
+/// including the errors and the cancelled writes
+struct FirestoreBulkWriteDocumentsLock<'a> {
+    writer: &'a FirestoreBulkWriter,
+    document_names: Vec<String>,
+}
+
+impl Drop for FirestoreBulkWriteDocumentsLock<'_> {
+    fn drop(&mut self) {
+        self.writer.release_documents(&self.document_names);
+    }
+}
+
+#[async_trait]
+impl FirestoreBatchWriter for FirestoreBulkWriter {
+    type WriteResult = FirestoreBatchWriteResponse;
+
+    async fn write(&self, writes: Vec<Write>) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        let mut write_results = vec![FirestoreWriteResult::new(vec![]); writes.len()];
+        let mut statuses = vec![Status::default(); writes.len()];
+
+        // Firestore doesn't accept several writes to the same document in a request,
+        // so they are sent one after another in the order they were added
+        for round_positions in write_rounds(&writes) {
+            let requests_positions: Vec<Vec<usize>> = round_positions
+                .chunks(self.options.max_writes_per_request.max(1))
+                .map(|positions| positions.to_vec())
+                .collect();
+
+            let round_results: Vec<Vec<(usize, FirestoreWriteResult, Status)>> =
+                futures::stream::iter(requests_positions)
+                    .map(|positions| self.write_positions(&writes, positions))
+                    .buffer_unordered(self.options.max_concurrent_requests.max(1))
+                    .try_collect()
+                    .await?;
+
+            for (position, write_result, status) in round_results.into_iter().flatten() {
+                write_results[position] = write_result;
+                statuses[position] = status;
+            }
+        }
+
+        Ok(FirestoreBatchWriteResponse::new(0, write_results, statuses))
+    }
+
+    async fn write_with_keys(
+        &self,
+        writes: Vec<Write>,
+        write_keys: Vec<Option<String>>,
+    ) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        Ok(self.write(writes).await?.with_write_keys(write_keys))
+    }
+}
+
+fn is_throttling_code(code: tonic::Code) -> bool {
+    matches!(code, tonic::Code::ResourceExhausted | tonic::Code::Aborted)
+}
+
+fn write_document_name(write: &Write) -> Option<String> {
+    match write.operation.as_ref()? {
+        write::Operation::Update(document) => Some(document.name.clone()),
+        write::Operation::Delete(document_name) => Some(document_name.clone()),
+        write::Operation::Transform(transform) => Some(transform.document.clone()),
+    }
+}
+
+/// Splits the positions of the writes into rounds without repeated documents
+fn write_rounds(writes: &[Write]) -> Vec<Vec<usize>> {
+    let mut rounds: Vec<Vec<usize>> = Vec::new();
+    let mut document_writes: HashMap<String, usize> = HashMap::new();
+
+    for (position, write) in writes.iter().enumerate() {
+        let round = match write_document_name(write) {
+            Some(document_name) => {
+                let counter = document_writes.entry(document_name).or_insert(0);
+                *counter += 1;
+                *counter - 1
+            }
+            None => 0,
+        };
+        if rounds.len() <= round {
+            rounds.resize(round + 1, Vec::new());
+        }
+        rounds[round].push(position);
+    }
+
+    rounds
+}
+
+#[derive(Debug)]
+struct FirestoreBulkWriteRateLimiter {
+    ops_per_second: f64,
+    max_ops_per_second: f64,
+    ramp_up_interval: Duration,
+    ramp_up_multiplier: f64,
+    available_ops: f64,
+    last_refill: Instant,
+    last_ramp_up: Instant,
+}
+
+impl FirestoreBulkWriteRateLimiter {
+    fn new(options: &FirestoreBulkWriteOptions, now: Instant) -> Self {
+        let ops_per_second = options.initial_ops_per_second.max(1) as f64;
+        Self {
+            ops_per_second,
+            max_ops_per_second: options
+                .max_ops_per_second
+                .map(|max_ops| max_ops.max(1) as f64)
+                .unwrap_or(f64::MAX),
+            ramp_up_interval: options.ramp_up_interval,
+            ramp_up_multiplier: options.ramp_up_multiplier.max(1.0),
+            available_ops: ops_per_second,
+            last_refill: now,
+            last_ramp_up: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now.duration_since(self.last_ramp_up) >= self.ramp_up_interval {
+            self.ops_per_second =
+                (self.ops_per_second * self.ramp_up_multiplier).min(self.max_ops_per_second);
+            self.last_ramp_up = now;
+        }
+
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available_ops =
+            (self.available_ops + elapsed * self.ops_per_second).min(self.ops_per_second);
+        self.last_refill = now;
+    }
+
+    /// Returns the delay before the next attempt, if there isn't enough capacity now
+    fn try_acquire(&mut self, ops: usize, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        // Requests larger than the limit wait for the full capacity
+        let required_ops = (ops as f64).min(self.ops_per_second);
+        if self.available_ops >= required_ops {
+            self.available_ops -= ops as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (required_ops - self.available_ops) / self.ops_per_second,
+            ))
+        }
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.ops_per_second = (self.ops_per_second / self.ramp_up_multiplier).max(1.0);
+        self.available_ops = self.available_ops.min(self.ops_per_second);
+        self.last_ramp_up = now;
+    }
+}
+
+impl FirestoreDb {
+    pub async fn create_bulk_writer(&self) -> FirestoreResult<FirestoreBulkWriter> {
+        self.create_bulk_writer_with_options(FirestoreBulkWriteOptions::new())
+            .await
+    }
+
+    pub async fn create_bulk_writer_with_options(
+        &self,
+        options: FirestoreBulkWriteOptions,
+    ) -> FirestoreResult<FirestoreBulkWriter> {
+        FirestoreBulkWriter::new(self.clone(), options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_ramp_up() {
+        let start = Instant::now();
+        let mut rate_limiter =
+            FirestoreBulkWriteRateLimiter::new(&FirestoreBulkWriteOptions::new(), start);
+
+        assert_eq!(rate_limiter.try_acquire(500, start), Ok(()));
+        assert_eq!(
+            rate_limiter.try_acquire(50, start),
+            Err(Duration::from_millis(100))
+        );
+        assert_eq!(
+            rate_limiter.try_acquire(50, start + Duration::from_millis(100)),
+            Ok(())
+        );
+
+        rate_limiter.refill(start + Duration::from_secs(5 * 60));
+        assert_eq!(rate_limiter.ops_per_second, 750.0);
+
+        rate_limiter.back_off(start + Duration::from_secs(5 * 60));
+        assert_eq!(rate_limiter.ops_per_second, 500.0);
+    }
+
+    #[tokio::test]
+    async fn throttled_response_backs_off_once() -> FirestoreResult<()> {
+        let db = crate::db::tests::offline_test_db().await;
+        let writer = db.create_bulk_writer().await?;
+        let throttled = Status {
+            code: tonic::Code::ResourceExhausted as i32,
+            ..Status::default()
+        };
+
+        let mut results = Vec::new();
+        let (failed_positions, retry_delay) = writer.handle_response(
+            (0..20).collect(),
+            BatchWriteResponse {
+                write_results: vec![],
+                status: vec![throttled; 20],
+            },
+            0,
+            &mut results,
+        )?;
+
+        assert_eq!(failed_positions, (0..20).collect::<Vec<_>>());
+        assert!(retry_delay.is_some());
+        assert!(results.is_empty());
+        assert_eq!(writer.ops_per_second(), 500.0 / 1.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_rounds_without_repeated_documents() {
+        let delete = |document_name: &str| Write {
+            operation: Some(write::Operation::Delete(document_name.to_string())),
+            ..Write::default()
+        };
+
+        let writes = vec![
+            delete("a"),
+            delete("b"),
+            delete("a"),
+            delete("c"),
+            delete("a"),
+        ];
+        assert_eq!(write_rounds(&writes), vec![vec![0, 1, 3], vec![2], vec![4]]);
+    }
+}
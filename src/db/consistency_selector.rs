@@ -1,6 +1,6 @@
 use crate::errors::*;
 use crate::timestamp_utils::to_timestamp;
-use crate::{FirestoreError, FirestoreTransactionId};
+use crate::{FirestoreDb, FirestoreError, FirestoreResult, FirestoreTransactionId};
 use chrono::prelude::*;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -118,3 +118,43 @@ impl TryFrom<&FirestoreConsistencySelector>
         }
     }
 }
+
+impl FirestoreDb {
+    /// The read time of a request takes precedence over the consistency selector of the session,
+    /// except for transactions, which can't be combined with it
+    pub(crate) fn request_consistency_selector<T>(
+        &self,
+        read_time: Option<DateTime<Utc>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'a> T: TryFrom<&'a FirestoreConsistencySelector, Error = FirestoreError>,
+    {
+        match read_time {
+            Some(_)
+                if matches!(
+                    self.session_params.consistency_selector,
+                    Some(FirestoreConsistencySelector::Transaction(_))
+                ) =>
+            {
+                Err(FirestoreError::InvalidParametersError(
+                    FirestoreInvalidParametersError::new(
+                        FirestoreInvalidParametersPublicDetails::new(
+                            "read_time".to_string(),
+                            "The read time can't be used for reads within a transaction"
+                                .to_string(),
+                        ),
+                    ),
+                ))
+            }
+            Some(read_time) => {
+                T::try_from(&FirestoreConsistencySelector::ReadTime(read_time)).map(Some)
+            }
+            None => self
+                .session_params
+                .consistency_selector
+                .as_ref()
+                .map(T::try_from)
+                .transpose(),
+        }
+    }
+}
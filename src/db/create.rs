@@ -1,3 +1,4 @@
+use crate::errors::FirestoreError;
 use crate::{FirestoreDb, FirestoreResult};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
@@ -5,6 +6,9 @@ use gcloud_sdk::google::firestore::v1::*;
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
+/// The creates are retried only when Firestore rejected the attempt (`Aborted`, `ResourceExhausted`).
+/// Other errors, like `Unavailable`, are returned as they are, since the document may have been created
+/// and a retry would fail with `AlreadyExists`.
 #[async_trait]
 pub trait FirestoreCreateSupport {
     async fn create_doc<S>(
@@ -54,6 +58,20 @@ pub trait FirestoreCreateSupport {
         S: AsRef<str> + Send;
 }
 
+// A create isn't idempotent, so only the attempts known to be not applied can be retried
+fn create_document_error(status: tonic::Status) -> FirestoreError {
+    let not_applied = matches!(
+        status.code(),
+        tonic::Code::Aborted | tonic::Code::ResourceExhausted
+    );
+    match FirestoreError::from(status) {
+        FirestoreError::DatabaseError(db_err) if !not_applied => {
+            FirestoreError::DatabaseError(db_err.with_retry_possible(false))
+        }
+        err => err,
+    }
+}
+
 #[async_trait]
 impl FirestoreCreateSupport for FirestoreDb {
     async fn create_doc<S>(
@@ -94,25 +112,32 @@ impl FirestoreCreateSupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        let create_document_request = tonic::Request::new(CreateDocumentRequest {
+        let create_document_request = CreateDocumentRequest {
             parent: parent.into(),
             document_id: document_id
                 .as_ref()
                 .map(|id| id.as_ref().to_string())
-                .unwrap_or_default(),
+                .unwrap_or_else(Self::generate_document_id),
             mask: return_only_fields.as_ref().map(|masks| DocumentMask {
                 field_paths: masks.clone(),
             }),
             collection_id: collection_id.into(),
             document: Some(input_doc),
-        });
+        };
 
         let begin_query_utc: DateTime<Utc> = Utc::now();
 
         let create_response = self
-            .client()
-            .get()
-            .create_document(create_document_request)
+            .with_retries(|| {
+                let request = self.create_request(create_document_request.clone());
+                async move {
+                    self.client()
+                        .get()
+                        .create_document(request)
+                        .await
+                        .map_err(create_document_error)
+                }
+            })
             .await?;
 
         let end_query_utc: DateTime<Utc> = Utc::now();
@@ -184,3 +209,22 @@ impl FirestoreCreateSupport for FirestoreDb {
         Self::deserialize_doc_to(&doc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_document_retries() {
+        let retry_possible =
+            |code: tonic::Code| match create_document_error(tonic::Status::new(code, "test")) {
+                FirestoreError::DatabaseError(db_err) => db_err.retry_possible,
+                _ => false,
+            };
+
+        assert!(retry_possible(tonic::Code::Aborted));
+        assert!(retry_possible(tonic::Code::ResourceExhausted));
+        assert!(!retry_possible(tonic::Code::Unavailable));
+        assert!(!retry_possible(tonic::Code::DeadlineExceeded));
+    }
+}
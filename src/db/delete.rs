@@ -1,9 +1,34 @@
-use crate::db::safe_document_path;
-use crate::{FirestoreDb, FirestoreResult, FirestoreWritePrecondition};
+use crate::db::{query_doc_page, safe_document_path};
+use crate::errors::*;
+use crate::{
+    FirestoreBatchWriter, FirestoreBulkWriter, FirestoreDb, FirestoreQueryCollection,
+    FirestoreQueryFilter, FirestoreQueryFilterCompare, FirestoreQueryFilterComposite,
+    FirestoreQueryPageToken, FirestoreQueryParams, FirestoreResult, FirestoreValue,
+    FirestoreWritePrecondition, FIRESTORE_MAX_WRITES_PER_REQUEST,
+};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use gcloud_sdk::google::firestore::v1::*;
-use tracing::*;
+use rsb_derive::Builder;
+use tracing::{debug, field, span, Level};
+
+#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+pub struct FirestoreRecursiveDeleteProgress {
+    pub deleted_count: usize,
+    pub failed_count: usize,
+}
+
+#[derive(Debug, Builder)]
+pub struct FirestoreRecursiveDeleteFailure {
+    pub document_path: String,
+    pub error: FirestoreError,
+}
+
+#[derive(Debug, Builder)]
+pub struct FirestoreRecursiveDeleteResult {
+    pub deleted_count: usize,
+    pub failures: Vec<FirestoreRecursiveDeleteFailure>,
+}
 
 #[async_trait]
 pub trait FirestoreDeleteSupport {
@@ -27,6 +52,55 @@ pub trait FirestoreDeleteSupport {
         S: AsRef<str> + Send;
 }
 
+/// Deletes documents with all their subcollections, in addition to [`FirestoreDeleteSupport`]
+#[async_trait]
+pub trait FirestoreRecursiveDeleteSupport {
+    /// Deletes a document or a collection with all their subcollections.
+    /// The path is relative to the documents path of the database, unless it starts with it.
+    async fn recursive_delete<S>(&self, path: S) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send;
+
+    async fn recursive_delete_with_progress<S, F>(
+        &self,
+        path: S,
+        on_progress: F,
+    ) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send,
+        F: Fn(&FirestoreRecursiveDeleteProgress) + Send + Sync;
+}
+
+/// The minimal document id in the ordering of Firestore
+const REFERENCE_NAME_MIN_ID: &str = "__id-9223372036854775808__";
+
+/// Returns the full path and if it is a path of a document
+pub(crate) fn recursive_delete_path(
+    documents_path: &str,
+    path: &str,
+) -> FirestoreResult<(String, bool)> {
+    let relative_path = path
+        .strip_prefix(documents_path)
+        .and_then(|relative_path| relative_path.strip_prefix('/'))
+        .unwrap_or(path)
+        .trim_matches('/');
+
+    let segments: Vec<&str> = relative_path.split('/').collect();
+    if relative_path.is_empty() || segments.iter().any(|segment| segment.is_empty()) {
+        return Err(FirestoreError::InvalidParametersError(
+            FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                "path".to_string(),
+                format!("Invalid path to delete: {path}"),
+            )),
+        ));
+    }
+
+    Ok((
+        format!("{documents_path}/{relative_path}"),
+        segments.len() % 2 == 0,
+    ))
+}
+
 #[async_trait]
 impl FirestoreDeleteSupport for FirestoreDb {
     async fn delete_by_id<S>(
@@ -66,13 +140,17 @@ impl FirestoreDeleteSupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        let request = tonic::Request::new(DeleteDocumentRequest {
+        let delete_document_request = DeleteDocumentRequest {
             name: document_path,
             current_document: precondition.map(|cond| cond.try_into()).transpose()?,
-        });
+        };
 
         let begin_query_utc: DateTime<Utc> = Utc::now();
-        self.client().get().delete_document(request).await?;
+        self.with_retries(|| {
+            let request = self.create_request(delete_document_request.clone());
+            async move { Ok(self.client().get().delete_document(request).await?) }
+        })
+        .await?;
         let end_query_utc: DateTime<Utc> = Utc::now();
         let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
 
@@ -92,3 +170,210 @@ impl FirestoreDeleteSupport for FirestoreDb {
         Ok(())
     }
 }
+
+#[async_trait]
+impl FirestoreRecursiveDeleteSupport for FirestoreDb {
+    async fn recursive_delete<S>(&self, path: S) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.recursive_delete_with_progress(path, |_| {}).await
+    }
+
+    async fn recursive_delete_with_progress<S, F>(
+        &self,
+        path: S,
+        on_progress: F,
+    ) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send,
+        F: Fn(&FirestoreRecursiveDeleteProgress) + Send + Sync,
+    {
+        let (full_path, is_document) =
+            recursive_delete_path(self.get_documents_path(), path.as_ref())?;
+
+        let span = span!(
+            Level::DEBUG,
+            "Firestore Recursive Delete",
+            "/firestore/path" = full_path.as_str()
+        );
+
+        // Descendants are found with a query for all collections under the closest parent document
+        let query_params = if is_document {
+            FirestoreQueryParams::new(FirestoreQueryCollection::Single(String::new()))
+                .with_parent(full_path.clone())
+        } else {
+            let (parent, collection_id) = full_path
+                .rsplit_once('/')
+                .unwrap_or((self.get_documents_path().as_str(), full_path.as_str()));
+            let reference = |reference_path: String| {
+                FirestoreValue::from(Value {
+                    value_type: Some(value::ValueType::ReferenceValue(reference_path)),
+                })
+            };
+
+            // All documents from the collection and its subcollections are in this range of names
+            FirestoreQueryParams::new(FirestoreQueryCollection::Single(String::new()))
+                .with_parent(parent.to_string())
+                .with_filter(FirestoreQueryFilter::Composite(
+                    FirestoreQueryFilterComposite::new(vec![
+                        FirestoreQueryFilter::Compare(Some(
+                            FirestoreQueryFilterCompare::GreaterThanOrEqual(
+                                "__name__".to_string(),
+                                reference(format!(
+                                    "{parent}/{collection_id}/{REFERENCE_NAME_MIN_ID}"
+                                )),
+                            ),
+                        )),
+                        FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::LessThan(
+                            "__name__".to_string(),
+                            reference(format!(
+                                "{parent}/{collection_id}\0/{REFERENCE_NAME_MIN_ID}"
+                            )),
+                        ))),
+                    ]),
+                ))
+        }
+        .with_all_descendants(true)
+        .with_return_only_fields(vec!["__name__".to_string()]);
+
+        let bulk_writer = self.create_bulk_writer().await?;
+        let mut result = FirestoreRecursiveDeleteResult::new(0, vec![]);
+        let report_progress = |result: &FirestoreRecursiveDeleteResult| {
+            span.in_scope(|| {
+                debug!(
+                    "[DB]: Recursive delete progress. Deleted: {}. Failed: {}",
+                    result.deleted_count,
+                    result.failures.len()
+                )
+            });
+            on_progress(&FirestoreRecursiveDeleteProgress::new(
+                result.deleted_count,
+                result.failures.len(),
+            ));
+        };
+
+        // Every page is a separate query after the last found name, so a large subtree
+        // doesn't keep one query stream open during the whole delete
+        let mut page_token: Option<FirestoreQueryPageToken> = None;
+        loop {
+            let page = query_doc_page(
+                self,
+                query_params.clone(),
+                FIRESTORE_MAX_WRITES_PER_REQUEST as u32,
+                page_token.as_ref(),
+            )
+            .await?;
+
+            if !page.items.is_empty() {
+                let document_paths = page
+                    .items
+                    .into_iter()
+                    .map(|document| document.name)
+                    .collect();
+                self.recursive_delete_documents(&bulk_writer, document_paths, &mut result)
+                    .await?;
+                report_progress(&result);
+            }
+
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        // The document itself is deleted after its descendants
+        if is_document {
+            self.recursive_delete_documents(&bulk_writer, vec![full_path], &mut result)
+                .await?;
+            report_progress(&result);
+        }
+
+        Ok(result)
+    }
+}
+
+impl FirestoreDb {
+    async fn recursive_delete_documents(
+        &self,
+        bulk_writer: &FirestoreBulkWriter,
+        document_paths: Vec<String>,
+        result: &mut FirestoreRecursiveDeleteResult,
+    ) -> FirestoreResult<()> {
+        let writes: Vec<Write> = document_paths
+            .iter()
+            .map(|document_path| Write {
+                update_mask: None,
+                update_transforms: vec![],
+                current_document: None,
+                operation: Some(write::Operation::Delete(document_path.clone())),
+            })
+            .collect();
+
+        let outcomes = bulk_writer.write(writes).await?.into_outcomes();
+        for (document_path, outcome) in document_paths.into_iter().zip(outcomes) {
+            match outcome.result {
+                Ok(_) => result.deleted_count += 1,
+                Err(error) => result
+                    .failures
+                    .push(FirestoreRecursiveDeleteFailure::new(document_path, error)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+
+    #[tokio::test]
+    async fn recursive_delete_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let nested_parent = db.parent_path(TEST_COLLECTION_NAME, &inserted[0].some_id)?;
+        for (collection_id, parent) in
+            [("nested", Some(nested_parent.to_string())), ("other", None)]
+        {
+            let builder = db
+                .fluent()
+                .insert()
+                .into(collection_id)
+                .document_id(&inserted[0].some_id);
+            let builder = match parent {
+                Some(parent) => builder.parent(parent),
+                None => builder,
+            };
+            let _: TestStructure = builder.object(&inserted[0]).execute().await?;
+        }
+
+        let deleted = db
+            .recursive_delete(format!("{TEST_COLLECTION_NAME}/{}", inserted[0].some_id))
+            .await?;
+        assert_eq!(deleted.deleted_count, 2);
+
+        let progress = std::sync::Mutex::new(Vec::new());
+        let deleted = db
+            .fluent()
+            .delete()
+            .from(TEST_COLLECTION_NAME)
+            .recursive()
+            .execute_with_progress(|current| progress.lock().unwrap().push(current.clone()))
+            .await?;
+        assert_eq!(deleted.deleted_count, 4);
+        assert!(deleted.failures.is_empty());
+        assert_eq!(
+            progress.into_inner().unwrap(),
+            vec![FirestoreRecursiveDeleteProgress::new(4, 0)]
+        );
+
+        let remaining: Vec<TestStructure> =
+            db.fluent().select().from("other").obj().query().await?;
+        assert_eq!(remaining, vec![inserted[0].clone()]);
+
+        Ok(())
+    }
+}
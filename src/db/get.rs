@@ -1,16 +1,27 @@
 use crate::db::safe_document_path;
+use crate::errors::unsupported_operation_error;
 use crate::{FirestoreDb, FirestoreError, FirestoreResult};
 use async_trait::async_trait;
 use chrono::prelude::*;
-use futures::future::{BoxFuture, FutureExt};
 use futures::stream::BoxStream;
-use futures::TryFutureExt;
 use futures::TryStreamExt;
 use futures::{future, StreamExt};
 use gcloud_sdk::google::firestore::v1::*;
+use rsb_derive::Builder;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use tracing::*;
 
+#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+pub struct FirestoreBatchGetParams {
+    pub collection_id: String,
+    pub document_ids: Vec<String>,
+    pub parent: Option<String>,
+    pub return_only_fields: Option<Vec<String>>,
+    /// Reads the documents as they were at this time
+    pub read_time: Option<DateTime<Utc>>,
+}
+
 #[async_trait]
 pub trait FirestoreGetByIdSupport {
     async fn get_doc<S>(
@@ -176,6 +187,207 @@ pub trait FirestoreGetByIdSupport {
         for<'de> T: Deserialize<'de> + Send + 'a,
         S: AsRef<str> + Send,
         I: IntoIterator<Item = S> + Send;
+
+    /// Gets the documents by ids with the options of the params, such as the read time.
+    /// The default implementation doesn't support the read time.
+    async fn batch_stream_get_docs_with_params(
+        &self,
+        params: FirestoreBatchGetParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>>
+    where
+        Self: Sync,
+    {
+        if params.read_time.is_some() {
+            return Err(unsupported_operation_error("Batch get at a read time"));
+        }
+
+        match params.parent {
+            Some(parent) => {
+                self.batch_stream_get_docs_at_with_errors(
+                    parent.as_str(),
+                    params.collection_id.as_str(),
+                    params.document_ids,
+                    params.return_only_fields,
+                )
+                .await
+            }
+            None => {
+                self.batch_stream_get_docs_with_errors(
+                    params.collection_id.as_str(),
+                    params.document_ids,
+                    params.return_only_fields,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Large sets of ids are split into several batch get requests
+pub const FIRESTORE_MAX_DOCS_PER_BATCH_GET: usize = 100;
+
+/// How many batch get requests are executed concurrently
+pub const FIRESTORE_BATCH_GET_CONCURRENCY: usize = 4;
+
+impl FirestoreDb {
+    async fn batch_stream_get_docs_at_read_time<S, I>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+        read_time: Option<DateTime<Utc>>,
+    ) -> FirestoreResult<BoxStream<'_, FirestoreResult<(String, Option<Document>)>>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let full_doc_ids: Vec<String> = document_ids
+            .into_iter()
+            .map(|document_id| safe_document_path(parent, collection_id, document_id.as_ref()))
+            .collect::<FirestoreResult<Vec<String>>>()?;
+
+        let span = span!(
+            Level::DEBUG,
+            "Firestore Batch Get",
+            "/firestore/collection_name" = collection_id,
+            "/firestore/ids_count" = full_doc_ids.len()
+        );
+
+        let consistency_selector = self.request_consistency_selector(read_time)?;
+        let mask = return_only_fields.map({
+            |vf| gcloud_sdk::google::firestore::v1::DocumentMask {
+                field_paths: vf.iter().map(|f| f.to_string()).collect(),
+            }
+        });
+
+        let batch_get_requests: Vec<BatchGetDocumentsRequest> = full_doc_ids
+            .chunks(FIRESTORE_MAX_DOCS_PER_BATCH_GET)
+            .map(|chunk_doc_ids| BatchGetDocumentsRequest {
+                database: self.get_database_path().clone(),
+                documents: chunk_doc_ids.to_vec(),
+                consistency_selector: consistency_selector.clone(),
+                mask: mask.clone(),
+            })
+            .collect();
+
+        span.in_scope(|| {
+            debug!(
+                "Start consuming a batch of documents by ids in {} requests",
+                batch_get_requests.len()
+            )
+        });
+
+        let mut batch_get_requests = batch_get_requests.into_iter();
+        let first_chunk = match batch_get_requests.next() {
+            Some(batch_get_request) => self.open_batch_get_chunk(batch_get_request).await?,
+            None => return Ok(futures::stream::empty().boxed()),
+        };
+
+        // The following chunks are opened concurrently, but the results keep the order of the requested ids
+        let stream = futures::stream::once(future::ready(Ok(first_chunk)))
+            .chain(
+                futures::stream::iter(batch_get_requests)
+                    .map(move |batch_get_request| self.open_batch_get_chunk(batch_get_request))
+                    .buffered(FIRESTORE_BATCH_GET_CONCURRENCY),
+            )
+            .flat_map(|chunk_result| match chunk_result {
+                Ok(chunk_stream) => chunk_stream,
+                Err(err) => futures::stream::once(future::ready(Err(err))).boxed(),
+            })
+            .boxed();
+
+        Ok(stream)
+    }
+
+    async fn open_batch_get_chunk(
+        &self,
+        batch_get_request: BatchGetDocumentsRequest,
+    ) -> FirestoreResult<BoxStream<'static, FirestoreResult<(String, Option<Document>)>>> {
+        let response = self
+            .with_retries(|| {
+                let request = self.create_request(batch_get_request.clone());
+                async move { Ok(self.client().get().batch_get_documents(request).await?) }
+            })
+            .await?;
+
+        // Responses arrive in any order, so they are mapped back to the positions of the ids
+        let mut positions: HashMap<String, VecDeque<usize>> = HashMap::new();
+        for (position, full_doc_id) in batch_get_request.documents.into_iter().enumerate() {
+            positions
+                .entry(full_doc_id)
+                .or_default()
+                .push_back(position);
+        }
+
+        let state = BatchGetChunkState {
+            responses: response.into_inner(),
+            positions,
+            pending: BTreeMap::new(),
+            next_position: 0,
+            finished: false,
+        };
+
+        Ok(futures::stream::unfold(state, |mut state| async move {
+            loop {
+                // Documents are yielded as soon as all the preceding ones have arrived
+                if let Some(doc) = state.pending.remove(&state.next_position) {
+                    state.next_position += 1;
+                    return Some((Ok(batch_get_doc_id(doc)), state));
+                }
+
+                if state.finished {
+                    let position = *state.pending.keys().next()?;
+                    state.next_position = position;
+                    continue;
+                }
+
+                match state.responses.next().await {
+                    Some(Ok(doc_response)) => {
+                        let doc = match doc_response.result {
+                            Some(batch_get_documents_response::Result::Found(document)) => {
+                                (document.name.clone(), Some(document))
+                            }
+                            Some(batch_get_documents_response::Result::Missing(full_doc_id)) => {
+                                (full_doc_id, None)
+                            }
+                            None => continue,
+                        };
+                        match state
+                            .positions
+                            .get_mut(doc.0.as_str())
+                            .and_then(|positions| positions.pop_front())
+                        {
+                            Some(position) => {
+                                state.pending.insert(position, doc);
+                            }
+                            None => return Some((Ok(batch_get_doc_id(doc)), state)),
+                        }
+                    }
+                    Some(Err(err)) => return Some((Err(err.into()), state)),
+                    None => state.finished = true,
+                }
+            }
+        })
+        .boxed())
+    }
+}
+
+struct BatchGetChunkState {
+    responses: tonic::Streaming<BatchGetDocumentsResponse>,
+    positions: HashMap<String, VecDeque<usize>>,
+    pending: BTreeMap<usize, (String, Option<Document>)>,
+    next_position: usize,
+    finished: bool,
+}
+
+fn batch_get_doc_id((full_doc_id, doc): (String, Option<Document>)) -> (String, Option<Document>) {
+    let doc_id = full_doc_id
+        .split('/')
+        .last()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| full_doc_id.clone());
+    (doc_id, doc)
 }
 
 #[async_trait]
@@ -191,7 +403,7 @@ impl FirestoreGetByIdSupport for FirestoreDb {
         S: AsRef<str> + Send,
     {
         let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
-        self.get_doc_by_path(document_path, return_only_fields, 0)
+        self.get_doc_by_path(document_path, return_only_fields)
             .await
     }
 
@@ -340,67 +552,31 @@ impl FirestoreGetByIdSupport for FirestoreDb {
         S: AsRef<str> + Send,
         I: IntoIterator<Item = S> + Send,
     {
-        let full_doc_ids: Vec<String> = document_ids
-            .into_iter()
-            .map(|document_id| safe_document_path(parent, collection_id, document_id.as_ref()))
-            .collect::<FirestoreResult<Vec<String>>>()?;
-
-        let span = span!(
-            Level::DEBUG,
-            "Firestore Batch Get",
-            "/firestore/collection_name" = collection_id,
-            "/firestore/ids_count" = full_doc_ids.len()
-        );
+        self.batch_stream_get_docs_at_read_time(
+            parent,
+            collection_id,
+            document_ids,
+            return_only_fields,
+            None,
+        )
+        .await
+    }
 
-        let request = tonic::Request::new(BatchGetDocumentsRequest {
-            database: self.get_database_path().clone(),
-            documents: full_doc_ids,
-            consistency_selector: self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?,
-            mask: return_only_fields.map({
-                |vf| gcloud_sdk::google::firestore::v1::DocumentMask {
-                    field_paths: vf.iter().map(|f| f.to_string()).collect(),
-                }
-            }),
-        });
-        match self.client().get().batch_get_documents(request).await {
-            Ok(response) => {
-                span.in_scope(|| debug!("Start consuming a batch of documents by ids"));
-                let stream = response
-                    .into_inner()
-                    .filter_map(|r| {
-                        future::ready(match r {
-                            Ok(doc_response) => doc_response.result.map(|doc_res| match doc_res {
-                                batch_get_documents_response::Result::Found(document) => {
-                                    let doc_id = document
-                                        .name
-                                        .split('/')
-                                        .last()
-                                        .map(|s| s.to_string())
-                                        .unwrap_or_else(|| document.name.clone());
-                                    Ok((doc_id, Some(document)))
-                                }
-                                batch_get_documents_response::Result::Missing(full_doc_id) => {
-                                    let doc_id = full_doc_id
-                                        .split('/')
-                                        .last()
-                                        .map(|s| s.to_string())
-                                        .unwrap_or_else(|| full_doc_id);
-                                    Ok((doc_id, None))
-                                }
-                            }),
-                            Err(err) => Some(Err(err.into())),
-                        })
-                    })
-                    .boxed();
-                Ok(stream)
-            }
-            Err(err) => Err(err.into()),
-        }
+    async fn batch_stream_get_docs_with_params(
+        &self,
+        params: FirestoreBatchGetParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>> {
+        self.batch_stream_get_docs_at_read_time(
+            params
+                .parent
+                .as_deref()
+                .unwrap_or_else(|| self.get_documents_path().as_str()),
+            params.collection_id.as_str(),
+            params.document_ids,
+            params.return_only_fields,
+            params.read_time,
+        )
+        .await
     }
 
     async fn batch_stream_get_docs_at<S, I>(
@@ -593,65 +769,44 @@ impl FirestoreGetByIdSupport for FirestoreDb {
 }
 
 impl FirestoreDb {
-    pub(crate) fn get_doc_by_path(
+    pub(crate) async fn get_doc_by_path(
         &self,
         document_path: String,
         return_only_fields: Option<Vec<String>>,
-        retries: usize,
-    ) -> BoxFuture<FirestoreResult<Document>> {
-        async move {
-            let begin_query_utc: DateTime<Utc> = Utc::now();
-
-            let request = tonic::Request::new(GetDocumentRequest {
-                name: document_path.clone(),
-                consistency_selector: self
-                    .session_params
-                    .consistency_selector
-                    .as_ref()
-                    .map(|selector| selector.try_into())
-                    .transpose()?,
-                mask: return_only_fields.map({
-                    |vf| gcloud_sdk::google::firestore::v1::DocumentMask {
-                        field_paths: vf.iter().map(|f| f.to_string()).collect(),
-                    }
-                }),
-            });
-
-            match self
-                .client()
-                .get()
-                .get_document(request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(doc_response) => {
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
-
-                    debug!(
-                        "[DB]: Reading document {} took {}ms",
-                        document_path,
-                        query_duration.num_milliseconds()
-                    );
-
-                    Ok(doc_response.into_inner())
+    ) -> FirestoreResult<Document> {
+        let get_document_request = GetDocumentRequest {
+            name: document_path.clone(),
+            consistency_selector: self
+                .session_params
+                .consistency_selector
+                .as_ref()
+                .map(|selector| selector.try_into())
+                .transpose()?,
+            mask: return_only_fields.map({
+                |vf| gcloud_sdk::google::firestore::v1::DocumentMask {
+                    field_paths: vf.iter().map(|f| f.to_string()).collect(),
                 }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.get_options().max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.get_options().max_retries
-                        );
-                        self.get_doc_by_path(document_path, None, retries + 1).await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+            }),
+        };
+
+        let begin_query_utc: DateTime<Utc> = Utc::now();
+
+        let doc_response = self
+            .with_retries(|| {
+                let request = self.create_request(get_document_request.clone());
+                async move { Ok(self.client().get().get_document(request).await?) }
+            })
+            .await?;
+
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+
+        debug!(
+            "[DB]: Reading document {} took {}ms",
+            document_path,
+            query_duration.num_milliseconds()
+        );
+
+        Ok(doc_response.into_inner())
     }
 }
@@ -1,11 +1,8 @@
 use crate::{FirestoreDb, FirestoreError, FirestoreQueryOrder, FirestoreResult};
 use async_trait::async_trait;
 use chrono::prelude::*;
-use futures::future::BoxFuture;
 use futures::stream::BoxStream;
-use futures::FutureExt;
 use futures::StreamExt;
-use futures::TryFutureExt;
 use futures::TryStreamExt;
 use gcloud_sdk::google::firestore::v1::*;
 use rsb_derive::*;
@@ -25,6 +22,8 @@ pub struct FirestoreListDocParams {
     pub page_token: Option<String>,
     pub order_by: Option<Vec<FirestoreQueryOrder>>,
     pub return_only_fields: Option<Vec<String>>,
+    /// Lists the documents as they were at this time
+    pub read_time: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, PartialEq, Clone, Builder)]
@@ -40,6 +39,8 @@ pub struct FirestoreListCollectionIdsParams {
     #[default = "100"]
     pub page_size: usize,
     pub page_token: Option<String>,
+    /// Lists the collections as they were at this time
+    pub read_time: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, PartialEq, Clone, Builder)]
@@ -108,7 +109,7 @@ impl FirestoreListingSupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        self.list_doc_with_retries(params, 0, &span).await
+        self.list_doc_with_retries(params, &span).await
     }
 
     async fn stream_list_doc_with_errors(
@@ -127,7 +128,7 @@ impl FirestoreListingSupport for FirestoreDb {
                         "/firestore/response_time" = field::Empty
                     );
 
-                    match self.list_doc_with_retries(params.clone(), 0, &span).await {
+                    match self.list_doc_with_retries(params.clone(), &span).await {
                         Ok(results) => {
                             if let Some(next_page_token) = results.page_token.clone() {
                                 Some((Ok(results), Some(params.with_page_token(next_page_token))))
@@ -222,8 +223,7 @@ impl FirestoreListingSupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        self.list_collection_ids_with_retries(params, 0, &span)
-            .await
+        self.list_collection_ids_with_retries(params, &span).await
     }
 
     async fn stream_list_collection_ids(
@@ -259,7 +259,7 @@ impl FirestoreListingSupport for FirestoreDb {
                     );
 
                     match self
-                        .list_collection_ids_with_retries(params.clone(), 0, &span)
+                        .list_collection_ids_with_retries(params.clone(), &span)
                         .await
                     {
                         Ok(results) => {
@@ -299,7 +299,7 @@ impl FirestoreDb {
         &self,
         params: FirestoreListDocParams,
     ) -> FirestoreResult<tonic::Request<ListDocumentsRequest>> {
-        Ok(tonic::Request::new(ListDocumentsRequest {
+        Ok(self.create_request(ListDocumentsRequest {
             parent: params
                 .parent
                 .as_ref()
@@ -321,83 +321,56 @@ impl FirestoreDb {
             mask: params
                 .return_only_fields
                 .map(|masks| DocumentMask { field_paths: masks }),
-            consistency_selector: self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?,
+            consistency_selector: self.request_consistency_selector(params.read_time)?,
             show_missing: false,
         }))
     }
 
-    fn list_doc_with_retries<'a>(
-        &'a self,
+    async fn list_doc_with_retries(
+        &self,
         params: FirestoreListDocParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<FirestoreListDocResult>> {
-        async move {
-            let list_request = self.create_list_doc_request(params.clone())?;
-            let begin_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .list_documents(list_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(listing_response) => {
-                    let list_inner = listing_response.into_inner();
-                    let result = FirestoreListDocResult::new(list_inner.documents).opt_page_token(
-                        if !list_inner.next_page_token.is_empty() {
-                            Some(list_inner.next_page_token)
-                        } else {
-                            None
-                        },
-                    );
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let listing_duration = end_query_utc.signed_duration_since(begin_utc);
+        span: &Span,
+    ) -> FirestoreResult<FirestoreListDocResult> {
+        let begin_utc: DateTime<Utc> = Utc::now();
 
-                    span.record(
-                        "/firestore/response_time",
-                        listing_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Listing documents in {:?} took {}ms",
-                            params.collection_id,
-                            listing_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(result)
-                }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Listing failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-                        self.list_doc_with_retries(params, retries + 1, span).await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+        let listing_response = self
+            .with_retries(|| {
+                let list_request = self.create_list_doc_request(params.clone());
+                async move { Ok(self.client().get().list_documents(list_request?).await?) }
+            })
+            .await?;
+
+        let list_inner = listing_response.into_inner();
+        let result = FirestoreListDocResult::new(list_inner.documents).opt_page_token(
+            if !list_inner.next_page_token.is_empty() {
+                Some(list_inner.next_page_token)
+            } else {
+                None
+            },
+        );
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let listing_duration = end_query_utc.signed_duration_since(begin_utc);
+
+        span.record(
+            "/firestore/response_time",
+            listing_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Listing documents in {:?} took {}ms",
+                params.collection_id,
+                listing_duration.num_milliseconds()
+            );
+        });
+
+        Ok(result)
     }
 
     fn create_list_collection_ids_request(
         &self,
         params: &FirestoreListCollectionIdsParams,
     ) -> FirestoreResult<tonic::Request<ListCollectionIdsRequest>> {
-        Ok(tonic::Request::new(ListCollectionIdsRequest {
+        Ok(self.create_request(ListCollectionIdsRequest {
             parent: params
                 .parent
                 .as_ref()
@@ -405,73 +378,51 @@ impl FirestoreDb {
                 .clone(),
             page_size: params.page_size as i32,
             page_token: params.page_token.clone().unwrap_or_default(),
-            consistency_selector: self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?,
+            consistency_selector: self.request_consistency_selector(params.read_time)?,
         }))
     }
 
-    fn list_collection_ids_with_retries<'a>(
-        &'a self,
+    async fn list_collection_ids_with_retries(
+        &self,
         params: FirestoreListCollectionIdsParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<FirestoreListCollectionIdsResult>> {
-        async move {
-            let list_request = self.create_list_collection_ids_request(&params)?;
-            let begin_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .list_collection_ids(list_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(listing_response) => {
-                    let list_inner = listing_response.into_inner();
-                    let result = FirestoreListCollectionIdsResult::new(list_inner.collection_ids)
-                        .opt_page_token(if !list_inner.next_page_token.is_empty() {
-                            Some(list_inner.next_page_token)
-                        } else {
-                            None
-                        });
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let listing_duration = end_query_utc.signed_duration_since(begin_utc);
-
-                    span.record(
-                        "/firestore/response_time",
-                        listing_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Listing collections took {}ms",
-                            listing_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(result)
+        span: &Span,
+    ) -> FirestoreResult<FirestoreListCollectionIdsResult> {
+        let begin_utc: DateTime<Utc> = Utc::now();
+
+        let listing_response = self
+            .with_retries(|| {
+                let list_request = self.create_list_collection_ids_request(&params);
+                async move {
+                    Ok(self
+                        .client()
+                        .get()
+                        .list_collection_ids(list_request?)
+                        .await?)
                 }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Listing failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-                        self.list_collection_ids_with_retries(params, retries + 1, span)
-                            .await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+            })
+            .await?;
+
+        let list_inner = listing_response.into_inner();
+        let result = FirestoreListCollectionIdsResult::new(list_inner.collection_ids)
+            .opt_page_token(if !list_inner.next_page_token.is_empty() {
+                Some(list_inner.next_page_token)
+            } else {
+                None
+            });
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let listing_duration = end_query_utc.signed_duration_since(begin_utc);
+
+        span.record(
+            "/firestore/response_time",
+            listing_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Listing collections took {}ms",
+                listing_duration.num_milliseconds()
+            );
+        });
+
+        Ok(result)
     }
 }
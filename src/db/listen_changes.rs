@@ -1,17 +1,21 @@
+use crate::db::query_ordering::reject_limit_to_last;
 use crate::db::safe_document_path;
 use crate::errors::*;
 use crate::timestamp_utils::to_timestamp;
 use crate::{FirestoreDb, FirestoreQueryParams, FirestoreResult};
 pub use async_trait::async_trait;
+use backoff::backoff::Backoff;
 use chrono::prelude::*;
+use futures::future;
 use futures::stream::BoxStream;
+use futures::Stream;
 use futures::StreamExt;
 use futures::TryFutureExt;
 use futures::TryStreamExt;
 use gcloud_sdk::google::firestore::v1::*;
 use rsb_derive::*;
 pub use rvstruct::ValueStruct;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -28,6 +32,27 @@ pub struct FirestoreListenerTargetParams {
     pub labels: HashMap<String, String>,
 }
 
+impl FirestoreListenerTargetParams {
+    pub(crate) fn validate(&self) -> FirestoreResult<()> {
+        if let FirestoreTargetType::Query(query_params) = &self.target_type {
+            query_params.validate()?;
+            reject_limit_to_last(query_params, "listen targets")?;
+            // Listening starts from the current state, or from the resume type of the target
+            if query_params.read_time.is_some() {
+                return Err(FirestoreError::InvalidParametersError(
+                    FirestoreInvalidParametersError::new(
+                        FirestoreInvalidParametersPublicDetails::new(
+                            "read_time".to_string(),
+                            "Listen targets don't support the read time of a query, use the resume type of the target instead".to_string(),
+                        ),
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Builder)]
 pub struct FirestoreCollectionDocuments {
     pub parent: Option<String>,
@@ -47,12 +72,44 @@ pub enum FirestoreListenerTargetResumeType {
     ReadTime(DateTime<Utc>),
 }
 
+#[derive(Debug, Clone)]
+pub enum FirestoreListenerTargetChange {
+    Add(Box<FirestoreListenerTargetParams>),
+    Remove(FirestoreListenerTarget),
+}
+
 #[async_trait]
 pub trait FirestoreListenSupport {
     async fn listen_doc_changes<'a, 'b>(
         &'a self,
         targets: Vec<FirestoreListenerTargetParams>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>>;
+
+    /// Listens to the changes of the targets, that can be added or removed later using the target changes stream.
+    /// The default implementation doesn't support the target changes and fails the stream on them.
+    async fn listen_doc_changes_with_target_changes<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        target_changes: UnboundedReceiver<FirestoreListenerTargetChange>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>>
+    where
+        Self: Sync,
+    {
+        let listen_stream = self.listen_doc_changes(targets).await?;
+        let unsupported_changes =
+            tokio_stream::wrappers::UnboundedReceiverStream::new(target_changes).map(|_| {
+                Err(FirestoreError::InvalidParametersError(
+                    FirestoreInvalidParametersError::new(
+                        FirestoreInvalidParametersPublicDetails::new(
+                            "target_changes".to_string(),
+                            "Target changes aren't supported by the listen implementation"
+                                .to_string(),
+                        ),
+                    ),
+                ))
+            });
+        Ok(futures::stream::select(listen_stream, unsupported_changes).boxed())
+    }
 }
 
 #[async_trait]
@@ -60,14 +117,26 @@ impl FirestoreListenSupport for FirestoreDb {
     async fn listen_doc_changes<'a, 'b>(
         &'a self,
         targets: Vec<FirestoreListenerTargetParams>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        let (_, target_changes) = tokio::sync::mpsc::unbounded_channel();
+        self.listen_doc_changes_with_target_changes(targets, target_changes)
+            .await
+    }
+
+    async fn listen_doc_changes_with_target_changes<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        target_changes: UnboundedReceiver<FirestoreListenerTargetChange>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
         let listen_requests = targets
             .into_iter()
             .map(|target_params| self.create_listen_request(target_params))
             .collect::<FirestoreResult<Vec<ListenRequest>>>()?;
 
-        let request = tonic::Request::new(
-            futures::stream::iter(listen_requests).chain(futures::stream::pending()),
+        let request = self.create_request(
+            futures::stream::iter(listen_requests)
+                .chain(self.create_target_change_requests(target_changes))
+                .chain(futures::stream::pending()),
         );
 
         let response = self.client().get().listen(request).await?;
@@ -82,7 +151,7 @@ pub struct FirestoreListenerTarget(i32);
 #[derive(Clone, Debug, ValueStruct)]
 pub struct FirestoreListenerToken(Vec<u8>);
 
-type BoxedErrResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+pub(crate) type BoxedErrResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 impl FirestoreDb {
     pub async fn create_listener<S>(
@@ -107,10 +176,47 @@ impl FirestoreDb {
         FirestoreListener::new(self.clone(), storage, params).await
     }
 
+    fn create_target_change_requests(
+        &self,
+        target_changes: UnboundedReceiver<FirestoreListenerTargetChange>,
+    ) -> impl Stream<Item = ListenRequest> + Send + 'static {
+        let db = self.clone();
+        tokio_stream::wrappers::UnboundedReceiverStream::new(target_changes).filter_map(
+            move |target_change| {
+                future::ready(match db.create_target_change_request(target_change) {
+                    Ok(listen_request) => Some(listen_request),
+                    Err(err) => {
+                        error!("Invalid listener target change: {}", err);
+                        None
+                    }
+                })
+            },
+        )
+    }
+
+    fn create_target_change_request(
+        &self,
+        target_change: FirestoreListenerTargetChange,
+    ) -> FirestoreResult<ListenRequest> {
+        match target_change {
+            FirestoreListenerTargetChange::Add(target_params) => {
+                self.create_listen_request(*target_params)
+            }
+            FirestoreListenerTargetChange::Remove(target) => Ok(ListenRequest {
+                database: self.get_database_path().to_string(),
+                labels: HashMap::new(),
+                target_change: Some(listen_request::TargetChange::RemoveTarget(
+                    target.into_value(),
+                )),
+            }),
+        }
+    }
+
     fn create_listen_request(
         &self,
         target_params: FirestoreListenerTargetParams,
     ) -> FirestoreResult<ListenRequest> {
+        target_params.validate()?;
         Ok(ListenRequest {
             database: self.get_database_path().to_string(),
             labels: target_params.labels,
@@ -159,6 +265,7 @@ impl FirestoreDb {
                             target::ResumeType::ReadTime(to_timestamp(dt))
                         }
                     }),
+                expected_count: None,
             })),
         })
     }
@@ -183,8 +290,15 @@ pub type FirestoreListenEvent = listen_response::ResponseType;
 #[derive(Debug, Clone, Builder)]
 pub struct FirestoreListenerParams {
     pub retry_delay: Option<std::time::Duration>,
+    /// Backoff between the failed attempts to open the listen stream.
+    /// The default exponential backoff has no time limit, so only `max_connect_attempts` stops the attempts.
+    pub connect_backoff: Option<backoff::ExponentialBackoff>,
+    /// The number of failed attempts to open the listen stream before giving up
+    pub max_connect_attempts: Option<usize>,
 }
 
+pub type FirestoreListenerErrorHandler = Arc<dyn Fn(FirestoreError) + Send + Sync>;
+
 pub struct FirestoreListener<D, S>
 where
     D: FirestoreListenSupport,
@@ -197,6 +311,8 @@ where
     shutdown_flag: Arc<AtomicBool>,
     shutdown_handle: Option<JoinHandle<()>>,
     shutdown_writer: Option<Arc<UnboundedSender<i8>>>,
+    target_changes_writer: Option<UnboundedSender<FirestoreListenerTargetChange>>,
+    error_handler: Option<FirestoreListenerErrorHandler>,
 }
 
 impl<D, S> FirestoreListener<D, S>
@@ -217,12 +333,64 @@ where
             shutdown_flag: Arc::new(AtomicBool::new(false)),
             shutdown_handle: None,
             shutdown_writer: None,
+            target_changes_writer: None,
+            error_handler: None,
         })
     }
 
+    /// Sets a handler for the errors stopping the listener, such as failing to open the listen stream.
+    pub fn set_error_handler<EFN>(&mut self, handler: EFN)
+    where
+        EFN: Fn(FirestoreError) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Arc::new(handler));
+    }
+
+    /// Adds a target to listen. The target is added to the open stream if the listener is already started.
+    /// Firestore doesn't allow adding a target ID twice, so an existing target has to be removed first.
     pub fn add_target(&mut self, target: FirestoreListenerTargetParams) -> FirestoreResult<()> {
-        self.targets.push(target);
-        Ok(())
+        target.validate()?;
+        if self
+            .targets
+            .iter()
+            .any(|target_params| target_params.target == target.target)
+        {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "target".to_string(),
+                    format!(
+                        "Target {} has already been added, remove it before adding it again",
+                        target.target.value()
+                    ),
+                )),
+            ));
+        }
+        self.targets.push(target.clone());
+        self.send_target_change(FirestoreListenerTargetChange::Add(Box::new(target)))
+    }
+
+    /// Removes a target. The target is removed from the open stream if the listener is already started.
+    pub fn remove_target(&mut self, target: &FirestoreListenerTarget) -> FirestoreResult<()> {
+        self.targets
+            .retain(|target_params| &target_params.target != target);
+        self.send_target_change(FirestoreListenerTargetChange::Remove(target.clone()))
+    }
+
+    fn send_target_change(
+        &self,
+        target_change: FirestoreListenerTargetChange,
+    ) -> FirestoreResult<()> {
+        match &self.target_changes_writer {
+            Some(target_changes_writer) => {
+                target_changes_writer.send(target_change).map_err(|_| {
+                    FirestoreError::SystemError(FirestoreSystemError::new(
+                        FirestoreErrorPublicGenericDetails::new("SystemError".into()),
+                        "Listener has been stopped".to_string(),
+                    ))
+                })
+            }
+            None => Ok(()),
+        }
     }
 
     pub async fn start<FN, F>(&mut self, cb: FN) -> FirestoreResult<()>
@@ -257,15 +425,19 @@ where
 
         let (tx, rx): (UnboundedSender<i8>, UnboundedReceiver<i8>) =
             tokio::sync::mpsc::unbounded_channel();
+        let (target_changes_tx, target_changes_rx) = tokio::sync::mpsc::unbounded_channel();
 
         self.shutdown_writer = Some(Arc::new(tx));
+        self.target_changes_writer = Some(target_changes_tx);
         self.shutdown_handle = Some(tokio::spawn(Self::listener_loop(
             self.db.clone(),
             self.storage.clone(),
             self.shutdown_flag.clone(),
             initial_states,
             self.listener_params.clone(),
+            self.error_handler.clone(),
             rx,
+            target_changes_rx,
             cb,
         )));
         Ok(())
@@ -277,6 +449,7 @@ where
         if let Some(shutdown_writer) = self.shutdown_writer.take() {
             shutdown_writer.send(1).ok();
         }
+        self.target_changes_writer.take();
         if let Some(signaller) = self.shutdown_handle.take() {
             if let Err(err) = signaller.await {
                 warn!("Firestore listener exit error: {}...", err);
@@ -292,20 +465,89 @@ where
         shutdown_flag: Arc<AtomicBool>,
         mut targets_state: HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
         listener_params: FirestoreListenerParams,
+        error_handler: Option<FirestoreListenerErrorHandler>,
         mut shutdown_receiver: UnboundedReceiver<i8>,
+        mut target_changes_receiver: UnboundedReceiver<FirestoreListenerTargetChange>,
         cb: FN,
     ) where
         D: FirestoreListenSupport + Clone + Send + Sync,
         FN: Fn(FirestoreListenEvent) -> F + Send + Sync,
         F: Future<Output = BoxedErrResult<()>> + Send,
     {
+        let mut connect_backoff = listener_params.connect_backoff.clone().unwrap_or_else(|| {
+            backoff::ExponentialBackoff {
+                max_elapsed_time: None,
+                ..backoff::ExponentialBackoff::default()
+            }
+        });
+        let mut failed_attempts: usize = 0;
+        let mut removing_targets: HashSet<FirestoreListenerTarget> = HashSet::new();
+        let mut delayed_targets: HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams> =
+            HashMap::new();
+
         while !shutdown_flag.load(Ordering::Relaxed) {
             debug!("Start listening on targets {:?}... ", targets_state.len());
 
-            let mut listen_stream = db
-                .listen_doc_changes(targets_state.values().into_iter().cloned().collect())
+            // A new stream doesn't listen the removed targets, so the delayed targets are added right away
+            removing_targets.clear();
+            targets_state.extend(delayed_targets.drain());
+
+            let (stream_target_changes_writer, stream_target_changes_receiver) =
+                tokio::sync::mpsc::unbounded_channel();
+
+            let mut listen_stream = match db
+                .listen_doc_changes_with_target_changes(
+                    targets_state.values().cloned().collect(),
+                    stream_target_changes_receiver,
+                )
                 .await
-                .unwrap();
+            {
+                Ok(listen_stream) => {
+                    connect_backoff.reset();
+                    failed_attempts = 0;
+                    listen_stream
+                }
+                Err(err) => {
+                    failed_attempts += 1;
+                    let next_delay = if listener_params
+                        .max_connect_attempts
+                        .map(|max_attempts| failed_attempts >= max_attempts)
+                        .unwrap_or(false)
+                    {
+                        None
+                    } else {
+                        connect_backoff.next_backoff()
+                    };
+
+                    match next_delay {
+                        Some(delay) => {
+                            warn!(
+                                "Unable to start listening (attempt {}): {}. Retrying in {:?}...",
+                                failed_attempts, err, delay
+                            );
+                            if tokio::time::timeout(delay, shutdown_receiver.recv())
+                                .await
+                                .is_ok()
+                            {
+                                debug!("Exiting from listener...");
+                                shutdown_receiver.close();
+                                break;
+                            }
+                            continue;
+                        }
+                        None => {
+                            error!(
+                                "Unable to start listening after {} attempts: {}. Stopping the listener.",
+                                failed_attempts, err
+                            );
+                            if let Some(error_handler) = &error_handler {
+                                error_handler(err);
+                            }
+                            break;
+                        }
+                    }
+                }
+            };
 
             loop {
                 tokio::select! {
@@ -314,6 +556,37 @@ where
                             shutdown_receiver.close();
                             break;
                         }
+                        Some(target_change) = target_changes_receiver.recv() => {
+                            match target_change {
+                                FirestoreListenerTargetChange::Add(target_params) => {
+                                    let resume_type = storage
+                                        .read_resume_state(&target_params.target)
+                                        .await
+                                        .unwrap_or_else(|err| {
+                                            error!("Listener token storage error occurred {:?}.", err);
+                                            None
+                                        });
+                                    let target_params = target_params.opt_resume_type(resume_type);
+                                    // Firestore may still listen the target ID until the removal is confirmed
+                                    if removing_targets.contains(&target_params.target) {
+                                        delayed_targets.insert(target_params.target.clone(), target_params);
+                                    }
+                                    else if targets_state.contains_key(&target_params.target) {
+                                        warn!("Ignoring the target {:?} that is already listened.", target_params.target);
+                                    }
+                                    else {
+                                        targets_state.insert(target_params.target.clone(), target_params.clone());
+                                        stream_target_changes_writer.send(FirestoreListenerTargetChange::Add(Box::new(target_params))).ok();
+                                    }
+                                }
+                                FirestoreListenerTargetChange::Remove(target) => {
+                                    if delayed_targets.remove(&target).is_none() && targets_state.remove(&target).is_some() {
+                                        removing_targets.insert(target.clone());
+                                        stream_target_changes_writer.send(FirestoreListenerTargetChange::Remove(target)).ok();
+                                    }
+                                }
+                            }
+                        }
                         tried = listen_stream.try_next() => {
                             if shutdown_flag.load(Ordering::Relaxed) {
                                 break;
@@ -322,6 +595,19 @@ where
                                 match tried {
                                     Ok(Some(event)) => {
                                         trace!("Received a listen response event to handle: {:?}", event);
+                                        if let Some(listen_response::ResponseType::TargetChange(ref target_change)) = event.response_type {
+                                            if target_change.target_change_type == target_change::TargetChangeType::Remove as i32 {
+                                                for target_id_num in &target_change.target_ids {
+                                                    let target = FirestoreListenerTarget::new(*target_id_num);
+                                                    if removing_targets.remove(&target) {
+                                                        if let Some(target_params) = delayed_targets.remove(&target) {
+                                                            targets_state.insert(target.clone(), target_params.clone());
+                                                            stream_target_changes_writer.send(FirestoreListenerTargetChange::Add(Box::new(target_params))).ok();
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        }
                                         match event.response_type {
                                             Some(listen_response::ResponseType::TargetChange(ref target_change))
                                                 if !target_change.resume_token.is_empty() =>
@@ -365,3 +651,169 @@ where
         }
     }
 }
+
+#[cfg(all(test, feature = "memory-db"))]
+mod tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+    use gcloud_sdk::google::firestore::v1::*;
+
+    #[tokio::test]
+    async fn listener_start_failure_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+
+        let mut listener = db
+            .create_listener_with_params(
+                FirestoreMemListenStateStorage::new(),
+                FirestoreListenerParams::new()
+                    .with_connect_backoff(
+                        backoff::ExponentialBackoffBuilder::new()
+                            .with_initial_interval(std::time::Duration::from_millis(1))
+                            .build(),
+                    )
+                    .with_max_connect_attempts(3),
+            )
+            .await?;
+
+        let (error_sender, mut error_receiver) = tokio::sync::mpsc::unbounded_channel();
+        listener.set_error_handler(move |err| {
+            error_sender.send(err).ok();
+        });
+
+        // Invalid document IDs make opening the listen stream fail
+        db.fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .batch_listen(["invalid/id"])
+            .add_target(FirestoreListenerTarget::new(1), &mut listener)?;
+
+        listener.start(|_| async move { Ok(()) }).await?;
+
+        assert!(matches!(
+            error_receiver.recv().await,
+            Some(FirestoreError::InvalidParametersError(_))
+        ));
+
+        listener.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn next_doc_change(
+        event_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<FirestoreListenEvent>,
+    ) -> Option<DocumentChange> {
+        while let Some(event) = event_receiver.recv().await {
+            if let listen_response::ResponseType::DocumentChange(change) = event {
+                return Some(change);
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn listener_target_changes_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let mut listener = db
+            .create_listener(FirestoreMemListenStateStorage::new())
+            .await?;
+
+        db.fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .batch_listen([&inserted[1].some_id])
+            .add_target(FirestoreListenerTarget::new(1), &mut listener)?;
+
+        let (event_sender, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
+        listener
+            .start(move |event| {
+                let event_sender = event_sender.clone();
+                async move {
+                    event_sender.send(event).ok();
+                    Ok(())
+                }
+            })
+            .await?;
+
+        let change = next_doc_change(&mut event_receiver).await.unwrap();
+        assert_eq!(change.target_ids, vec![1]);
+
+        db.fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .batch_listen([&inserted[2].some_id])
+            .add_target(FirestoreListenerTarget::new(2), &mut listener)?;
+
+        let change = next_doc_change(&mut event_receiver).await.unwrap();
+        assert_eq!(change.target_ids, vec![2]);
+        assert!(change
+            .document
+            .unwrap()
+            .name
+            .ends_with(&inserted[2].some_id));
+
+        listener.remove_target(&FirestoreListenerTarget::new(1))?;
+        while let Some(event) = event_receiver.recv().await {
+            if let listen_response::ResponseType::TargetChange(target_change) = event {
+                if target_change.target_change_type
+                    == target_change::TargetChangeType::Remove as i32
+                {
+                    assert_eq!(target_change.target_ids, vec![1]);
+                    break;
+                }
+            }
+        }
+
+        for obj in [&inserted[1], &inserted[2]] {
+            db.fluent()
+                .update()
+                .in_col(TEST_COLLECTION_NAME)
+                .document_id(&obj.some_id)
+                .object(&TestStructure {
+                    some_num: obj.some_num + 10,
+                    ..obj.clone()
+                })
+                .execute::<TestStructure>()
+                .await?;
+        }
+
+        // Only the remaining target receives the changes
+        let change = next_doc_change(&mut event_receiver).await.unwrap();
+        assert_eq!(change.target_ids, vec![2]);
+        assert!(change
+            .document
+            .unwrap()
+            .name
+            .ends_with(&inserted[2].some_id));
+
+        // Adding an existing target ID is rejected until the target is removed
+        assert!(matches!(
+            db.fluent()
+                .select()
+                .by_id_in(TEST_COLLECTION_NAME)
+                .batch_listen([&inserted[3].some_id])
+                .add_target(FirestoreListenerTarget::new(2), &mut listener),
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        listener.remove_target(&FirestoreListenerTarget::new(2))?;
+        db.fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .batch_listen([&inserted[3].some_id])
+            .add_target(FirestoreListenerTarget::new(2), &mut listener)?;
+
+        let change = next_doc_change(&mut event_receiver).await.unwrap();
+        assert_eq!(change.target_ids, vec![2]);
+        assert!(change
+            .document
+            .unwrap()
+            .name
+            .ends_with(&inserted[3].some_id));
+
+        listener.shutdown().await?;
+
+        Ok(())
+    }
+}
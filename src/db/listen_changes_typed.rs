@@ -0,0 +1,376 @@
+use crate::db::listen_changes::BoxedErrResult;
+use crate::timestamp_utils::from_timestamp;
+use crate::{
+    FirestoreDb, FirestoreListenEvent, FirestoreListenSupport, FirestoreListener,
+    FirestoreListenerTarget, FirestoreResult, FirestoreResumeStateStorage,
+};
+use chrono::prelude::*;
+use gcloud_sdk::google::firestore::v1::*;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FirestoreDocChangeType {
+    /// The document has been added to the target results (including the initial snapshot)
+    Added,
+    /// The document has been changed and still matches the target
+    Modified,
+    /// The document doesn't match the target anymore
+    Removed,
+    /// The document has been deleted
+    Deleted,
+}
+
+#[derive(Debug, Clone)]
+pub struct FirestoreDocChange<T> {
+    pub change_type: FirestoreDocChangeType,
+    pub document_id: String,
+    pub document_path: String,
+    /// `None` for the removed and deleted documents
+    pub update_time: Option<DateTime<Utc>>,
+    pub target_ids: Vec<FirestoreListenerTarget>,
+    /// The deserialized document. Available only for `Added` and `Modified` changes.
+    pub object: Option<T>,
+}
+
+/// Tracks documents known to each target of a listener to distinguish added documents from modified ones.
+#[derive(Debug, Default)]
+pub(crate) struct FirestoreDocChangesTracker {
+    known_documents: Mutex<HashSet<(i32, String)>>,
+}
+
+impl FirestoreDocChangesTracker {
+    /// Converts a listen event to typed changes, grouping the targets by the change type.
+    /// Returns no changes for events that aren't document changes.
+    pub(crate) fn doc_changes_from_event<T>(
+        &self,
+        event: FirestoreListenEvent,
+    ) -> Vec<FirestoreResult<FirestoreDocChange<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let mut known_documents = self
+            .known_documents
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match event {
+            listen_response::ResponseType::DocumentChange(doc_change) => {
+                let document = match doc_change.document {
+                    Some(document) => document,
+                    None => return vec![],
+                };
+                let mut changes = Vec::new();
+
+                if !doc_change.removed_target_ids.is_empty() {
+                    for target_id in &doc_change.removed_target_ids {
+                        known_documents.remove(&(*target_id, document.name.clone()));
+                    }
+                    changes.push(Self::removed_change(
+                        FirestoreDocChangeType::Removed,
+                        document.name.clone(),
+                        doc_change.removed_target_ids,
+                    ));
+                }
+
+                let (added_target_ids, modified_target_ids): (Vec<i32>, Vec<i32>) =
+                    doc_change.target_ids.into_iter().partition(|target_id| {
+                        known_documents.insert((*target_id, document.name.clone()))
+                    });
+
+                for (change_type, target_ids) in [
+                    (FirestoreDocChangeType::Added, added_target_ids),
+                    (FirestoreDocChangeType::Modified, modified_target_ids),
+                ] {
+                    if !target_ids.is_empty() {
+                        changes.push(Self::document_change(change_type, &document, target_ids));
+                    }
+                }
+
+                changes
+            }
+            listen_response::ResponseType::DocumentDelete(doc_delete) => {
+                known_documents.retain(|(_, document_name)| document_name != &doc_delete.document);
+                vec![Self::removed_change(
+                    FirestoreDocChangeType::Deleted,
+                    doc_delete.document,
+                    doc_delete.removed_target_ids,
+                )]
+            }
+            listen_response::ResponseType::DocumentRemove(doc_remove) => {
+                if doc_remove.removed_target_ids.is_empty() {
+                    known_documents
+                        .retain(|(_, document_name)| document_name != &doc_remove.document);
+                } else {
+                    for target_id in &doc_remove.removed_target_ids {
+                        known_documents.remove(&(*target_id, doc_remove.document.clone()));
+                    }
+                }
+                vec![Self::removed_change(
+                    FirestoreDocChangeType::Removed,
+                    doc_remove.document,
+                    doc_remove.removed_target_ids,
+                )]
+            }
+            listen_response::ResponseType::TargetChange(_)
+            | listen_response::ResponseType::Filter(_) => vec![],
+        }
+    }
+
+    fn document_change<T>(
+        change_type: FirestoreDocChangeType,
+        document: &Document,
+        target_ids: Vec<i32>,
+    ) -> FirestoreResult<FirestoreDocChange<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let obj = FirestoreDb::deserialize_doc_to::<T>(document)?;
+        Ok(FirestoreDocChange {
+            change_type,
+            document_id: document_id_from_path(&document.name),
+            document_path: document.name.clone(),
+            update_time: document
+                .update_time
+                .clone()
+                .map(from_timestamp)
+                .transpose()?,
+            target_ids: Self::targets(target_ids),
+            object: Some(obj),
+        })
+    }
+
+    // The events of removed documents have only the read time, not the update time
+    fn removed_change<T>(
+        change_type: FirestoreDocChangeType,
+        document_path: String,
+        target_ids: Vec<i32>,
+    ) -> FirestoreResult<FirestoreDocChange<T>> {
+        Ok(FirestoreDocChange {
+            change_type,
+            document_id: document_id_from_path(&document_path),
+            document_path,
+            update_time: None,
+            target_ids: Self::targets(target_ids),
+            object: None,
+        })
+    }
+
+    fn targets(target_ids: Vec<i32>) -> Vec<FirestoreListenerTarget> {
+        target_ids
+            .into_iter()
+            .map(FirestoreListenerTarget::new)
+            .collect()
+    }
+}
+
+pub(crate) fn document_id_from_path(document_path: &str) -> String {
+    document_path
+        .rsplit('/')
+        .next()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| document_path.to_string())
+}
+
+impl<D, S> FirestoreListener<D, S>
+where
+    D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+{
+    /// Starts the listener with typed document changes.
+    /// A document changed for several targets is reported once for each change type.
+    /// Deserialization errors are provided to the callback per change and don't stop the listener.
+    pub async fn start_typed<T, FN, F>(&mut self, cb: FN) -> FirestoreResult<()>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'static,
+        FN: Fn(FirestoreResult<FirestoreDocChange<T>>) -> F + Send + Sync + 'static,
+        F: Future<Output = BoxedErrResult<()>> + Send + 'static,
+    {
+        let tracker = Arc::new(FirestoreDocChangesTracker::default());
+        let cb = Arc::new(cb);
+
+        self.start(move |event| {
+            let doc_changes = tracker.doc_changes_from_event::<T>(event);
+            let cb = cb.clone();
+            async move {
+                if doc_changes.is_empty() {
+                    trace!("Skipping a listen event without document changes");
+                }
+                for doc_change in doc_changes {
+                    cb(doc_change).await?;
+                }
+                Ok(())
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp_utils::to_timestamp;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, PartialEq, Deserialize)]
+    struct TestStructure {
+        some_num: i64,
+    }
+
+    const DOC_NAME: &str = "projects/test/databases/(default)/documents/test/doc1";
+
+    fn doc_change_event(
+        some_num: Value,
+        target_ids: Vec<i32>,
+        removed_target_ids: Vec<i32>,
+    ) -> FirestoreListenEvent {
+        listen_response::ResponseType::DocumentChange(DocumentChange {
+            document: Some(Document {
+                name: DOC_NAME.to_string(),
+                fields: HashMap::from([("some_num".to_string(), some_num)]),
+                create_time: None,
+                update_time: Some(to_timestamp(Utc.timestamp_opt(1000, 0).unwrap())),
+            }),
+            target_ids,
+            removed_target_ids,
+        })
+    }
+
+    fn integer_value(value: i64) -> Value {
+        Value {
+            value_type: Some(value::ValueType::IntegerValue(value)),
+        }
+    }
+
+    fn change_types(
+        changes: Vec<FirestoreResult<FirestoreDocChange<TestStructure>>>,
+    ) -> Vec<(FirestoreDocChangeType, Vec<FirestoreListenerTarget>)> {
+        changes
+            .into_iter()
+            .map(|change| {
+                let change = change.unwrap();
+                (change.change_type, change.target_ids)
+            })
+            .collect()
+    }
+
+    fn targets(target_ids: &[i32]) -> Vec<FirestoreListenerTarget> {
+        FirestoreDocChangesTracker::targets(target_ids.to_vec())
+    }
+
+    #[test]
+    fn doc_changes_from_events() {
+        let tracker = FirestoreDocChangesTracker::default();
+
+        let mut added = tracker.doc_changes_from_event::<TestStructure>(doc_change_event(
+            integer_value(1),
+            vec![1],
+            vec![],
+        ));
+        assert_eq!(added.len(), 1);
+        let added = added.remove(0).unwrap();
+        assert_eq!(added.change_type, FirestoreDocChangeType::Added);
+        assert_eq!(added.document_id, "doc1");
+        assert_eq!(added.update_time, Some(Utc.timestamp_opt(1000, 0).unwrap()));
+        assert_eq!(added.target_ids, targets(&[1]));
+        assert_eq!(added.object, Some(TestStructure { some_num: 1 }));
+
+        let invalid = tracker.doc_changes_from_event::<TestStructure>(doc_change_event(
+            Value {
+                value_type: Some(value::ValueType::StringValue("invalid".to_string())),
+            },
+            vec![1],
+            vec![],
+        ));
+        assert!(matches!(invalid.as_slice(), [Err(_)]));
+
+        let mut modified = tracker.doc_changes_from_event::<TestStructure>(doc_change_event(
+            integer_value(2),
+            vec![1],
+            vec![],
+        ));
+        let modified = modified.remove(0).unwrap();
+        assert_eq!(modified.change_type, FirestoreDocChangeType::Modified);
+        assert_eq!(modified.object, Some(TestStructure { some_num: 2 }));
+
+        let mut deleted = tracker.doc_changes_from_event::<TestStructure>(
+            listen_response::ResponseType::DocumentDelete(DocumentDelete {
+                document: DOC_NAME.to_string(),
+                removed_target_ids: vec![1],
+                read_time: Some(to_timestamp(Utc.timestamp_opt(2000, 0).unwrap())),
+            }),
+        );
+        let deleted = deleted.remove(0).unwrap();
+        assert_eq!(deleted.change_type, FirestoreDocChangeType::Deleted);
+        assert_eq!(deleted.update_time, None);
+        assert_eq!(deleted.object, None);
+
+        assert!(tracker
+            .doc_changes_from_event::<TestStructure>(listen_response::ResponseType::Filter(
+                ExistenceFilter {
+                    target_id: 1,
+                    count: 0,
+                    unchanged_names: None,
+                }
+            ))
+            .is_empty());
+    }
+
+    #[test]
+    fn doc_changes_for_several_targets() {
+        let tracker = FirestoreDocChangesTracker::default();
+        let doc_changes = |target_ids: Vec<i32>, removed_target_ids: Vec<i32>| {
+            change_types(
+                tracker.doc_changes_from_event::<TestStructure>(doc_change_event(
+                    integer_value(1),
+                    target_ids,
+                    removed_target_ids,
+                )),
+            )
+        };
+
+        assert_eq!(
+            doc_changes(vec![1], vec![]),
+            vec![(FirestoreDocChangeType::Added, targets(&[1]))]
+        );
+
+        // The document enters the second target while it stays in the first one
+        assert_eq!(
+            doc_changes(vec![1, 2], vec![]),
+            vec![
+                (FirestoreDocChangeType::Added, targets(&[2])),
+                (FirestoreDocChangeType::Modified, targets(&[1])),
+            ]
+        );
+
+        // The document leaves the first target only
+        assert_eq!(
+            doc_changes(vec![2], vec![1]),
+            vec![
+                (FirestoreDocChangeType::Removed, targets(&[1])),
+                (FirestoreDocChangeType::Modified, targets(&[2])),
+            ]
+        );
+
+        let removed = tracker.doc_changes_from_event::<TestStructure>(
+            listen_response::ResponseType::DocumentRemove(DocumentRemove {
+                document: DOC_NAME.to_string(),
+                removed_target_ids: vec![2],
+                read_time: Some(to_timestamp(Utc.timestamp_opt(2000, 0).unwrap())),
+            }),
+        );
+        assert!(matches!(
+            removed.as_slice(),
+            [Ok(change)] if change.change_type == FirestoreDocChangeType::Removed && change.update_time.is_none()
+        ));
+
+        assert_eq!(
+            doc_changes(vec![1, 2], vec![]),
+            vec![(FirestoreDocChangeType::Added, targets(&[1, 2]))]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::db::listen_changes::BoxedErrResult;
+use crate::{
+    FirestoreGetByIdSupport, FirestoreListenerTarget, FirestoreListenerTargetResumeType,
+    FirestoreListenerToken, FirestoreResumeStateStorage, FirestoreUpdateSupport,
+};
+use async_trait::async_trait;
+use rvstruct::ValueStruct;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Keeps resume tokens in memory, so the listener resumes only within the same process.
+#[derive(Clone, Debug, Default)]
+pub struct FirestoreMemListenStateStorage {
+    tokens: Arc<RwLock<HashMap<FirestoreListenerTarget, FirestoreListenerToken>>>,
+}
+
+impl FirestoreMemListenStateStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl FirestoreResumeStateStorage for FirestoreMemListenStateStorage {
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> BoxedErrResult<Option<FirestoreListenerTargetResumeType>> {
+        Ok(self
+            .tokens
+            .read()
+            .await
+            .get(target)
+            .cloned()
+            .map(FirestoreListenerTargetResumeType::Token))
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> BoxedErrResult<()> {
+        self.tokens.write().await.insert(target.clone(), token);
+        Ok(())
+    }
+}
+
+/// Keeps resume tokens in files, a file per target.
+#[derive(Clone, Debug)]
+pub struct FirestoreFileListenStateStorage {
+    directory: PathBuf,
+    file_prefix: String,
+}
+
+impl FirestoreFileListenStateStorage {
+    pub fn new<P>(directory: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            directory: directory.into(),
+            file_prefix: "firestore-listener-".to_string(),
+        }
+    }
+
+    pub fn with_file_prefix<S>(self, file_prefix: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self {
+            file_prefix: file_prefix.as_ref().to_string(),
+            ..self
+        }
+    }
+
+    fn target_file_path(&self, target: &FirestoreListenerTarget) -> PathBuf {
+        self.directory
+            .join(format!("{}{}.token", self.file_prefix, target.value()))
+    }
+}
+
+#[async_trait]
+impl FirestoreResumeStateStorage for FirestoreFileListenStateStorage {
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> BoxedErrResult<Option<FirestoreListenerTargetResumeType>> {
+        match tokio::fs::read_to_string(self.target_file_path(target)).await {
+            Ok(hex_token) => Ok(Some(FirestoreListenerTargetResumeType::Token(
+                FirestoreListenerToken::new(hex::decode(hex_token.trim())?),
+            ))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Box::new(err)),
+        }
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> BoxedErrResult<()> {
+        let file_path = self.target_file_path(target);
+        let mut temp_file_path = file_path.clone().into_os_string();
+        temp_file_path.push(".tmp");
+
+        // Renaming the file makes the update atomic
+        tokio::fs::write(&temp_file_path, hex::encode(token.value())).await?;
+        tokio::fs::rename(&temp_file_path, &file_path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FirestoreListenStateDocument {
+    resume_token: String,
+}
+
+/// Keeps resume tokens in Firestore documents, a document per target.
+#[derive(Clone, Debug)]
+pub struct FirestoreDbListenStateStorage<D> {
+    db: D,
+    collection_id: String,
+    document_id_prefix: String,
+}
+
+impl<D> FirestoreDbListenStateStorage<D>
+where
+    D: FirestoreGetByIdSupport + FirestoreUpdateSupport + Clone + Send + Sync,
+{
+    pub fn new<S>(db: D, collection_id: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self {
+            db,
+            collection_id: collection_id.as_ref().to_string(),
+            document_id_prefix: "listener-".to_string(),
+        }
+    }
+
+    pub fn with_document_id_prefix<S>(self, document_id_prefix: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self {
+            document_id_prefix: document_id_prefix.as_ref().to_string(),
+            ..self
+        }
+    }
+
+    fn target_document_id(&self, target: &FirestoreListenerTarget) -> String {
+        format!("{}{}", self.document_id_prefix, target.value())
+    }
+}
+
+#[async_trait]
+impl<D> FirestoreResumeStateStorage for FirestoreDbListenStateStorage<D>
+where
+    D: FirestoreGetByIdSupport + FirestoreUpdateSupport + Clone + Send + Sync,
+{
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> BoxedErrResult<Option<FirestoreListenerTargetResumeType>> {
+        let state_doc: Option<FirestoreListenStateDocument> = self
+            .db
+            .get_obj_if_exists(
+                self.collection_id.as_str(),
+                self.target_document_id(target),
+                None,
+            )
+            .await?;
+
+        Ok(state_doc
+            .map(|state_doc| hex::decode(state_doc.resume_token))
+            .transpose()?
+            .map(|token| {
+                FirestoreListenerTargetResumeType::Token(FirestoreListenerToken::new(token))
+            }))
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> BoxedErrResult<()> {
+        let _: FirestoreListenStateDocument = self
+            .db
+            .update_obj(
+                self.collection_id.as_str(),
+                self.target_document_id(target),
+                &FirestoreListenStateDocument {
+                    resume_token: hex::encode(token.value()),
+                },
+                None,
+                None,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check_storage<S>(storage: S) -> BoxedErrResult<()>
+    where
+        S: FirestoreResumeStateStorage,
+    {
+        let target = FirestoreListenerTarget::new(42);
+        assert!(storage.read_resume_state(&target).await?.is_none());
+
+        storage
+            .update_resume_token(&target, FirestoreListenerToken::new(vec![1, 2, 3]))
+            .await?;
+        storage
+            .update_resume_token(&target, FirestoreListenerToken::new(vec![4, 5]))
+            .await?;
+
+        assert!(matches!(
+            storage.read_resume_state(&target).await?,
+            Some(FirestoreListenerTargetResumeType::Token(token)) if token.value() == &vec![4, 5]
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mem_listen_state_storage() -> BoxedErrResult<()> {
+        check_storage(FirestoreMemListenStateStorage::new()).await
+    }
+
+    #[tokio::test]
+    async fn file_listen_state_storage() -> BoxedErrResult<()> {
+        let directory = std::env::temp_dir().join(format!(
+            "firestore-listen-state-test-{}",
+            std::process::id()
+        ));
+        tokio::fs::create_dir_all(&directory).await?;
+        let result = check_storage(FirestoreFileListenStateStorage::new(&directory)).await;
+        tokio::fs::remove_dir_all(&directory).await?;
+        result
+    }
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod memory_db_tests {
+    use crate::*;
+
+    #[tokio::test]
+    async fn db_listen_state_storage_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let storage = FirestoreDbListenStateStorage::new(db.clone(), "listener-state");
+        let target = FirestoreListenerTarget::new(1);
+
+        assert!(storage.read_resume_state(&target).await.unwrap().is_none());
+
+        storage
+            .update_resume_token(&target, FirestoreListenerToken::new(vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            storage.read_resume_state(&target).await.unwrap(),
+            Some(FirestoreListenerTargetResumeType::Token(token)) if token.value() == &vec![1, 2, 3]
+        ));
+
+        Ok(())
+    }
+}
@@ -20,6 +20,17 @@ pub use precondition_models::*;
 mod query;
 pub use query::*;
 
+mod query_write;
+pub use query_write::*;
+
+mod query_page;
+pub use query_page::*;
+
+mod query_validation;
+pub use query_validation::*;
+
+pub(crate) mod query_ordering;
+
 mod aggregated_query;
 pub use aggregated_query::*;
 
@@ -29,6 +40,15 @@ pub use list::*;
 mod listen_changes;
 pub use listen_changes::*;
 
+mod listen_changes_typed;
+pub use listen_changes_typed::*;
+
+mod listen_state_storage;
+pub use listen_state_storage::*;
+
+mod query_view;
+pub use query_view::*;
+
 use crate::FirestoreResult;
 use gcloud_sdk::google::firestore::v1::firestore_client::FirestoreClient;
 use gcloud_sdk::google::firestore::v1::*;
@@ -46,11 +66,14 @@ mod transaction_models;
 pub use transaction_models::*;
 
 mod transaction_ops;
-use transaction_ops::*;
+pub(crate) use transaction_ops::*;
 
 mod session_params;
 pub use session_params::*;
 
+mod retry_policy;
+pub use retry_policy::*;
+
 mod consistency_selector;
 pub use consistency_selector::*;
 
@@ -64,10 +87,13 @@ mod batch_streaming_writer;
 pub use batch_streaming_writer::*;
 mod batch_simple_writer;
 pub use batch_simple_writer::*;
+mod bulk_writer;
+pub use bulk_writer::*;
 
 use crate::errors::{
     FirestoreError, FirestoreInvalidParametersError, FirestoreInvalidParametersPublicDetails,
 };
+use rand::Rng;
 use std::fmt::Formatter;
 use std::sync::Arc;
 
@@ -77,8 +103,59 @@ pub use transform_models::*;
 struct FirestoreDbInner {
     database_path: String,
     doc_path: String,
+    request_params: tonic::metadata::MetadataValue<tonic::metadata::Ascii>,
     options: FirestoreDbOptions,
+    retry_policy: Arc<dyn FirestoreRetryPolicy>,
     client: GoogleApi<FirestoreClient<GoogleAuthMiddleware>>,
+    token_scopes: Vec<String>,
+    token_source_type: TokenSourceType,
+}
+
+impl FirestoreDbInner {
+    fn new(
+        options: FirestoreDbOptions,
+        client: GoogleApi<FirestoreClient<GoogleAuthMiddleware>>,
+        token_scopes: Vec<String>,
+        token_source_type: TokenSourceType,
+    ) -> FirestoreResult<Self> {
+        let database_path = format!(
+            "projects/{}/databases/{}",
+            options.google_project_id, options.database_id
+        );
+        let doc_path = format!("{database_path}/documents");
+
+        // Firestore routes the requests to named databases by this header
+        let request_params = format!(
+            "project_id={}&database_id={}",
+            encode_request_param(&options.google_project_id),
+            encode_request_param(&options.database_id)
+        )
+        .parse()
+        .map_err(|_| {
+            FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                FirestoreInvalidParametersPublicDetails::new(
+                    "database_id".to_string(),
+                    format!("Invalid database ID provided: {}", options.database_id),
+                ),
+            ))
+        })?;
+
+        #[allow(deprecated)]
+        let retry_policy = options.retry_policy.clone().unwrap_or_else(|| {
+            Arc::new(FirestoreExponentialRetryPolicy::new().with_max_retries(options.max_retries))
+        });
+
+        Ok(Self {
+            database_path,
+            doc_path,
+            request_params,
+            options,
+            retry_policy,
+            client,
+            token_scopes,
+            token_source_type,
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -87,6 +164,10 @@ pub struct FirestoreDb {
     session_params: FirestoreDbSessionParams,
 }
 
+const DOCUMENT_ID_ALPHABET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const DOCUMENT_ID_LENGTH: usize = 20;
+
 const GOOGLE_FIREBASE_API_URL: &str = "https://firestore.googleapis.com";
 const GOOGLE_FIRESTORE_EMULATOR_HOST_ENV: &str = "FIRESTORE_EMULATOR_HOST";
 
@@ -115,10 +196,6 @@ impl FirestoreDb {
         token_scopes: Vec<String>,
         token_source_type: TokenSourceType,
     ) -> FirestoreResult<Self> {
-        let firestore_database_path =
-            format!("projects/{}/databases/(default)", options.google_project_id);
-        let firestore_database_doc_path = format!("{firestore_database_path}/documents");
-
         let effective_firebase_api_url = options
             .firebase_api_url
             .clone()
@@ -129,6 +206,11 @@ impl FirestoreDb {
             })
             .unwrap_or_else(|| GOOGLE_FIREBASE_API_URL.to_string());
 
+        let firestore_database_path = format!(
+            "projects/{}/databases/{}",
+            options.google_project_id, options.database_id
+        );
+
         info!(
             "Creating a new DB client: {}. API: {} Token scopes: {}",
             firestore_database_path,
@@ -139,25 +221,32 @@ impl FirestoreDb {
         let client = GoogleApiClient::from_function_with_token_source(
             FirestoreClient::new,
             effective_firebase_api_url,
-            Some(firestore_database_path.clone()),
-            token_scopes,
-            token_source_type,
+            Some(firestore_database_path),
+            token_scopes.clone(),
+            token_source_type.clone(),
         )
         .await?;
 
-        let inner = FirestoreDbInner {
-            database_path: firestore_database_path,
-            doc_path: firestore_database_doc_path,
-            client,
-            options,
-        };
-
         Ok(Self {
-            inner: Arc::new(inner),
+            inner: Arc::new(FirestoreDbInner::new(
+                options,
+                client,
+                token_scopes,
+                token_source_type,
+            )?),
             session_params: FirestoreDbSessionParams::new(),
         })
     }
 
+    /// Generates a random document ID the same way the official Firestore SDKs do:
+    /// 20 alphanumeric characters, so the ID is known before writing the document.
+    pub fn generate_document_id() -> String {
+        let mut rng = rand::thread_rng();
+        (0..DOCUMENT_ID_LENGTH)
+            .map(|_| DOCUMENT_ID_ALPHABET[rng.gen_range(0..DOCUMENT_ID_ALPHABET.len())] as char)
+            .collect()
+    }
+
     pub fn deserialize_doc_to<T>(doc: &Document) -> FirestoreResult<T>
     where
         for<'de> T: Deserialize<'de>,
@@ -174,7 +263,7 @@ impl FirestoreDb {
 
     pub async fn ping(&self) -> FirestoreResult<()> {
         // Reading non-existing document just to check that database is available to read
-        self.get_doc_by_path(self.get_database_path().clone(), None, 0)
+        self.get_doc_by_path(self.get_database_path().clone(), None)
             .await
             .ok();
         Ok(())
@@ -221,6 +310,32 @@ impl FirestoreDb {
         &self.inner.client
     }
 
+    /// Creates a client for another database in the same project with the same options and credentials.
+    /// The client has its own channel, since the database is a part of the channel metadata.
+    pub async fn clone_with_database<S>(&self, database_id: S) -> FirestoreResult<Self>
+    where
+        S: AsRef<str>,
+    {
+        Ok(Self::with_options_token_source(
+            self.inner
+                .options
+                .clone()
+                .with_database_id(database_id.as_ref().to_string()),
+            self.inner.token_scopes.clone(),
+            self.inner.token_source_type.clone(),
+        )
+        .await?
+        .with_session_params(self.session_params.clone()))
+    }
+
+    pub(crate) fn create_request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("x-goog-request-params", self.inner.request_params.clone());
+        request
+    }
+
     #[inline]
     pub fn clone_with_session_params(&self, session_params: FirestoreDbSessionParams) -> Self {
         Self {
@@ -248,6 +363,23 @@ impl FirestoreDb {
                 .with_consistency_selector(consistency_selector),
         )
     }
+
+    #[inline]
+    pub fn clone_with_retry_policy(&self, retry_policy: Arc<dyn FirestoreRetryPolicy>) -> Self {
+        self.clone_with_session_params(self.session_params.clone().with_retry_policy(retry_policy))
+    }
+}
+
+fn encode_request_param(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
 }
 
 fn ensure_url_scheme(url: String) -> String {
@@ -293,9 +425,36 @@ where
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    /// A database connected to a local socket, for the tests checking requests without sending them
+    pub(crate) async fn offline_test_db() -> FirestoreDb {
+        offline_test_db_with_options(FirestoreDbOptions::new("test-project".to_string())).await
+    }
+
+    pub(crate) async fn offline_test_db_with_options(options: FirestoreDbOptions) -> FirestoreDb {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        FirestoreDb::with_options_token_source(
+            options.with_firebase_api_url(format!("http://{address}")),
+            vec![],
+            TokenSourceType::Json(
+                r#"{"type":"authorized_user","client_id":"test","client_secret":"test","refresh_token":"test"}"#
+                    .to_string(),
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
     #[test]
     fn test_safe_document_path() {
         assert_eq!(
@@ -346,4 +505,18 @@ mod tests {
             "http://invalid:localhost:8080"
         );
     }
+
+    #[test]
+    fn test_encode_request_param() {
+        assert_eq!(encode_request_param("test-project"), "test-project");
+        assert_eq!(encode_request_param("(default)"), "%28default%29");
+    }
+
+    #[test]
+    fn test_generate_document_id() {
+        let document_id = FirestoreDb::generate_document_id();
+        assert_eq!(document_id.len(), 20);
+        assert!(document_id.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(document_id, FirestoreDb::generate_document_id());
+    }
 }
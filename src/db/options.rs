@@ -1,11 +1,44 @@
+#![allow(deprecated)] // The builder and the comparison still use the deprecated max_retries
+
+use crate::FirestoreRetryPolicy;
 use rsb_derive::Builder;
+use std::sync::Arc;
+
+pub const FIRESTORE_DEFAULT_DATABASE_ID: &str = "(default)";
 
-#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+#[derive(Debug, Clone, Builder)]
 pub struct FirestoreDbOptions {
     pub google_project_id: String,
 
+    #[default = "FIRESTORE_DEFAULT_DATABASE_ID.to_string()"]
+    pub database_id: String,
+
+    /// The retries of the default retry policy, when `retry_policy` isn't specified
+    #[deprecated(note = "Use retry_policy with FirestoreExponentialRetryPolicy instead")]
     #[default = "3"]
     pub max_retries: usize,
 
+    /// `FirestoreExponentialRetryPolicy` with `max_retries` by default
+    pub retry_policy: Option<Arc<dyn FirestoreRetryPolicy>>,
+
     pub firebase_api_url: Option<String>,
 }
+
+// The retry policies are compared by their instances
+impl PartialEq for FirestoreDbOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.google_project_id == other.google_project_id
+            && self.database_id == other.database_id
+            && self.max_retries == other.max_retries
+            && match (&self.retry_policy, &other.retry_policy) {
+                (Some(policy), Some(other_policy)) => {
+                    Arc::as_ptr(policy) as *const () == Arc::as_ptr(other_policy) as *const ()
+                }
+                (None, None) => true,
+                _ => false,
+            }
+            && self.firebase_api_url == other.firebase_api_url
+    }
+}
+
+impl Eq for FirestoreDbOptions {}
@@ -1,3 +1,4 @@
+use crate::db::query_ordering::{limit_to_last_query, reject_limit_to_last};
 use crate::{
     FirestoreDb, FirestoreError, FirestorePartition, FirestorePartitionQueryParams,
     FirestoreQueryCursor, FirestoreQueryParams, FirestoreResult,
@@ -6,8 +7,6 @@ use async_trait::async_trait;
 use chrono::prelude::*;
 use futures::future::BoxFuture;
 use futures::stream::BoxStream;
-use futures::FutureExt;
-use futures::TryFutureExt;
 use futures::TryStreamExt;
 use futures::{future, StreamExt};
 use gcloud_sdk::google::firestore::v1::*;
@@ -75,145 +74,111 @@ impl FirestoreDb {
         &self,
         params: FirestoreQueryParams,
     ) -> FirestoreResult<tonic::Request<RunQueryRequest>> {
-        Ok(tonic::Request::new(RunQueryRequest {
+        params.validate()?;
+        reject_limit_to_last(&params, "this query")?;
+        Ok(self.create_request(RunQueryRequest {
             parent: params
                 .parent
                 .as_ref()
                 .unwrap_or_else(|| self.get_documents_path())
                 .clone(),
-            consistency_selector: self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?,
+            consistency_selector: self.request_consistency_selector(params.read_time)?,
             query_type: Some(run_query_request::QueryType::StructuredQuery(params.into())),
         }))
     }
 
-    fn stream_query_doc_with_retries<'a, 'b>(
-        &'a self,
+    async fn stream_query_doc_with_retries<'b>(
+        &self,
         params: FirestoreQueryParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<BoxStream<'b, FirestoreResult<Option<Document>>>>> {
-        async move {
-            let query_request = self.create_query_request(params.clone())?;
-            let begin_query_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .run_query(query_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(query_response) => {
-                    let query_stream = query_response
-                        .into_inner()
-                        .map_ok(|r| r.document)
-                        .map_err(|e| e.into())
-                        .boxed();
+        span: &Span,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Option<Document>>>> {
+        let begin_query_utc: DateTime<Utc> = Utc::now();
+
+        let query_response = self
+            .with_retries(|| {
+                let query_request = self.create_query_request(params.clone());
+                async move { Ok(self.client().get().run_query(query_request?).await?) }
+            })
+            .await?;
 
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+        let query_stream = query_response
+            .into_inner()
+            .map_ok(|r| r.document)
+            .map_err(|e| e.into())
+            .boxed();
 
-                    span.record(
-                        "/firestore/response_time",
-                        query_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Querying stream of documents in {:?} took {}ms",
-                            params.collection_id,
-                            query_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(query_stream)
-                }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-
-                        self.stream_query_doc_with_retries(params, retries + 1, span)
-                            .await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+
+        span.record(
+            "/firestore/response_time",
+            query_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Querying stream of documents in {:?} took {}ms",
+                params.collection_id,
+                query_duration.num_milliseconds()
+            );
+        });
+
+        Ok(query_stream)
     }
 
-    fn query_doc_with_retries<'a>(
-        &'a self,
+    async fn query_doc_with_retries(
+        &self,
         params: FirestoreQueryParams,
-        retries: usize,
-        span: &'a Span,
-    ) -> BoxFuture<'a, FirestoreResult<Vec<Document>>> {
-        async move {
-            let collection_id = params.collection_id.to_string();
-            let query_request = self.create_query_request(params.clone())?;
-            let begin_query_utc: DateTime<Utc> = Utc::now();
-
-            match self
-                .client()
-                .get()
-                .run_query(query_request)
-                .map_err(|e| e.into())
-                .await
-            {
-                Ok(query_response) => {
-                    let query_stream = query_response
+        span: &Span,
+    ) -> FirestoreResult<Vec<Document>> {
+        let begin_query_utc: DateTime<Utc> = Utc::now();
+
+        // Errors while reading the response stream are retried as well
+        let query_result: Vec<Document> = self
+            .with_retries(|| {
+                let query_request = self.create_query_request(params.clone());
+                async move {
+                    Ok(self
+                        .client()
+                        .get()
+                        .run_query(query_request?)
+                        .await?
                         .into_inner()
                         .map_ok(|rs| rs.document)
                         .try_collect::<Vec<Option<Document>>>()
                         .await?
                         .into_iter()
                         .flatten()
-                        .collect();
-                    let end_query_utc: DateTime<Utc> = Utc::now();
-                    let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
-
-                    span.record(
-                        "/firestore/response_time",
-                        query_duration.num_milliseconds(),
-                    );
-                    span.in_scope(|| {
-                        debug!(
-                            "[DB]: Querying documents in {:?} took {}ms",
-                            collection_id,
-                            query_duration.num_milliseconds()
-                        );
-                    });
-
-                    Ok(query_stream)
+                        .collect())
                 }
-                Err(err) => match err {
-                    FirestoreError::DatabaseError(ref db_err)
-                        if db_err.retry_possible && retries < self.inner.options.max_retries =>
-                    {
-                        warn!(
-                            "[DB]: Failed with {}. Retrying: {}/{}",
-                            db_err,
-                            retries + 1,
-                            self.inner.options.max_retries
-                        );
-                        self.query_doc_with_retries(params, retries + 1, span).await
-                    }
-                    _ => Err(err),
-                },
-            }
-        }
-        .boxed()
+            })
+            .await?;
+
+        let end_query_utc: DateTime<Utc> = Utc::now();
+        let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
+
+        span.record(
+            "/firestore/response_time",
+            query_duration.num_milliseconds(),
+        );
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Querying documents in {:?} took {}ms",
+                params.collection_id,
+                query_duration.num_milliseconds()
+            );
+        });
+
+        Ok(query_result)
+    }
+
+    async fn query_limit_to_last_doc(
+        &self,
+        reversed_params: FirestoreQueryParams,
+        span: &Span,
+    ) -> FirestoreResult<Vec<Document>> {
+        let mut docs = self.query_doc_with_retries(reversed_params, span).await?;
+        docs.reverse();
+        Ok(docs)
     }
 }
 
@@ -227,7 +192,10 @@ impl FirestoreQuerySupport for FirestoreDb {
             "/firestore/collection_name" = collection_str.as_str(),
             "/firestore/response_time" = field::Empty
         );
-        self.query_doc_with_retries(params, 0, &span).await
+        match limit_to_last_query(&params)? {
+            Some(reversed_params) => self.query_limit_to_last_doc(reversed_params, &span).await,
+            None => self.query_doc_with_retries(params, &span).await,
+        }
     }
 
     async fn stream_query_doc<'b>(
@@ -243,7 +211,12 @@ impl FirestoreQuerySupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        let doc_stream = self.stream_query_doc_with_retries(params, 0, &span).await?;
+        if let Some(reversed_params) = limit_to_last_query(&params)? {
+            let docs = self.query_limit_to_last_doc(reversed_params, &span).await?;
+            return Ok(futures::stream::iter(docs).boxed());
+        }
+
+        let doc_stream = self.stream_query_doc_with_retries(params, &span).await?;
 
         Ok(Box::pin(doc_stream.filter_map(|doc_res| {
             future::ready(match doc_res {
@@ -270,7 +243,12 @@ impl FirestoreQuerySupport for FirestoreDb {
             "/firestore/response_time" = field::Empty
         );
 
-        let doc_stream = self.stream_query_doc_with_retries(params, 0, &span).await?;
+        if let Some(reversed_params) = limit_to_last_query(&params)? {
+            let docs = self.query_limit_to_last_doc(reversed_params, &span).await?;
+            return Ok(futures::stream::iter(docs.into_iter().map(Ok)).boxed());
+        }
+
+        let doc_stream = self.stream_query_doc_with_retries(params, &span).await?;
 
         Ok(Box::pin(doc_stream.filter_map(|doc_res| {
             future::ready(match doc_res {
@@ -336,21 +314,18 @@ impl FirestoreQuerySupport for FirestoreDb {
         params: FirestorePartitionQueryParams,
     ) -> BoxFuture<FirestoreResult<PeekableBoxStream<FirestoreResult<FirestoreQueryCursor>>>> {
         Box::pin(async move {
+            params.query_params.validate()?;
+            reject_limit_to_last(&params.query_params, "partition queries")?;
             let consistency_selector: Option<
                 gcloud_sdk::google::firestore::v1::partition_query_request::ConsistencySelector,
-            > = self
-                .session_params
-                .consistency_selector
-                .as_ref()
-                .map(|selector| selector.try_into())
-                .transpose()?;
+            > = self.request_consistency_selector(params.query_params.read_time)?;
 
             let stream: PeekableBoxStream<FirestoreResult<FirestoreQueryCursor>> =
                 futures::stream::unfold(
                     Some((params, consistency_selector)),
                     move |maybe_params| async move {
                         if let Some((params, maybe_consistency_selector)) = maybe_params {
-                            let request = tonic::Request::new(PartitionQueryRequest {
+                            let partition_query_request = PartitionQueryRequest {
                                 page_size: params.page_size as i32,
                                 partition_count: params.partition_count as i64,
                                 parent: params
@@ -366,9 +341,18 @@ impl FirestoreQuerySupport for FirestoreDb {
                                     ),
                                 ),
                                 page_token: params.page_token.clone().unwrap_or_default(),
-                            });
-
-                            match self.client().get().partition_query(request).await {
+                            };
+
+                            match self
+                                .with_retries(|| {
+                                    let request =
+                                        self.create_request(partition_query_request.clone());
+                                    async move {
+                                        Ok(self.client().get().partition_query(request).await?)
+                                    }
+                                })
+                                .await
+                            {
                                 Ok(response) => {
                                     let partition_response = response.into_inner();
                                     let firestore_cursors: Vec<FirestoreQueryCursor> =
@@ -392,7 +376,7 @@ impl FirestoreQuerySupport for FirestoreDb {
                                         Some((Ok(firestore_cursors), None))
                                     }
                                 }
-                                Err(err) => Some((Err(FirestoreError::from(err)), None)),
+                                Err(err) => Some((Err(err), None)),
                             }
                         } else {
                             None
@@ -543,3 +527,39 @@ impl FirestoreQuerySupport for FirestoreDb {
         })))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::offline_test_db;
+    use crate::timestamp_utils::to_timestamp;
+    use crate::{FirestoreConsistencySelector, FirestoreError};
+
+    #[tokio::test]
+    async fn query_request_read_time() -> FirestoreResult<()> {
+        let db = offline_test_db().await;
+        let read_time = Utc::now() - chrono::Duration::hours(1);
+        let params = FirestoreQueryParams::new("test".into()).with_read_time(read_time);
+
+        let request = db.create_query_request(params.clone())?;
+        assert_eq!(
+            request.get_ref().consistency_selector,
+            Some(run_query_request::ConsistencySelector::ReadTime(
+                to_timestamp(read_time)
+            ))
+        );
+
+        let transaction_db =
+            db.clone_with_consistency_selector(FirestoreConsistencySelector::Transaction(vec![
+                1, 2, 3,
+            ]));
+        match transaction_db.create_query_request(params) {
+            Err(FirestoreError::InvalidParametersError(err)) => {
+                assert_eq!(err.public.field, "read_time")
+            }
+            other => panic!("Unexpected result: {other:?}"),
+        }
+
+        Ok(())
+    }
+}
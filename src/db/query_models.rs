@@ -1,6 +1,7 @@
 #![allow(clippy::derive_partial_eq_without_eq)] // Since we may not be able to implement Eq for the changes coming from Firestore protos
 
 use crate::FirestoreValue;
+use chrono::prelude::*;
 use gcloud_sdk::google::firestore::v1::*;
 use rsb_derive::Builder;
 
@@ -37,6 +38,10 @@ pub struct FirestoreQueryParams {
     pub return_only_fields: Option<Vec<String>>,
     pub start_at: Option<FirestoreQueryCursor>,
     pub end_at: Option<FirestoreQueryCursor>,
+    /// Reads the documents as they were at this time
+    pub read_time: Option<DateTime<Utc>>,
+    /// Returns the last documents of the query, still in the query order. Requires `order_by`.
+    pub limit_to_last: Option<u32>,
 }
 
 impl From<FirestoreQueryParams> for StructuredQuery {
@@ -189,7 +194,14 @@ impl From<FirestoreQueryFilter> for structured_query::Filter {
             FirestoreQueryFilter::Composite(composite) => {
                 Some(structured_query::filter::FilterType::CompositeFilter(
                     structured_query::CompositeFilter {
-                        op: structured_query::composite_filter::Operator::And.into(),
+                        op: (match composite.operator {
+                            FirestoreQueryFilterCompositeOperator::And => {
+                                structured_query::composite_filter::Operator::And.into()
+                            }
+                            FirestoreQueryFilterCompositeOperator::Or => {
+                                structured_query::composite_filter::Operator::Or.into()
+                            }
+                        }),
                         filters: composite
                             .for_all_filters
                             .into_iter()
@@ -309,7 +321,16 @@ impl ToString for FirestoreQueryDirection {
 
 #[derive(Debug, PartialEq, Clone, Builder)]
 pub struct FirestoreQueryFilterComposite {
+    /// The filters combined with the `operator`. The name is kept from the times only `And` was supported.
     pub for_all_filters: Vec<FirestoreQueryFilter>,
+    #[default = "FirestoreQueryFilterCompositeOperator::And"]
+    pub operator: FirestoreQueryFilterCompositeOperator,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum FirestoreQueryFilterCompositeOperator {
+    And,
+    Or,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
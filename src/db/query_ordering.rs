@@ -0,0 +1,512 @@
+use crate::errors::*;
+use crate::{
+    FirestoreQueryCursor, FirestoreQueryDirection, FirestoreQueryFilter,
+    FirestoreQueryFilterCompare, FirestoreQueryOrder, FirestoreQueryParams, FirestoreResult,
+};
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use gcloud_sdk::google::firestore::v1::*;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub(crate) const DOCUMENT_NAME_FIELD: &str = "__name__";
+
+// Firestore sorts values of different types by the type first
+pub(crate) fn type_order(value: &Value) -> u8 {
+    match &value.value_type {
+        None | Some(ValueType::NullValue(_)) => 0,
+        Some(ValueType::BooleanValue(_)) => 1,
+        Some(ValueType::IntegerValue(_)) | Some(ValueType::DoubleValue(_)) => 2,
+        Some(ValueType::TimestampValue(_)) => 3,
+        Some(ValueType::StringValue(_)) => 4,
+        Some(ValueType::BytesValue(_)) => 5,
+        Some(ValueType::ReferenceValue(_)) => 6,
+        Some(ValueType::GeoPointValue(_)) => 7,
+        Some(ValueType::ArrayValue(_)) => 8,
+        Some(ValueType::MapValue(_)) => 9,
+    }
+}
+
+fn compare_f64(left: f64, right: f64) -> Ordering {
+    // NaN is ordered before any other number and is equal to itself
+    match (left.is_nan(), right.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => left.partial_cmp(&right).unwrap_or(Ordering::Equal),
+    }
+}
+
+fn compare_numbers(left: &ValueType, right: &ValueType) -> Ordering {
+    match (left, right) {
+        (ValueType::IntegerValue(l), ValueType::IntegerValue(r)) => l.cmp(r),
+        (ValueType::IntegerValue(l), ValueType::DoubleValue(r)) => compare_f64(*l as f64, *r),
+        (ValueType::DoubleValue(l), ValueType::IntegerValue(r)) => compare_f64(*l, *r as f64),
+        (ValueType::DoubleValue(l), ValueType::DoubleValue(r)) => compare_f64(*l, *r),
+        _ => Ordering::Equal,
+    }
+}
+
+pub(crate) fn compare_values(left: &Value, right: &Value) -> Ordering {
+    let type_ordering = type_order(left).cmp(&type_order(right));
+    if type_ordering != Ordering::Equal {
+        return type_ordering;
+    }
+
+    match (&left.value_type, &right.value_type) {
+        (Some(ValueType::BooleanValue(l)), Some(ValueType::BooleanValue(r))) => l.cmp(r),
+        (Some(l @ ValueType::IntegerValue(_)), Some(r))
+        | (Some(l @ ValueType::DoubleValue(_)), Some(r)) => compare_numbers(l, r),
+        (Some(ValueType::TimestampValue(l)), Some(ValueType::TimestampValue(r))) => {
+            (l.seconds, l.nanos).cmp(&(r.seconds, r.nanos))
+        }
+        (Some(ValueType::StringValue(l)), Some(ValueType::StringValue(r))) => l.cmp(r),
+        (Some(ValueType::BytesValue(l)), Some(ValueType::BytesValue(r))) => l.cmp(r),
+        (Some(ValueType::ReferenceValue(l)), Some(ValueType::ReferenceValue(r))) => {
+            l.split('/').cmp(r.split('/'))
+        }
+        (Some(ValueType::GeoPointValue(l)), Some(ValueType::GeoPointValue(r))) => {
+            compare_f64(l.latitude, r.latitude).then(compare_f64(l.longitude, r.longitude))
+        }
+        (Some(ValueType::ArrayValue(l)), Some(ValueType::ArrayValue(r))) => {
+            compare_arrays(&l.values, &r.values)
+        }
+        (Some(ValueType::MapValue(l)), Some(ValueType::MapValue(r))) => {
+            compare_maps(&l.fields, &r.fields)
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+fn compare_arrays(left: &[Value], right: &[Value]) -> Ordering {
+    for (l, r) in left.iter().zip(right.iter()) {
+        let ordering = compare_values(l, r);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    left.len().cmp(&right.len())
+}
+
+fn compare_maps(left: &HashMap<String, Value>, right: &HashMap<String, Value>) -> Ordering {
+    let mut left_entries: Vec<(&String, &Value)> = left.iter().collect();
+    let mut right_entries: Vec<(&String, &Value)> = right.iter().collect();
+    left_entries.sort_by(|a, b| a.0.cmp(b.0));
+    right_entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    for ((lk, lv), (rk, rv)) in left_entries.iter().zip(right_entries.iter()) {
+        let ordering = lk.cmp(rk).then_with(|| compare_values(lv, rv));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    left_entries.len().cmp(&right_entries.len())
+}
+
+pub(crate) fn reference_value(document_name: &str) -> Value {
+    Value {
+        value_type: Some(ValueType::ReferenceValue(document_name.to_string())),
+    }
+}
+
+// Splits a field path into segments, supporting the backtick quoting for the special characters
+pub(crate) fn parse_field_path(field_path: &str) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = field_path.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => quoted = !quoted,
+            '\\' if quoted => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '.' if !quoted => segments.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    segments.push(current);
+    segments
+}
+
+pub(crate) fn get_field<'a>(
+    fields: &'a HashMap<String, Value>,
+    field_path: &str,
+) -> Option<&'a Value> {
+    let segments = parse_field_path(field_path);
+    let (last, parents) = segments.split_last()?;
+    let mut current = fields;
+    for segment in parents {
+        match current.get(segment).and_then(|v| v.value_type.as_ref()) {
+            Some(ValueType::MapValue(map)) => current = &map.fields,
+            _ => return None,
+        }
+    }
+    current.get(last)
+}
+
+pub(crate) fn get_document_field(document: &Document, field_path: &str) -> Option<Value> {
+    if field_path == DOCUMENT_NAME_FIELD {
+        Some(reference_value(&document.name))
+    } else {
+        get_field(&document.fields, field_path).cloned()
+    }
+}
+
+fn find_inequality_field(filter: &FirestoreQueryFilter) -> Option<&String> {
+    match filter {
+        FirestoreQueryFilter::Composite(composite) => composite
+            .for_all_filters
+            .iter()
+            .find_map(find_inequality_field),
+        FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::LessThan(
+            field_name,
+            _,
+        )))
+        | FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::LessThanOrEqual(
+            field_name,
+            _,
+        )))
+        | FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::GreaterThan(
+            field_name,
+            _,
+        )))
+        | FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::GreaterThanOrEqual(
+            field_name,
+            _,
+        )))
+        | FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::NotEqual(
+            field_name,
+            _,
+        )))
+        | FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::NotIn(field_name, _))) => {
+            Some(field_name)
+        }
+        _ => None,
+    }
+}
+
+// The ordering applied by Firestore: explicit fields, an implicit inequality field and a document name
+pub(crate) fn effective_order_by(params: &FirestoreQueryParams) -> Vec<FirestoreQueryOrder> {
+    let mut order_by = params.order_by.clone().unwrap_or_default();
+
+    if order_by.is_empty() {
+        if let Some(field_name) = params.filter.as_ref().and_then(find_inequality_field) {
+            order_by.push(FirestoreQueryOrder::new(
+                field_name.clone(),
+                FirestoreQueryDirection::Ascending,
+            ));
+        }
+    }
+
+    if !order_by
+        .iter()
+        .any(|order| order.field_name == DOCUMENT_NAME_FIELD)
+    {
+        let direction = order_by
+            .last()
+            .map(|order| order.direction.clone())
+            .unwrap_or(FirestoreQueryDirection::Ascending);
+        order_by.push(FirestoreQueryOrder::new(
+            DOCUMENT_NAME_FIELD.to_string(),
+            direction,
+        ));
+    }
+
+    order_by
+}
+
+pub(crate) fn compare_with_direction(
+    left: &[Value],
+    right: &[Value],
+    order_by: &[FirestoreQueryOrder],
+) -> Ordering {
+    for ((l, r), order) in left.iter().zip(right.iter()).zip(order_by.iter()) {
+        let ordering = match order.direction {
+            FirestoreQueryDirection::Ascending => compare_values(l, r),
+            FirestoreQueryDirection::Descending => compare_values(r, l),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Values of the ordering fields for a document, or `None` when the document doesn't have some of them.
+pub(crate) fn document_order_values(
+    document: &Document,
+    order_by: &[FirestoreQueryOrder],
+) -> Option<Vec<Value>> {
+    order_by
+        .iter()
+        .map(|order| get_document_field(document, &order.field_name))
+        .collect()
+}
+
+fn limit_to_last_error(error: &str) -> FirestoreError {
+    FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+        FirestoreInvalidParametersPublicDetails::new(
+            "limit_to_last".to_string(),
+            error.to_string(),
+        ),
+    ))
+}
+
+// Only the document queries apply `limit_to_last`, so the other operations reject it
+// instead of returning the first documents
+pub(crate) fn reject_limit_to_last(
+    params: &FirestoreQueryParams,
+    operation: &str,
+) -> FirestoreResult<()> {
+    if params.limit_to_last.is_some() {
+        Err(limit_to_last_error(&format!(
+            "limit_to_last isn't supported by {operation}"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn validate_limit_to_last(params: &FirestoreQueryParams) -> FirestoreResult<()> {
+    if params.limit_to_last.is_none() {
+        return Ok(());
+    }
+
+    if params
+        .order_by
+        .as_ref()
+        .map(|order_by| order_by.is_empty())
+        .unwrap_or(true)
+    {
+        return Err(limit_to_last_error("limit_to_last requires order_by"));
+    }
+
+    if params.limit.is_some() {
+        return Err(limit_to_last_error(
+            "limit_to_last can't be used together with limit",
+        ));
+    }
+
+    Ok(())
+}
+
+// Firestore doesn't support `limit_to_last`, so it runs as the query in the reversed order
+// and the results have to be reversed back.
+pub(crate) fn limit_to_last_query(
+    params: &FirestoreQueryParams,
+) -> FirestoreResult<Option<FirestoreQueryParams>> {
+    validate_limit_to_last(params)?;
+
+    let (limit_to_last, order_by) = match (params.limit_to_last, &params.order_by) {
+        (Some(limit_to_last), Some(order_by)) => (limit_to_last, order_by),
+        _ => return Ok(None),
+    };
+
+    // The cursor bounds are swapped, so an inclusive start becomes an inclusive end and so on
+    let reverse_cursor = |cursor: &FirestoreQueryCursor| match cursor {
+        FirestoreQueryCursor::BeforeValue(values) => {
+            FirestoreQueryCursor::AfterValue(values.clone())
+        }
+        FirestoreQueryCursor::AfterValue(values) => {
+            FirestoreQueryCursor::BeforeValue(values.clone())
+        }
+    };
+
+    Ok(Some(FirestoreQueryParams {
+        limit: Some(limit_to_last),
+        limit_to_last: None,
+        order_by: Some(
+            order_by
+                .iter()
+                .map(|order| FirestoreQueryOrder {
+                    field_name: order.field_name.clone(),
+                    direction: match order.direction {
+                        FirestoreQueryDirection::Ascending => FirestoreQueryDirection::Descending,
+                        FirestoreQueryDirection::Descending => FirestoreQueryDirection::Ascending,
+                    },
+                })
+                .collect(),
+        ),
+        start_at: params.end_at.as_ref().map(reverse_cursor),
+        end_at: params.start_at.as_ref().map(reverse_cursor),
+        ..params.clone()
+    }))
+}
+
+// The cursor values of a document snapshot, failing on the first missing field
+pub(crate) fn document_cursor_values(
+    document: &Document,
+    order_by: &[FirestoreQueryOrder],
+) -> FirestoreResult<Vec<Value>> {
+    order_by
+        .iter()
+        .map(|order| {
+            get_document_field(document, &order.field_name).ok_or_else(|| {
+                FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                    FirestoreInvalidParametersPublicDetails::new(
+                        order.field_name.clone(),
+                        format!(
+                            "The document {} doesn't have the ordering field",
+                            document.name
+                        ),
+                    ),
+                ))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_field_path() {
+        assert_eq!(parse_field_path("a"), vec!["a"]);
+        assert_eq!(parse_field_path("a.b.c"), vec!["a", "b", "c"]);
+        assert_eq!(parse_field_path("a.`b.c`"), vec!["a", "b.c"]);
+        assert_eq!(parse_field_path("`a\\`b`"), vec!["a`b"]);
+    }
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod memory_db_tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+    use futures::TryStreamExt;
+    use gcloud_sdk::google::firestore::v1::Document;
+
+    #[tokio::test]
+    async fn document_cursors_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+
+        let snapshot = db
+            .fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .one("test-1")
+            .await?
+            .unwrap();
+
+        let select_ordered = || {
+            db.fluent().select().from(TEST_COLLECTION_NAME).order_by([(
+                path!(TestStructure::some_string),
+                FirestoreQueryDirection::Ascending,
+            )])
+        };
+        let query_nums = |docs: Vec<Document>| -> FirestoreResult<Vec<u64>> {
+            docs.iter()
+                .map(|doc| {
+                    FirestoreDb::deserialize_doc_to::<TestStructure>(doc).map(|obj| obj.some_num)
+                })
+                .collect()
+        };
+
+        let after = select_ordered()
+            .start_after_document(&snapshot)?
+            .query()
+            .await?;
+        assert_eq!(query_nums(after)?, vec![3]);
+
+        let from = select_ordered()
+            .start_at_document(&snapshot)?
+            .query()
+            .await?;
+        assert_eq!(query_nums(from)?, vec![1, 3]);
+
+        let before = select_ordered()
+            .end_before_document(&snapshot)?
+            .query()
+            .await?;
+        assert_eq!(query_nums(before)?, vec![0, 2, 4]);
+
+        let until = select_ordered().end_at_document(&snapshot)?.query().await?;
+        assert_eq!(query_nums(until)?, vec![0, 2, 4, 1]);
+
+        let missing_field = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .order_by([("missing_field", FirestoreQueryDirection::Ascending)])
+            .start_after_document(&snapshot);
+        assert!(matches!(
+            missing_field,
+            Err(FirestoreError::InvalidParametersError(err)) if err.public.field == "missing_field"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_to_last_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+
+        let select_last = || {
+            db.fluent()
+                .select()
+                .from(TEST_COLLECTION_NAME)
+                .order_by([(
+                    path!(TestStructure::some_num),
+                    FirestoreQueryDirection::Ascending,
+                )])
+                .limit_to_last(2)
+        };
+
+        let last: Vec<TestStructure> = select_last().obj().query().await?;
+        assert_eq!(
+            last.iter().map(|obj| obj.some_num).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+
+        let last_in_range: Vec<TestStructure> = select_last()
+            .start_at(FirestoreQueryCursor::AfterValue(vec![0.into()]))
+            .end_at(FirestoreQueryCursor::AfterValue(vec![3.into()]))
+            .obj()
+            .stream_query_with_errors()
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(
+            last_in_range
+                .iter()
+                .map(|obj| obj.some_num)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        let without_order = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .limit_to_last(2)
+            .query()
+            .await;
+        assert!(matches!(
+            without_order,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        // The operations which can't return the last documents reject it
+        let counts = select_last()
+            .aggregate(|a| a.fields([a.field("count").count()]))
+            .query()
+            .await;
+        assert!(matches!(
+            counts,
+            Err(FirestoreError::InvalidParametersError(err)) if err.public.field == "limit_to_last"
+        ));
+
+        let page = select_last().page(2, None).await;
+        assert!(matches!(
+            page,
+            Err(FirestoreError::InvalidParametersError(err)) if err.public.field == "limit_to_last"
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use crate::db::query_ordering::{document_cursor_values, effective_order_by, reject_limit_to_last};
+use crate::errors::*;
+use crate::{
+    FirestoreQueryCursor, FirestoreQueryOrder, FirestoreQueryParams, FirestoreQuerySupport,
+    FirestoreResult, FirestoreValue,
+};
+use gcloud_sdk::google::firestore::v1::{structured_query, Cursor, Document, StructuredQuery};
+use prost::Message;
+use rvstruct::ValueStruct;
+use serde::{Deserialize, Serialize};
+
+/// An opaque token of the next page of a query.
+/// It keeps the query ordering and the values of the last document for it, including the document name.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, ValueStruct, Serialize, Deserialize)]
+pub struct FirestoreQueryPageToken(String);
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FirestoreQueryPage<T> {
+    pub items: Vec<T>,
+    /// `None` for the last page
+    pub next_page_token: Option<FirestoreQueryPageToken>,
+}
+
+impl FirestoreQueryPageToken {
+    fn from_cursor_values(
+        order_by: &[FirestoreQueryOrder],
+        values: Vec<gcloud_sdk::google::firestore::v1::Value>,
+    ) -> Self {
+        Self(hex::encode(
+            StructuredQuery {
+                order_by: order_by.iter().cloned().map(Into::into).collect(),
+                start_at: Some(Cursor {
+                    values,
+                    before: false,
+                }),
+                ..Default::default()
+            }
+            .encode_to_vec(),
+        ))
+    }
+
+    fn to_cursor(&self, order_by: &[FirestoreQueryOrder]) -> FirestoreResult<FirestoreQueryCursor> {
+        let expected_order_by: Vec<structured_query::Order> =
+            order_by.iter().cloned().map(Into::into).collect();
+
+        let cursor = hex::decode(&self.0)
+            .ok()
+            .and_then(|bytes| StructuredQuery::decode(bytes.as_slice()).ok())
+            .filter(|token_query| token_query.order_by == expected_order_by)
+            .and_then(|token_query| token_query.start_at)
+            .filter(|cursor| cursor.values.len() == order_by.len())
+            .ok_or_else(|| {
+                page_params_error("page_token", "The page token doesn't match the query")
+            })?;
+
+        Ok(FirestoreQueryCursor::AfterValue(
+            cursor
+                .values
+                .into_iter()
+                .map(FirestoreValue::from)
+                .collect(),
+        ))
+    }
+}
+
+fn page_params_error(field: &str, error: &str) -> FirestoreError {
+    FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+        FirestoreInvalidParametersPublicDetails::new(field.to_string(), error.to_string()),
+    ))
+}
+
+/// The query of a page: explicitly ordered, starting after the token and requesting
+/// an extra document to know if there is a next page.
+pub(crate) fn page_query_params(
+    params: FirestoreQueryParams,
+    page_size: u32,
+    page_token: Option<&FirestoreQueryPageToken>,
+) -> FirestoreResult<FirestoreQueryParams> {
+    if page_size == 0 {
+        return Err(page_params_error(
+            "page_size",
+            "The page size must be greater than zero",
+        ));
+    }
+
+    reject_limit_to_last(&params, "pages")?;
+
+    if params.limit.is_some() {
+        return Err(page_params_error(
+            "limit",
+            "The limit can't be used for pages, use the page size instead",
+        ));
+    }
+
+    let order_by = effective_order_by(&params);
+    let params = match page_token {
+        Some(page_token) => FirestoreQueryParams {
+            start_at: Some(page_token.to_cursor(&order_by)?),
+            offset: None,
+            ..params
+        },
+        None => params,
+    };
+
+    Ok(params
+        .with_order_by(order_by)
+        .with_limit(page_size.saturating_add(1)))
+}
+
+pub(crate) fn query_page_from_docs(
+    params: &FirestoreQueryParams,
+    mut docs: Vec<Document>,
+    page_size: u32,
+) -> FirestoreResult<FirestoreQueryPage<Document>> {
+    let next_page_token = if docs.len() > page_size as usize {
+        docs.truncate(page_size as usize);
+        match docs.last() {
+            Some(last_doc) => {
+                let order_by = effective_order_by(params);
+                let values = document_cursor_values(last_doc, &order_by)?;
+                Some(FirestoreQueryPageToken::from_cursor_values(
+                    &order_by, values,
+                ))
+            }
+            None => None,
+        }
+    } else {
+        None
+    };
+
+    Ok(FirestoreQueryPage {
+        items: docs,
+        next_page_token,
+    })
+}
+
+pub(crate) async fn query_doc_page<D>(
+    db: &D,
+    params: FirestoreQueryParams,
+    page_size: u32,
+    page_token: Option<&FirestoreQueryPageToken>,
+) -> FirestoreResult<FirestoreQueryPage<Document>>
+where
+    D: FirestoreQuerySupport,
+{
+    let page_params = page_query_params(params, page_size, page_token)?;
+    let docs = db.query_doc(page_params.clone()).await?;
+    query_page_from_docs(&page_params, docs, page_size)
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+    use gcloud_sdk::google::firestore::v1::Document;
+
+    #[tokio::test]
+    async fn query_page_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+
+        let mut page_token: Option<FirestoreQueryPageToken> = None;
+        let mut pages = Vec::new();
+        loop {
+            let page: FirestoreQueryPage<TestStructure> = db
+                .fluent()
+                .select()
+                .from(TEST_COLLECTION_NAME)
+                .order_by([(
+                    path!(TestStructure::some_string),
+                    FirestoreQueryDirection::Descending,
+                )])
+                .obj()
+                .page(2, page_token.as_ref())
+                .await?;
+            pages.push(
+                page.items
+                    .iter()
+                    .map(|obj| obj.some_num)
+                    .collect::<Vec<_>>(),
+            );
+            match page.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec![3, 1], vec![4, 2], vec![0]]);
+
+        let invalid_page = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .page(
+                2,
+                Some(&FirestoreQueryPageToken::new("invalid".to_string())),
+            )
+            .await;
+        assert!(matches!(
+            invalid_page,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        let first_page: FirestoreQueryPage<Document> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .order_by([(
+                path!(TestStructure::some_string),
+                FirestoreQueryDirection::Descending,
+            )])
+            .page(2, None)
+            .await?;
+        let other_order_page = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Descending,
+            )])
+            .page(2, first_page.next_page_token.as_ref())
+            .await;
+        assert!(matches!(
+            other_order_page,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        let empty_page = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .page(0, None)
+            .await;
+        assert!(matches!(
+            empty_page,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        let limited_page = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .limit(1)
+            .page(2, None)
+            .await;
+        assert!(matches!(
+            limited_page,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        Ok(())
+    }
+}
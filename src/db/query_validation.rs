@@ -0,0 +1,376 @@
+use crate::db::query_ordering::{effective_order_by, validate_limit_to_last};
+use crate::errors::*;
+use crate::{
+    FirestoreQueryCursor, FirestoreQueryFilter, FirestoreQueryFilterCompare,
+    FirestoreQueryFilterCompositeOperator, FirestoreQueryParams, FirestoreResult, FirestoreValue,
+};
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+
+/// The maximum number of values of `In` and `ArrayContainsAny` filters
+pub const FIRESTORE_QUERY_MAX_DISJUNCTION_VALUES: usize = 30;
+
+/// The maximum number of values of `NotIn` filters
+pub const FIRESTORE_QUERY_MAX_NOT_IN_VALUES: usize = 10;
+
+fn invalid_query_error(field: &str, error: String) -> FirestoreError {
+    FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+        FirestoreInvalidParametersPublicDetails::new(field.to_string(), error),
+    ))
+}
+
+// Returns the compare filters of every conjunction of the filter in the disjunctive normal form
+fn collect_conjunctions(filter: &FirestoreQueryFilter) -> Vec<Vec<&FirestoreQueryFilterCompare>> {
+    match filter {
+        FirestoreQueryFilter::Composite(composite) => {
+            match composite.operator {
+                FirestoreQueryFilterCompositeOperator::And => composite
+                    .for_all_filters
+                    .iter()
+                    .fold(vec![Vec::new()], |conjunctions, filter| {
+                        let filter_conjunctions = collect_conjunctions(filter);
+                        conjunctions
+                            .iter()
+                            .flat_map(|conjunction| {
+                                filter_conjunctions.iter().map(move |filter_conjunction| {
+                                    conjunction
+                                        .iter()
+                                        .chain(filter_conjunction.iter())
+                                        .copied()
+                                        .collect()
+                                })
+                            })
+                            .collect()
+                    }),
+                FirestoreQueryFilterCompositeOperator::Or => composite
+                    .for_all_filters
+                    .iter()
+                    .flat_map(collect_conjunctions)
+                    .collect(),
+            }
+        }
+        FirestoreQueryFilter::Compare(Some(compare_filter)) => vec![vec![compare_filter]],
+        _ => vec![Vec::new()],
+    }
+}
+
+fn validate_values_count(
+    field_name: &str,
+    value: &FirestoreValue,
+    operator: &str,
+    max_values: usize,
+) -> FirestoreResult<()> {
+    match &value.value.value_type {
+        Some(ValueType::ArrayValue(array)) if array.values.len() > max_values => {
+            Err(invalid_query_error(
+                field_name,
+                format!(
+                    "{} filter supports up to {} values, but {} were specified",
+                    operator,
+                    max_values,
+                    array.values.len()
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl FirestoreQueryParams {
+    /// Checks the query against the Firestore query limitations, so the mistakes are reported
+    /// before sending the query. The queries are validated automatically when they run.
+    pub fn validate(&self) -> FirestoreResult<()> {
+        validate_limit_to_last(self)?;
+
+        let conjunctions = self
+            .filter
+            .as_ref()
+            .map(collect_conjunctions)
+            .unwrap_or_default();
+
+        for compare_filters in conjunctions {
+            self.validate_conjunction(compare_filters)?;
+        }
+
+        let order_fields_count = effective_order_by(self).len();
+        for (cursor_name, cursor) in [("start_at", &self.start_at), ("end_at", &self.end_at)] {
+            if let Some(
+                FirestoreQueryCursor::BeforeValue(values)
+                | FirestoreQueryCursor::AfterValue(values),
+            ) = cursor
+            {
+                if values.len() > order_fields_count {
+                    return Err(invalid_query_error(
+                        cursor_name,
+                        format!(
+                            "The cursor has {} values, but the query is ordered by {} fields",
+                            values.len(),
+                            order_fields_count
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_conjunction(
+        &self,
+        compare_filters: Vec<&FirestoreQueryFilterCompare>,
+    ) -> FirestoreResult<()> {
+        let mut not_equal_field: Option<&String> = None;
+        let mut inequality_fields: Vec<&String> = Vec::new();
+
+        for compare_filter in compare_filters {
+            match compare_filter {
+                FirestoreQueryFilterCompare::In(field_name, value) => validate_values_count(
+                    field_name,
+                    value,
+                    "In",
+                    FIRESTORE_QUERY_MAX_DISJUNCTION_VALUES,
+                )?,
+                FirestoreQueryFilterCompare::ArrayContainsAny(field_name, value) => {
+                    validate_values_count(
+                        field_name,
+                        value,
+                        "ArrayContainsAny",
+                        FIRESTORE_QUERY_MAX_DISJUNCTION_VALUES,
+                    )?
+                }
+                FirestoreQueryFilterCompare::NotIn(field_name, value) => validate_values_count(
+                    field_name,
+                    value,
+                    "NotIn",
+                    FIRESTORE_QUERY_MAX_NOT_IN_VALUES,
+                )?,
+                _ => {}
+            }
+
+            match compare_filter {
+                FirestoreQueryFilterCompare::NotEqual(field_name, _)
+                | FirestoreQueryFilterCompare::NotIn(field_name, _) => {
+                    if let Some(previous_field_name) = not_equal_field {
+                        return Err(invalid_query_error(
+                            field_name,
+                            format!(
+                                "A query supports only one NotEqual or NotIn filter, but {} has one already",
+                                previous_field_name
+                            ),
+                        ));
+                    }
+                    not_equal_field = Some(field_name);
+                    inequality_fields.push(field_name);
+                }
+                FirestoreQueryFilterCompare::LessThan(field_name, _)
+                | FirestoreQueryFilterCompare::LessThanOrEqual(field_name, _)
+                | FirestoreQueryFilterCompare::GreaterThan(field_name, _)
+                | FirestoreQueryFilterCompare::GreaterThanOrEqual(field_name, _) => {
+                    inequality_fields.push(field_name)
+                }
+                _ => {}
+            }
+        }
+        inequality_fields.sort();
+        inequality_fields.dedup();
+
+        // The inequality fields have to be ordered first, when the order is specified
+        if let Some(order_by) = self
+            .order_by
+            .as_ref()
+            .filter(|order_by| !order_by.is_empty())
+        {
+            let leading_order_fields: Vec<&String> = order_by
+                .iter()
+                .take(inequality_fields.len())
+                .map(|order| &order.field_name)
+                .collect();
+            if let Some(inequality_field) = inequality_fields
+                .iter()
+                .find(|field_name| !leading_order_fields.contains(field_name))
+            {
+                return Err(invalid_query_error(
+                    inequality_field,
+                    format!(
+                        "The fields of the inequality filters must be the first order_by fields, but the query is ordered by {}",
+                        order_by
+                            .iter()
+                            .map(|order| order.field_name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FirestoreQueryDirection, FirestoreQueryFilterComposite};
+
+    fn invalid_field(params: &FirestoreQueryParams) -> Option<String> {
+        match params.validate() {
+            Err(FirestoreError::InvalidParametersError(err)) => Some(err.public.field),
+            _ => None,
+        }
+    }
+
+    fn compare(compare_filter: FirestoreQueryFilterCompare) -> FirestoreQueryFilter {
+        FirestoreQueryFilter::Compare(Some(compare_filter))
+    }
+
+    #[test]
+    fn validate_query_params() {
+        let params = FirestoreQueryParams::new("test".into());
+
+        let in_values: Vec<i32> = (0..31).collect();
+        assert_eq!(
+            invalid_field(
+                &params
+                    .clone()
+                    .with_filter(compare(FirestoreQueryFilterCompare::In(
+                        "a".into(),
+                        in_values.clone().into()
+                    )))
+            ),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            invalid_field(
+                &params
+                    .clone()
+                    .with_filter(compare(FirestoreQueryFilterCompare::In(
+                        "a".into(),
+                        in_values[..30].to_vec().into()
+                    )))
+            ),
+            None
+        );
+
+        let not_equal_filters =
+            FirestoreQueryFilter::Composite(FirestoreQueryFilterComposite::new(vec![
+                compare(FirestoreQueryFilterCompare::NotEqual("a".into(), 1.into())),
+                compare(FirestoreQueryFilterCompare::NotIn(
+                    "b".into(),
+                    vec![1].into(),
+                )),
+            ]));
+        assert_eq!(
+            invalid_field(&params.clone().with_filter(not_equal_filters)),
+            Some("b".to_string())
+        );
+
+        let inequality_params =
+            params
+                .clone()
+                .with_filter(compare(FirestoreQueryFilterCompare::GreaterThan(
+                    "a".into(),
+                    1.into(),
+                )));
+        assert_eq!(
+            invalid_field(
+                &inequality_params.clone().with_order_by(vec![(
+                    "b",
+                    FirestoreQueryDirection::Ascending
+                )
+                    .into()])
+            ),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            invalid_field(&inequality_params.clone().with_order_by(vec![
+                ("a", FirestoreQueryDirection::Ascending).into(),
+                ("b", FirestoreQueryDirection::Ascending).into(),
+            ])),
+            None
+        );
+
+        let two_inequality_params = params.clone().with_filter(FirestoreQueryFilter::Composite(
+            FirestoreQueryFilterComposite::new(vec![
+                compare(FirestoreQueryFilterCompare::GreaterThan(
+                    "a".into(),
+                    1.into(),
+                )),
+                compare(FirestoreQueryFilterCompare::LessThan("b".into(), 2.into())),
+            ]),
+        ));
+        assert_eq!(
+            invalid_field(
+                &two_inequality_params.clone().with_order_by(vec![(
+                    "a",
+                    FirestoreQueryDirection::Ascending
+                )
+                    .into()])
+            ),
+            Some("b".to_string())
+        );
+        assert_eq!(
+            invalid_field(&two_inequality_params.with_order_by(vec![
+                ("b", FirestoreQueryDirection::Ascending).into(),
+                ("a", FirestoreQueryDirection::Ascending).into(),
+            ])),
+            None
+        );
+
+        // Every branch of a disjunction is validated on its own
+        let or_not_equal_filters = FirestoreQueryFilter::Composite(
+            FirestoreQueryFilterComposite::new(vec![
+                compare(FirestoreQueryFilterCompare::NotEqual("a".into(), 1.into())),
+                compare(FirestoreQueryFilterCompare::NotEqual("b".into(), 2.into())),
+            ])
+            .with_operator(FirestoreQueryFilterCompositeOperator::Or),
+        );
+        assert_eq!(
+            invalid_field(&params.clone().with_filter(or_not_equal_filters.clone())),
+            None
+        );
+        assert_eq!(
+            invalid_field(&params.clone().with_filter(FirestoreQueryFilter::Composite(
+                FirestoreQueryFilterComposite::new(vec![
+                    or_not_equal_filters,
+                    compare(FirestoreQueryFilterCompare::NotIn(
+                        "c".into(),
+                        vec![1].into(),
+                    )),
+                ])
+            ))),
+            Some("c".to_string())
+        );
+
+        // The implicit order is the inequality field and the document name
+        assert_eq!(
+            invalid_field(
+                &inequality_params
+                    .clone()
+                    .with_start_at(FirestoreQueryCursor::AfterValue(vec![1.into(), "n".into()]))
+            ),
+            None
+        );
+        assert_eq!(
+            invalid_field(
+                &inequality_params.with_end_at(FirestoreQueryCursor::BeforeValue(vec![
+                    1.into(),
+                    "n".into(),
+                    2.into()
+                ]))
+            ),
+            Some("end_at".to_string())
+        );
+
+        assert_eq!(
+            invalid_field(&params.clone().with_limit_to_last(10)),
+            Some("limit_to_last".to_string())
+        );
+        assert_eq!(
+            invalid_field(
+                &params
+                    .with_order_by(vec![("a", FirestoreQueryDirection::Ascending).into()])
+                    .with_limit_to_last(10)
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,688 @@
+use crate::db::listen_changes_typed::document_id_from_path;
+use crate::db::query_ordering::{
+    compare_with_direction, document_order_values, effective_order_by,
+};
+use crate::errors::*;
+use crate::timestamp_utils::from_timestamp;
+use crate::{
+    FirestoreDb, FirestoreDocChange, FirestoreDocChangeType, FirestoreExponentialRetryPolicy,
+    FirestoreListenSupport, FirestoreListenerErrorHandler, FirestoreListenerTarget,
+    FirestoreListenerTargetParams, FirestoreListenerTargetResumeType, FirestoreListenerToken,
+    FirestoreQueryOrder, FirestoreQueryParams, FirestoreResult, FirestoreRetryPolicy,
+    FirestoreTargetType,
+};
+use chrono::prelude::*;
+use futures::future::Either;
+use futures::TryStreamExt;
+use gcloud_sdk::google::firestore::v1::*;
+use rsb_derive::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+const FIRESTORE_QUERY_VIEW_TARGET: i32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct FirestoreQueryViewSnapshot<T> {
+    /// The query results in the query order
+    pub objects: Vec<T>,
+    /// The changes since the previous snapshot
+    pub changes: Vec<FirestoreDocChange<T>>,
+    pub read_time: Option<DateTime<Utc>>,
+}
+
+impl<T> Default for FirestoreQueryViewSnapshot<T> {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            changes: Vec::new(),
+            read_time: None,
+        }
+    }
+}
+
+#[derive(Clone, Builder)]
+pub struct FirestoreQueryViewParams {
+    /// The delay before reconnecting when the retry policy gives up, 5 seconds by default
+    pub retry_delay: Option<std::time::Duration>,
+    /// The policy of the delays between reconnects, reset when the stream receives a response
+    pub retry_policy: Option<Arc<dyn FirestoreRetryPolicy>>,
+    /// Receives the errors of the documents that are left out of the view because they fail to deserialize
+    pub error_handler: Option<FirestoreListenerErrorHandler>,
+}
+
+impl std::fmt::Debug for FirestoreQueryViewParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirestoreQueryViewParams")
+            .field("retry_delay", &self.retry_delay)
+            .field("retry_policy", &self.retry_policy)
+            .field("error_handler", &self.error_handler.is_some())
+            .finish()
+    }
+}
+
+/// A live view of query results maintained by listening to the changes.
+pub struct FirestoreQueryView<T> {
+    receiver: watch::Receiver<Arc<FirestoreQueryViewSnapshot<T>>>,
+    shutdown_flag: Arc<AtomicBool>,
+    shutdown_handle: Option<JoinHandle<()>>,
+    shutdown_writer: Option<UnboundedSender<i8>>,
+}
+
+enum FirestoreQueryViewNext {
+    Continue,
+    Resync,
+    Restart,
+}
+
+struct FirestoreQueryViewDoc<T> {
+    document: Document,
+    object: T,
+}
+
+struct FirestoreQueryViewState<T> {
+    order_by: Vec<FirestoreQueryOrder>,
+    published: HashMap<String, Arc<FirestoreQueryViewDoc<T>>>,
+    documents: HashMap<String, Arc<FirestoreQueryViewDoc<T>>>,
+    deleted: HashSet<String>,
+    current: bool,
+    resume_token: Option<FirestoreListenerToken>,
+    error_handler: Option<FirestoreListenerErrorHandler>,
+}
+
+impl<T> FirestoreQueryView<T>
+where
+    for<'de> T: Deserialize<'de>,
+    T: Clone + Send + Sync + 'static,
+{
+    pub async fn new<D>(db: D, query_params: FirestoreQueryParams) -> FirestoreResult<Self>
+    where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    {
+        Self::with_params(db, query_params, FirestoreQueryViewParams::new()).await
+    }
+
+    pub async fn with_params<D>(
+        db: D,
+        query_params: FirestoreQueryParams,
+        view_params: FirestoreQueryViewParams,
+    ) -> FirestoreResult<Self>
+    where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    {
+        let (sender, receiver) = watch::channel(Arc::new(FirestoreQueryViewSnapshot::default()));
+        let (shutdown_writer, shutdown_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let shutdown_flag = Arc::new(AtomicBool::new(false));
+
+        let shutdown_handle = tokio::spawn(Self::view_loop(
+            db,
+            query_params,
+            view_params,
+            shutdown_flag.clone(),
+            shutdown_receiver,
+            sender,
+        ));
+
+        Ok(Self {
+            receiver,
+            shutdown_flag,
+            shutdown_handle: Some(shutdown_handle),
+            shutdown_writer: Some(shutdown_writer),
+        })
+    }
+
+    /// The latest consistent snapshot of the query results.
+    pub fn snapshot(&self) -> Arc<FirestoreQueryViewSnapshot<T>> {
+        self.receiver.borrow().clone()
+    }
+
+    /// A receiver notified on every new snapshot.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<FirestoreQueryViewSnapshot<T>>> {
+        self.receiver.clone()
+    }
+
+    pub async fn shutdown(&mut self) -> FirestoreResult<()> {
+        debug!("Shutting down Firestore query view...");
+        self.shutdown_flag.store(true, Ordering::Relaxed);
+        if let Some(shutdown_writer) = self.shutdown_writer.take() {
+            shutdown_writer.send(1).ok();
+        }
+        if let Some(handle) = self.shutdown_handle.take() {
+            if let Err(err) = handle.await {
+                warn!("Firestore query view exit error: {}...", err);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false when the view is shut down while waiting
+    async fn wait_reconnect(
+        delay: std::time::Duration,
+        shutdown_receiver: &mut UnboundedReceiver<i8>,
+    ) -> bool {
+        if tokio::time::timeout(delay, shutdown_receiver.recv())
+            .await
+            .is_ok()
+        {
+            debug!("Exiting from query view...");
+            shutdown_receiver.close();
+            false
+        } else {
+            true
+        }
+    }
+
+    async fn view_loop<D>(
+        db: D,
+        query_params: FirestoreQueryParams,
+        view_params: FirestoreQueryViewParams,
+        shutdown_flag: Arc<AtomicBool>,
+        mut shutdown_receiver: UnboundedReceiver<i8>,
+        sender: watch::Sender<Arc<FirestoreQueryViewSnapshot<T>>>,
+    ) where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    {
+        let fallback_delay = view_params
+            .retry_delay
+            .unwrap_or_else(|| std::time::Duration::from_secs(5));
+        let retry_policy = view_params
+            .retry_policy
+            .unwrap_or_else(|| Arc::new(FirestoreExponentialRetryPolicy::new()));
+        let mut failures: usize = 0;
+        let reconnect_delay = |err: &FirestoreError, failures: usize| {
+            retry_policy
+                .retry_delay(err, failures)
+                .unwrap_or(fallback_delay)
+        };
+
+        let mut state: FirestoreQueryViewState<T> = FirestoreQueryViewState {
+            order_by: effective_order_by(&query_params),
+            published: HashMap::new(),
+            documents: HashMap::new(),
+            deleted: HashSet::new(),
+            current: false,
+            resume_token: None,
+            error_handler: view_params.error_handler,
+        };
+
+        while !shutdown_flag.load(Ordering::Relaxed) {
+            let target_params = FirestoreListenerTargetParams::new(
+                FirestoreListenerTarget::new(FIRESTORE_QUERY_VIEW_TARGET),
+                FirestoreTargetType::Query(query_params.clone()),
+                HashMap::new(),
+            )
+            .opt_resume_type(
+                state
+                    .resume_token
+                    .clone()
+                    .map(FirestoreListenerTargetResumeType::Token),
+            );
+
+            let mut listen_stream = match db.listen_doc_changes(vec![target_params]).await {
+                Ok(listen_stream) => listen_stream,
+                Err(err) => {
+                    let delay = reconnect_delay(&err, failures);
+                    failures += 1;
+                    error!(
+                        "Query view listen error occurred {:?}. Restarting in {:?}...",
+                        err, delay
+                    );
+                    if Self::wait_reconnect(delay, &mut shutdown_receiver).await {
+                        continue;
+                    }
+                    return;
+                }
+            };
+
+            let restart_error = loop {
+                let tried = {
+                    let shutdown = shutdown_receiver.recv();
+                    futures::pin_mut!(shutdown);
+                    match futures::future::select(shutdown, listen_stream.try_next()).await {
+                        Either::Left(_) => None,
+                        Either::Right((tried, _)) => Some(tried),
+                    }
+                };
+                match tried {
+                    None => {
+                        debug!("Exiting from query view...");
+                        shutdown_receiver.close();
+                        return;
+                    }
+                    Some(Ok(Some(ListenResponse {
+                        response_type: Some(response_type),
+                    }))) => {
+                        failures = 0;
+                        match state.apply(response_type, &sender) {
+                            FirestoreQueryViewNext::Continue => {}
+                            FirestoreQueryViewNext::Resync => break None,
+                            FirestoreQueryViewNext::Restart => {
+                                break Some(query_view_restart_error(
+                                    "Query view target has been removed",
+                                ))
+                            }
+                        }
+                    }
+                    Some(Ok(Some(_))) => {}
+                    Some(Ok(None)) => {
+                        break Some(query_view_restart_error(
+                            "Query view listen stream has been closed",
+                        ))
+                    }
+                    Some(Err(err)) => break Some(err),
+                }
+            };
+
+            if let Some(err) = restart_error {
+                let delay = reconnect_delay(&err, failures);
+                failures += 1;
+                debug!(
+                    "Query view listen error occurred {:?}. Restarting in {:?}...",
+                    err, delay
+                );
+                if !Self::wait_reconnect(delay, &mut shutdown_receiver).await {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for FirestoreQueryView<T> {
+    fn drop(&mut self) {
+        self.shutdown_flag.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.shutdown_handle.take() {
+            handle.abort();
+        }
+    }
+}
+
+// Reconnecting after the stream is closed or the target is removed is always retryable
+fn query_view_restart_error(message: &str) -> FirestoreError {
+    FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+        FirestoreErrorPublicGenericDetails::new("Unavailable".into()),
+        message.to_string(),
+        true,
+    ))
+}
+
+impl<T> FirestoreQueryViewState<T>
+where
+    for<'de> T: Deserialize<'de>,
+    T: Clone,
+{
+    fn apply(
+        &mut self,
+        response_type: listen_response::ResponseType,
+        sender: &watch::Sender<Arc<FirestoreQueryViewSnapshot<T>>>,
+    ) -> FirestoreQueryViewNext {
+        match response_type {
+            listen_response::ResponseType::DocumentChange(doc_change) => {
+                if let Some(document) = doc_change.document {
+                    if doc_change.target_ids.contains(&FIRESTORE_QUERY_VIEW_TARGET) {
+                        match FirestoreDb::deserialize_doc_to::<T>(&document) {
+                            Ok(object) => {
+                                self.deleted.remove(&document.name);
+                                self.documents.insert(
+                                    document.name.clone(),
+                                    Arc::new(FirestoreQueryViewDoc { document, object }),
+                                );
+                            }
+                            Err(err) => {
+                                error!(
+                                    "Query view is unable to deserialize {}: {}",
+                                    document.name, err
+                                );
+                                self.documents.remove(&document.name);
+                                if let Some(error_handler) = &self.error_handler {
+                                    error_handler(err);
+                                }
+                            }
+                        }
+                    } else if doc_change
+                        .removed_target_ids
+                        .contains(&FIRESTORE_QUERY_VIEW_TARGET)
+                    {
+                        self.documents.remove(&document.name);
+                    }
+                }
+            }
+            listen_response::ResponseType::DocumentDelete(doc_delete) => {
+                self.documents.remove(&doc_delete.document);
+                self.deleted.insert(doc_delete.document);
+            }
+            listen_response::ResponseType::DocumentRemove(doc_remove) => {
+                self.documents.remove(&doc_remove.document);
+            }
+            listen_response::ResponseType::Filter(filter) => {
+                if filter.target_id == FIRESTORE_QUERY_VIEW_TARGET
+                    && filter.count as usize != self.documents.len()
+                {
+                    warn!(
+                        "Query view existence filter mismatch: expected {}, actual {}. Resyncing...",
+                        filter.count,
+                        self.documents.len()
+                    );
+                    self.documents.clear();
+                    self.current = false;
+                    self.resume_token = None;
+                    return FirestoreQueryViewNext::Resync;
+                }
+            }
+            listen_response::ResponseType::TargetChange(target_change) => {
+                let for_view = target_change.target_ids.is_empty()
+                    || target_change
+                        .target_ids
+                        .contains(&FIRESTORE_QUERY_VIEW_TARGET);
+
+                if for_view {
+                    match target_change::TargetChangeType::from_i32(
+                        target_change.target_change_type,
+                    ) {
+                        Some(target_change::TargetChangeType::Current) => {
+                            self.current = true;
+                            self.publish(target_change.read_time.clone(), sender, true);
+                        }
+                        Some(target_change::TargetChangeType::NoChange) if self.current => {
+                            self.publish(target_change.read_time.clone(), sender, false);
+                        }
+                        Some(target_change::TargetChangeType::Reset) => {
+                            self.documents.clear();
+                            self.current = false;
+                        }
+                        Some(target_change::TargetChangeType::Remove) => {
+                            error!(
+                                "Query view target has been removed by the server: {:?}",
+                                target_change.cause
+                            );
+                            self.documents.clear();
+                            self.current = false;
+                            self.resume_token = None;
+                            return FirestoreQueryViewNext::Restart;
+                        }
+                        _ => {}
+                    }
+
+                    if !target_change.resume_token.is_empty() {
+                        self.resume_token = Some(target_change.resume_token.into());
+                    }
+                }
+            }
+        }
+        FirestoreQueryViewNext::Continue
+    }
+
+    fn publish(
+        &mut self,
+        read_time: Option<prost_types::Timestamp>,
+        sender: &watch::Sender<Arc<FirestoreQueryViewSnapshot<T>>>,
+        force: bool,
+    ) {
+        let changes = self.changes();
+        if changes.is_empty() && !force {
+            return;
+        }
+
+        let mut ordered: Vec<(Vec<Value>, &Arc<FirestoreQueryViewDoc<T>>)> = self
+            .documents
+            .values()
+            .map(|doc| {
+                (
+                    document_order_values(&doc.document, &self.order_by).unwrap_or_default(),
+                    doc,
+                )
+            })
+            .collect();
+        ordered
+            .sort_by(|(left, _), (right, _)| compare_with_direction(left, right, &self.order_by));
+
+        let snapshot = FirestoreQueryViewSnapshot {
+            objects: ordered
+                .into_iter()
+                .map(|(_, doc)| doc.object.clone())
+                .collect(),
+            changes,
+            read_time: read_time.and_then(|ts| from_timestamp(ts).ok()),
+        };
+
+        self.published = self.documents.clone();
+        self.deleted.clear();
+        sender.send_replace(Arc::new(snapshot));
+    }
+
+    fn changes(&self) -> Vec<FirestoreDocChange<T>> {
+        let added_or_modified = self.documents.iter().filter_map(|(name, doc)| {
+            let change_type = match self.published.get(name) {
+                None => FirestoreDocChangeType::Added,
+                Some(published) if published.document.update_time != doc.document.update_time => {
+                    FirestoreDocChangeType::Modified
+                }
+                Some(_) => return None,
+            };
+            Some(Self::doc_change(
+                change_type,
+                name,
+                doc.document.update_time.clone(),
+                Some(doc.object.clone()),
+            ))
+        });
+
+        let removed = self
+            .published
+            .iter()
+            .filter(|(name, _)| !self.documents.contains_key(*name))
+            .map(|(name, doc)| {
+                let change_type = if self.deleted.contains(name) {
+                    FirestoreDocChangeType::Deleted
+                } else {
+                    FirestoreDocChangeType::Removed
+                };
+                Self::doc_change(change_type, name, doc.document.update_time.clone(), None)
+            });
+
+        added_or_modified.chain(removed).collect()
+    }
+
+    fn doc_change(
+        change_type: FirestoreDocChangeType,
+        document_path: &str,
+        update_time: Option<prost_types::Timestamp>,
+        object: Option<T>,
+    ) -> FirestoreDocChange<T> {
+        FirestoreDocChange {
+            change_type,
+            document_id: document_id_from_path(document_path),
+            document_path: document_path.to_string(),
+            update_time: update_time.and_then(|ts| from_timestamp(ts).ok()),
+            target_ids: vec![FirestoreListenerTarget::new(FIRESTORE_QUERY_VIEW_TARGET)],
+            object,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone)]
+    struct TestListenSupport {
+        connects: UnboundedSender<Instant>,
+        responses: Vec<ListenResponse>,
+    }
+
+    impl TestListenSupport {
+        fn new(responses: Vec<ListenResponse>) -> (Self, UnboundedReceiver<Instant>) {
+            let (connects, connects_receiver) = tokio::sync::mpsc::unbounded_channel();
+            (
+                Self {
+                    connects,
+                    responses,
+                },
+                connects_receiver,
+            )
+        }
+    }
+
+    #[async_trait]
+    impl FirestoreListenSupport for TestListenSupport {
+        async fn listen_doc_changes<'a, 'b>(
+            &'a self,
+            _targets: Vec<FirestoreListenerTargetParams>,
+        ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+            self.connects.send(Instant::now()).ok();
+            Ok(futures::stream::iter(self.responses.clone().into_iter().map(Ok)).boxed())
+        }
+    }
+
+    #[derive(Debug, serde::Deserialize, Clone)]
+    struct TestStructure {
+        #[allow(dead_code)]
+        some_num: u64,
+    }
+
+    fn test_view_params() -> FirestoreQueryViewParams {
+        FirestoreQueryViewParams::new()
+            .with_retry_delay(Duration::from_millis(50))
+            .with_retry_policy(Arc::new(
+                FirestoreExponentialRetryPolicy::new().with_max_retries(0),
+            ))
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_delay_and_stops_on_drop() -> FirestoreResult<()> {
+        let (db, mut connects) = TestListenSupport::new(vec![]);
+        let view: FirestoreQueryView<TestStructure> = FirestoreQueryView::with_params(
+            db,
+            FirestoreQueryParams::new("test".into()),
+            test_view_params(),
+        )
+        .await?;
+
+        let first_connect = connects.recv().await.unwrap();
+        connects.recv().await.unwrap();
+        let third_connect = connects.recv().await.unwrap();
+        assert!(third_connect.duration_since(first_connect) >= Duration::from_millis(100));
+
+        // The runtime of the test is single threaded, so the view task doesn't run after it is aborted
+        drop(view);
+        while connects.try_recv().is_ok() {}
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(connects.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_documents_failing_to_deserialize() -> FirestoreResult<()> {
+        let (db, _connects) = TestListenSupport::new(vec![ListenResponse {
+            response_type: Some(listen_response::ResponseType::DocumentChange(
+                DocumentChange {
+                    document: Some(Document {
+                        name: "projects/test-project/databases/(default)/documents/test/doc-1"
+                            .into(),
+                        fields: HashMap::new(),
+                        create_time: None,
+                        update_time: None,
+                    }),
+                    target_ids: vec![FIRESTORE_QUERY_VIEW_TARGET],
+                    removed_target_ids: vec![],
+                },
+            )),
+        }]);
+        let (errors, mut errors_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut view: FirestoreQueryView<TestStructure> = FirestoreQueryView::with_params(
+            db,
+            FirestoreQueryParams::new("test".into()),
+            test_view_params().with_error_handler(Arc::new(move |err| {
+                errors.send(err).ok();
+            })),
+        )
+        .await?;
+
+        assert!(matches!(
+            errors_receiver.recv().await,
+            Some(FirestoreError::DeserializeError(_))
+        ));
+        assert!(view.snapshot().objects.is_empty());
+
+        view.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod memory_db_tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+
+    #[tokio::test]
+    async fn query_view_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let mut view: FirestoreQueryView<TestStructure> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_string)).eq("even")]))
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Descending,
+            )])
+            .obj()
+            .view()
+            .await?;
+
+        let mut snapshots = view.subscribe();
+        snapshots.changed().await.ok();
+        let snapshot = view.snapshot();
+        assert_eq!(
+            snapshot.objects,
+            vec![
+                inserted[4].clone(),
+                inserted[2].clone(),
+                inserted[0].clone()
+            ]
+        );
+        assert_eq!(snapshot.changes.len(), 3);
+
+        db.fluent()
+            .update()
+            .fields(paths!(TestStructure::some_string))
+            .in_col(TEST_COLLECTION_NAME)
+            .document_id(&inserted[2].some_id)
+            .object(&TestStructure {
+                some_string: "odd".to_string(),
+                ..inserted[2].clone()
+            })
+            .execute::<TestStructure>()
+            .await?;
+
+        snapshots.changed().await.ok();
+        let snapshot = view.snapshot();
+        assert_eq!(
+            snapshot.objects,
+            vec![inserted[4].clone(), inserted[0].clone()]
+        );
+        assert_eq!(snapshot.changes.len(), 1);
+        assert_eq!(
+            snapshot.changes[0].change_type,
+            FirestoreDocChangeType::Removed
+        );
+        assert_eq!(snapshot.changes[0].document_id, inserted[2].some_id);
+
+        view.shutdown().await?;
+
+        Ok(())
+    }
+}
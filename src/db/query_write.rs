@@ -0,0 +1,291 @@
+use crate::db::query_doc_page;
+use crate::db::query_ordering::effective_order_by;
+use crate::{
+    FirestoreBatchWriter, FirestoreDb, FirestoreFieldTransform, FirestoreQueryPageToken,
+    FirestoreQueryParams, FirestoreResult, FIRESTORE_MAX_WRITES_PER_REQUEST,
+};
+use async_trait::async_trait;
+use gcloud_sdk::google::firestore::v1::*;
+use rsb_derive::Builder;
+use tracing::*;
+
+/// Changes applied to every document matching a query
+#[derive(Debug, PartialEq, Clone, Builder)]
+pub struct FirestoreQueryUpdate {
+    /// Fields set on every document, other fields of the documents are kept
+    pub document: Option<Document>,
+    /// Fields to update, by default the fields of `document`
+    pub update_only: Option<Vec<String>>,
+    #[default = "vec![]"]
+    pub transforms: Vec<FirestoreFieldTransform>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+pub struct FirestoreQueryWriteResult {
+    pub affected_count: usize,
+    pub failed_count: usize,
+}
+
+/// Deletes and updates all documents matching a query, in addition to
+/// [`FirestoreDeleteSupport`](crate::FirestoreDeleteSupport) and [`FirestoreUpdateSupport`](crate::FirestoreUpdateSupport)
+#[async_trait]
+pub trait FirestoreQueryWriteSupport {
+    /// Deletes all documents matching the query.
+    /// With `update_time_precondition`, documents changed after the query aren't deleted.
+    async fn delete_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult>;
+
+    /// Updates all documents matching the query.
+    /// With `update_time_precondition`, documents changed after the query aren't updated.
+    /// The documents are read page by page in the query order, so the update must not change
+    /// the fields of the inequality filters and `order_by`: an updated document could move to
+    /// a later page and be updated again.
+    async fn update_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update: FirestoreQueryUpdate,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult>;
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum FirestoreQueryWriteOperation {
+    Delete,
+    Update(FirestoreQueryUpdate),
+}
+
+impl FirestoreQueryWriteOperation {
+    /// Creates a write for a document found by the query
+    pub(crate) fn to_write(
+        &self,
+        document: &Document,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<Write> {
+        // The document must still exist, and optionally not be changed after it was found by the query
+        let condition_type = match (update_time_precondition, document.update_time.clone()) {
+            (true, Some(update_time)) => precondition::ConditionType::UpdateTime(update_time),
+            _ => precondition::ConditionType::Exists(true),
+        };
+        let current_document = Some(Precondition {
+            condition_type: Some(condition_type),
+        });
+
+        match self {
+            FirestoreQueryWriteOperation::Delete => Ok(Write {
+                update_mask: None,
+                update_transforms: vec![],
+                current_document,
+                operation: Some(write::Operation::Delete(document.name.clone())),
+            }),
+            FirestoreQueryWriteOperation::Update(update) => {
+                let transforms = update
+                    .transforms
+                    .iter()
+                    .cloned()
+                    .map(|transform| transform.try_into())
+                    .collect::<FirestoreResult<Vec<document_transform::FieldTransform>>>()?;
+
+                match &update.document {
+                    Some(update_document) => Ok(Write {
+                        update_mask: Some(DocumentMask {
+                            field_paths: update.update_only.clone().unwrap_or_else(|| {
+                                update_document.fields.keys().cloned().collect()
+                            }),
+                        }),
+                        update_transforms: transforms,
+                        current_document,
+                        operation: Some(write::Operation::Update(Document {
+                            name: document.name.clone(),
+                            fields: update_document.fields.clone(),
+                            create_time: None,
+                            update_time: None,
+                        })),
+                    }),
+                    None => Ok(Write {
+                        update_mask: None,
+                        update_transforms: vec![],
+                        current_document,
+                        operation: Some(write::Operation::Transform(DocumentTransform {
+                            document: document.name.clone(),
+                            field_transforms: transforms,
+                        })),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl FirestoreDb {
+    pub(crate) async fn write_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        operation: FirestoreQueryWriteOperation,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        let collection_str = params.collection_id.to_string();
+        let span = span!(
+            Level::DEBUG,
+            "Firestore Write By Query",
+            "/firestore/collection_name" = collection_str.as_str()
+        );
+
+        // Only the names, the update times and the ordering fields of the documents are needed
+        let order_fields = effective_order_by(&params)
+            .into_iter()
+            .map(|order| order.field_name)
+            .collect();
+        let params = params.with_return_only_fields(order_fields);
+
+        let bulk_writer = self.create_bulk_writer().await?;
+        let mut result = FirestoreQueryWriteResult::new(0, 0);
+
+        // Every page is read before it is written, and queried separately after the last
+        // document of the previous page, so no query stream stays open during the writes
+        let mut page_token: Option<FirestoreQueryPageToken> = None;
+        loop {
+            let page = query_doc_page(
+                self,
+                params.clone(),
+                FIRESTORE_MAX_WRITES_PER_REQUEST as u32,
+                page_token.as_ref(),
+            )
+            .await?;
+
+            if !page.items.is_empty() {
+                let writes = page
+                    .items
+                    .iter()
+                    .map(|document| operation.to_write(document, update_time_precondition))
+                    .collect::<FirestoreResult<Vec<Write>>>()?;
+
+                for outcome in bulk_writer.write(writes).await?.into_outcomes() {
+                    match outcome.result {
+                        Ok(_) => result.affected_count += 1,
+                        Err(err) => {
+                            span.in_scope(|| debug!("[DB]: Write by query failed: {}", err));
+                            result.failed_count += 1;
+                        }
+                    }
+                }
+            }
+
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        span.in_scope(|| {
+            debug!(
+                "[DB]: Written by query. Affected: {}. Failed: {}",
+                result.affected_count, result.failed_count
+            )
+        });
+
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl FirestoreQueryWriteSupport for FirestoreDb {
+    async fn delete_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        self.write_by_query(
+            params,
+            FirestoreQueryWriteOperation::Delete,
+            update_time_precondition,
+        )
+        .await
+    }
+
+    async fn update_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update: FirestoreQueryUpdate,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        self.write_by_query(
+            params,
+            FirestoreQueryWriteOperation::Update(update),
+            update_time_precondition,
+        )
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "memory-db"))]
+mod tests {
+    use crate::memory_db::tests::*;
+    use crate::*;
+
+    #[tokio::test]
+    async fn write_by_query_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+
+        let updated = db
+            .fluent()
+            .update()
+            .fields(paths!(TestStructure::{some_string}))
+            .in_col(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_string)).eq("odd")]))
+            .object(&TestStructure {
+                some_id: "ignored".to_string(),
+                some_string: "updated".to_string(),
+                some_num: 0,
+            })
+            .transforms(|t| t.fields([t.field(path!(TestStructure::some_num)).increment(10)]))
+            .execute()
+            .await?;
+        assert_eq!(updated, FirestoreQueryWriteResult::new(2, 0));
+
+        let updated = db
+            .fluent()
+            .update()
+            .in_col(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_num)).eq(0)]))
+            .transforms(|t| t.fields([t.field(path!(TestStructure::some_num)).increment(1)]))
+            .update_time_precondition()
+            .execute()
+            .await?;
+        assert_eq!(updated, FirestoreQueryWriteResult::new(1, 0));
+
+        let deleted = db
+            .fluent()
+            .delete()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_string)).eq("even")]))
+            .update_time_precondition()
+            .execute()
+            .await?;
+        assert_eq!(deleted, FirestoreQueryWriteResult::new(3, 0));
+
+        let remaining: Vec<TestStructure> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(
+            remaining
+                .iter()
+                .map(|obj| (obj.some_id.as_str(), obj.some_string.as_str(), obj.some_num))
+                .collect::<Vec<_>>(),
+            vec![("test-1", "updated", 11), ("test-3", "updated", 13)]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use crate::errors::FirestoreError;
+use crate::{FirestoreDb, FirestoreResult};
+use rand::Rng;
+use rsb_derive::Builder;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+use tracing::*;
+
+/// Decides if and when a failed request is retried.
+pub trait FirestoreRetryPolicy: Debug + Send + Sync {
+    /// Returns the delay before the next attempt, or `None` to return the error.
+    /// `retries` is the number of retries already done for the request.
+    fn retry_delay(&self, err: &FirestoreError, retries: usize) -> Option<Duration>;
+}
+
+/// Retries the errors marked as retryable with an exponential backoff and a random jitter.
+/// The delay requested by the server with gRPC `RetryInfo` takes precedence over the backoff.
+#[derive(Debug, Clone, PartialEq, Builder)]
+pub struct FirestoreExponentialRetryPolicy {
+    #[default = "3"]
+    pub max_retries: usize,
+
+    #[default = "Duration::from_millis(100)"]
+    pub initial_interval: Duration,
+
+    #[default = "Duration::from_secs(30)"]
+    pub max_interval: Duration,
+
+    #[default = "2.0"]
+    pub multiplier: f64,
+
+    /// The delay is randomized within `[delay * (1 - factor), delay * (1 + factor)]`
+    #[default = "0.5"]
+    pub randomization_factor: f64,
+}
+
+impl FirestoreExponentialRetryPolicy {
+    fn backoff_interval(&self, retries: usize) -> Duration {
+        let interval = (self.initial_interval.as_secs_f64()
+            * self.multiplier.powi(retries.min(i32::MAX as usize) as i32))
+        .min(self.max_interval.as_secs_f64());
+
+        let delta = interval * self.randomization_factor.clamp(0.0, 1.0);
+        let randomized = if delta > 0.0 {
+            rand::thread_rng().gen_range((interval - delta)..=(interval + delta))
+        } else {
+            interval
+        };
+
+        Duration::from_secs_f64(randomized.max(0.0))
+    }
+}
+
+impl FirestoreRetryPolicy for FirestoreExponentialRetryPolicy {
+    fn retry_delay(&self, err: &FirestoreError, retries: usize) -> Option<Duration> {
+        match err {
+            FirestoreError::DatabaseError(db_err)
+                if db_err.retry_possible && retries < self.max_retries =>
+            {
+                Some(
+                    db_err
+                        .retry_delay
+                        .unwrap_or_else(|| self.backoff_interval(retries)),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Never retries failed requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FirestoreNoRetryPolicy;
+
+impl FirestoreRetryPolicy for FirestoreNoRetryPolicy {
+    fn retry_delay(&self, _err: &FirestoreError, _retries: usize) -> Option<Duration> {
+        None
+    }
+}
+
+impl FirestoreDb {
+    #[inline]
+    pub fn get_retry_policy(&self) -> &dyn FirestoreRetryPolicy {
+        self.session_params
+            .retry_policy
+            .as_deref()
+            .unwrap_or_else(|| self.inner.retry_policy.as_ref())
+    }
+
+    pub(crate) async fn with_retries<T, F, FT>(&self, operation: F) -> FirestoreResult<T>
+    where
+        F: Fn() -> FT,
+        FT: Future<Output = FirestoreResult<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            match operation().await {
+                Err(err) => match self.get_retry_policy().retry_delay(&err, retries) {
+                    Some(delay) => {
+                        retries += 1;
+                        warn!(
+                            "[DB]: Failed with {}. Retrying in {:?}: {}",
+                            err, delay, retries
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => return Err(err),
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::*;
+    use crate::FirestoreDbOptions;
+    use std::sync::Arc;
+
+    fn database_error(retry_possible: bool) -> FirestoreError {
+        FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+            FirestoreErrorPublicGenericDetails::new("Unavailable".into()),
+            "test".into(),
+            retry_possible,
+        ))
+    }
+
+    #[tokio::test]
+    #[allow(deprecated)]
+    async fn default_retry_policy_options() {
+        let db = crate::db::tests::offline_test_db_with_options(
+            FirestoreDbOptions::new("test-project".to_string()).with_max_retries(1),
+        )
+        .await;
+        assert!(db
+            .get_retry_policy()
+            .retry_delay(&database_error(true), 0)
+            .is_some());
+        assert!(db
+            .get_retry_policy()
+            .retry_delay(&database_error(true), 1)
+            .is_none());
+
+        let options = FirestoreDbOptions::new("test-project".to_string())
+            .with_retry_policy(Arc::new(FirestoreExponentialRetryPolicy::new()));
+        assert_eq!(options, options.clone());
+        assert_ne!(
+            options,
+            FirestoreDbOptions::new("test-project".to_string())
+                .with_retry_policy(Arc::new(FirestoreExponentialRetryPolicy::new()))
+        );
+    }
+
+    #[test]
+    fn exponential_retry_delays() {
+        let policy = FirestoreExponentialRetryPolicy::new()
+            .with_initial_interval(Duration::from_millis(100))
+            .with_max_interval(Duration::from_millis(250))
+            .with_randomization_factor(0.0);
+
+        assert_eq!(
+            policy.retry_delay(&database_error(true), 0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.retry_delay(&database_error(true), 1),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            policy.retry_delay(&database_error(true), 2),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(policy.retry_delay(&database_error(true), 3), None);
+        assert_eq!(policy.retry_delay(&database_error(false), 0), None);
+
+        let server_delayed = match database_error(true) {
+            FirestoreError::DatabaseError(db_err) => {
+                FirestoreError::DatabaseError(db_err.with_retry_delay(Duration::from_secs(5)))
+            }
+            other => other,
+        };
+        assert_eq!(
+            policy.retry_delay(&server_delayed, 0),
+            Some(Duration::from_secs(5))
+        );
+    }
+}
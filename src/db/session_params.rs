@@ -1,7 +1,10 @@
-use crate::FirestoreConsistencySelector;
+use crate::{FirestoreConsistencySelector, FirestoreRetryPolicy};
 use rsb_derive::*;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Builder)]
 pub struct FirestoreDbSessionParams {
     pub consistency_selector: Option<FirestoreConsistencySelector>,
+    /// Overrides the retry policy of the database options
+    pub retry_policy: Option<Arc<dyn FirestoreRetryPolicy>>,
 }
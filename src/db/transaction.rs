@@ -1,23 +1,36 @@
 use crate::errors::*;
 use crate::timestamp_utils::from_timestamp;
 use crate::{
-    FirestoreConsistencySelector, FirestoreDb, FirestoreError, FirestoreResult,
+    FirestoreConsistencySelector, FirestoreDb, FirestoreError, FirestoreExprBuilder,
+    FirestoreGetByIdSupport, FirestoreQueryParams, FirestoreQuerySupport, FirestoreResult,
     FirestoreTransactionId, FirestoreTransactionMode, FirestoreTransactionOptions,
     FirestoreTransactionResponse, FirestoreWriteResult,
 };
+use backoff::backoff::Backoff;
 use backoff::future::retry;
 use backoff::ExponentialBackoffBuilder;
 use futures::future::BoxFuture;
-use gcloud_sdk::google::firestore::v1::{BeginTransactionRequest, CommitRequest, RollbackRequest};
+use gcloud_sdk::google::firestore::v1::{
+    BeginTransactionRequest, CommitRequest, RollbackRequest, Write,
+};
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tracing::*;
 
+/// The default number of attempts of `run_transaction_fn`
+pub const FIRESTORE_TRANSACTION_MAX_ATTEMPTS: usize = 5;
+
 pub struct FirestoreTransaction<'a> {
     pub db: &'a FirestoreDb,
     pub transaction_id: FirestoreTransactionId,
     pub transaction_span: Span,
-    writes: Vec<gcloud_sdk::google::firestore::v1::Write>,
+    writes: Vec<Write>,
     finished: bool,
+    read_db: FirestoreDb,
+    returned_writes: Option<Arc<Mutex<Option<Vec<Write>>>>>,
 }
 
 impl<'a> FirestoreTransaction<'a> {
@@ -32,7 +45,7 @@ impl<'a> FirestoreTransaction<'a> {
             "/firestore/commit_time" = field::Empty
         );
 
-        let request = tonic::Request::new(BeginTransactionRequest {
+        let request = db.create_request(BeginTransactionRequest {
             database: db.get_database_path().clone(),
             options: Some(options.clone().try_into()?),
         });
@@ -44,22 +57,39 @@ impl<'a> FirestoreTransaction<'a> {
             .await?
             .into_inner();
 
-        let mut hex_trans_id = hex::encode(&response.transaction);
+        let transaction = Self::with_transaction_id(db, response.transaction, transaction_span);
+
+        transaction.transaction_span.in_scope(|| {
+            debug!("Created a new transaction. Mode: {:?}", options.mode);
+        });
+
+        Ok(transaction)
+    }
+
+    fn with_transaction_id(
+        db: &'a FirestoreDb,
+        transaction_id: FirestoreTransactionId,
+        transaction_span: Span,
+    ) -> FirestoreTransaction<'a> {
+        let mut hex_trans_id = hex::encode(&transaction_id);
         hex_trans_id.truncate(16);
 
         transaction_span.record("/firestore/transaction_id", hex_trans_id);
 
-        transaction_span.in_scope(|| {
-            debug!("Created a new transaction. Mode: {:?}", options.mode);
-        });
+        // All reads of the transaction are bound to its ID
+        let read_db = db.clone_with_consistency_selector(
+            FirestoreConsistencySelector::Transaction(transaction_id.clone()),
+        );
 
-        Ok(Self {
+        Self {
             db,
-            transaction_id: response.transaction,
+            transaction_id,
             transaction_span,
             writes: Vec::new(),
             finished: false,
-        })
+            read_db,
+            returned_writes: None,
+        }
     }
 
     #[inline]
@@ -67,6 +97,58 @@ impl<'a> FirestoreTransaction<'a> {
         &self.transaction_id
     }
 
+    /// Fluent API reading within the transaction.
+    /// Unlike `FirestoreDb::fluent`, it fails in debug builds when the transaction already has writes.
+    /// The check only runs when the builder is created, but the builder borrows the transaction,
+    /// so no writes can be added while it is used.
+    #[inline]
+    pub fn fluent(&self) -> FirestoreResult<FirestoreExprBuilder<'_, FirestoreDb>> {
+        Ok(self.read_db()?.fluent())
+    }
+
+    pub async fn get_obj<T, S>(&self, collection_id: &str, document_id: S) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.read_db()?.get_obj(collection_id, document_id).await
+    }
+
+    pub async fn get_obj_if_exists<T, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.read_db()?
+            .get_obj_if_exists(collection_id, document_id, return_only_fields)
+            .await
+    }
+
+    pub async fn query_obj<T>(&self, params: FirestoreQueryParams) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.read_db()?.query_obj(params).await
+    }
+
+    fn read_db(&self) -> FirestoreResult<&FirestoreDb> {
+        // Firestore requires all reads of a transaction to be done before its writes
+        if cfg!(debug_assertions) && !self.writes.is_empty() {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "transaction".to_string(),
+                    "Reads aren't allowed after writes in a transaction".to_string(),
+                )),
+            ));
+        }
+        Ok(&self.read_db)
+    }
+
     #[inline]
     pub fn add<I>(&mut self, write: I) -> FirestoreResult<&mut Self>
     where
@@ -79,7 +161,7 @@ impl<'a> FirestoreTransaction<'a> {
     pub async fn commit(mut self) -> FirestoreResult<FirestoreTransactionResponse> {
         self.finished = true;
 
-        let request = tonic::Request::new(CommitRequest {
+        let request = self.db.create_request(CommitRequest {
             database: self.db.get_database_path().clone(),
             writes: self.writes.drain(..).collect(),
             transaction: self.transaction_id.clone(),
@@ -110,7 +192,7 @@ impl<'a> FirestoreTransaction<'a> {
 
     pub async fn rollback(mut self) -> FirestoreResult<()> {
         self.finished = true;
-        let request = tonic::Request::new(RollbackRequest {
+        let request = self.db.create_request(RollbackRequest {
             database: self.db.get_database_path().clone(),
             transaction: self.transaction_id.clone(),
         });
@@ -128,8 +210,85 @@ impl<'a> FirestoreTransaction<'a> {
 impl<'a> Drop for FirestoreTransaction<'a> {
     fn drop(&mut self) {
         if !self.finished {
-            self.transaction_span
-                .in_scope(|| warn!("Transaction was neither committed nor rollback"));
+            match self.returned_writes.take() {
+                Some(returned_writes) => {
+                    *returned_writes
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                        Some(std::mem::take(&mut self.writes));
+                }
+                None => self
+                    .transaction_span
+                    .in_scope(|| warn!("Transaction was neither committed nor rollback")),
+            }
+        }
+    }
+}
+
+/// The writes of a transaction handed over to a transaction function,
+/// which are returned when the transaction is dropped to be committed
+struct FirestoreTransactionReturnedWrites<'a> {
+    db: &'a FirestoreDb,
+    transaction_id: FirestoreTransactionId,
+    transaction_span: Span,
+    read_db: FirestoreDb,
+    writes: Arc<Mutex<Option<Vec<Write>>>>,
+}
+
+impl<'a> FirestoreTransaction<'a> {
+    fn hand_over(
+        mut self,
+    ) -> (
+        FirestoreTransaction<'a>,
+        FirestoreTransactionReturnedWrites<'a>,
+    ) {
+        let writes = Arc::new(Mutex::new(None));
+        self.returned_writes = Some(writes.clone());
+        let returned_writes = FirestoreTransactionReturnedWrites {
+            db: self.db,
+            transaction_id: self.transaction_id.clone(),
+            transaction_span: self.transaction_span.clone(),
+            read_db: self.read_db.clone(),
+            writes,
+        };
+        (self, returned_writes)
+    }
+}
+
+impl<'a> FirestoreTransactionReturnedWrites<'a> {
+    async fn commit(self) -> FirestoreResult<()> {
+        let writes = self
+            .writes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+
+        match writes {
+            Some(writes) => {
+                FirestoreTransaction {
+                    db: self.db,
+                    transaction_id: self.transaction_id,
+                    transaction_span: self.transaction_span,
+                    writes,
+                    finished: false,
+                    read_db: self.read_db,
+                    returned_writes: None,
+                }
+                .commit()
+                .await?;
+                Ok(())
+            }
+            None if Arc::strong_count(&self.writes) > 1 => Err(
+                FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                    FirestoreInvalidParametersPublicDetails::new(
+                        "transaction".to_string(),
+                        "The transaction is still in use after the transaction function"
+                            .to_string(),
+                    ),
+                )),
+            ),
+            // The transaction function has committed or rolled back the transaction itself
+            None => Ok(()),
         }
     }
 }
@@ -170,9 +329,11 @@ impl FirestoreDb {
         ) -> BoxFuture<'b, std::result::Result<T, BackoffError<E>>>,
         E: std::error::Error + Send + Sync + 'static,
     {
+        let attempt_timeout = options.attempt_timeout.map(|v| v.to_std()).transpose()?;
+
         // Perform our initial attempt. If this fails and the backend tells us we can retry,
         // we'll try again with exponential backoff using the first attempt's transaction ID.
-        let (transaction_id, transaction_span, initial_backoff_duration) = {
+        let (transaction_id, transaction_span, initial_backoff_duration, initial_err) = {
             let mut transaction = self.begin_transaction_with_options(options.clone()).await?;
             let transaction_id = transaction.transaction_id().clone();
             let transaction_span = transaction.transaction_span.clone();
@@ -182,8 +343,11 @@ impl FirestoreDb {
                 FirestoreConsistencySelector::Transaction(transaction_id.clone()),
             );
 
-            match func(cdb, &mut transaction).await {
-                Ok(ret_val) => {
+            let func_result =
+                with_attempt_timeout(attempt_timeout, func(cdb, &mut transaction)).await;
+
+            let initial_err = match func_result {
+                Ok(Ok(ret_val)) => {
                     match transaction.commit().await {
                         Ok(_) => return Ok(ret_val),
                         Err(err) => match err {
@@ -195,17 +359,22 @@ impl FirestoreDb {
                                     )
                                 });
                                 // Ignore; we'll try again below
+                                err
                             }
                             other => return Err(other),
                         },
                     }
                 }
-                Err(err) => match err {
+                Ok(Err(err)) => match err {
                     BackoffError::Transient { err, retry_after } => {
                         transaction_span.in_scope(|| {
                             warn!("Transient error occurred in transaction function: {}. Retrying after: {:?}", &err, retry_after)
                         });
                         initial_backoff_duration = retry_after;
+                        FirestoreError::ErrorInTransaction(FirestoreErrorInTransaction::new(
+                            transaction_id.clone(),
+                            Box::new(err),
+                        ))
                     }
                     BackoffError::Permanent(err) => {
                         return Err(FirestoreError::ErrorInTransaction(
@@ -213,9 +382,20 @@ impl FirestoreDb {
                         ))
                     }
                 },
-            }
+                Err(err) => {
+                    transaction_span.in_scope(|| {
+                        warn!("Transaction function didn't complete in time: {}", &err)
+                    });
+                    err
+                }
+            };
 
-            (transaction_id, transaction_span, initial_backoff_duration)
+            (
+                transaction_id,
+                transaction_span,
+                initial_backoff_duration,
+                initial_err,
+            )
         };
 
         // We failed the first time. Now we must change the transaction mode to signal that we're retrying with the original transaction ID.
@@ -232,22 +412,39 @@ impl FirestoreDb {
             )))
             .build();
 
-        let retry_result = retry(backoff, || async {
+        // The initial attempt is the first one of `max_attempts`
+        let attempts = AtomicUsize::new(1);
+
+        let retry_attempts = retry(backoff, || async {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+            let last_attempt = options.max_attempts.map_or(false, |max| attempt >= max);
+            let to_backoff = move |err: FirestoreError| {
+                if last_attempt {
+                    backoff::Error::permanent(err)
+                } else {
+                    firestore_err_to_backoff(err)
+                }
+            };
+
             let options = FirestoreTransactionOptions {
                 mode: FirestoreTransactionMode::ReadWriteRetry(transaction_id.clone()),
-                ..options
+                ..options.clone()
             };
             let mut transaction = self
                 .begin_transaction_with_options(options)
                 .await
-                .map_err(firestore_err_to_backoff)?;
+                .map_err(to_backoff)?;
             let transaction_id = transaction.transaction_id().clone();
 
             let cdb = self.clone_with_consistency_selector(
                 FirestoreConsistencySelector::Transaction(transaction_id.clone()),
             );
 
-            let ret_val = func(cdb, &mut transaction).await.map_err(|backoff_err| {
+            let func_result = with_attempt_timeout(attempt_timeout, func(cdb, &mut transaction))
+                .await
+                .map_err(to_backoff)?;
+
+            let ret_val = func_result.map_err(|backoff_err| {
                 match backoff_err {
                     BackoffError::Transient { err, retry_after } => {
                         transaction_span.in_scope(|| {
@@ -261,7 +458,9 @@ impl FirestoreDb {
                             ),
                         );
 
-                        if let Some(retry_after_duration) = retry_after {
+                        if last_attempt {
+                            backoff::Error::permanent(firestore_err)
+                        } else if let Some(retry_after_duration) = retry_after {
                             backoff::Error::retry_after(
                                 firestore_err,
                                 retry_after_duration
@@ -283,14 +482,16 @@ impl FirestoreDb {
                 }
             })?;
 
-            transaction
-                .commit()
-                .await
-                .map_err(firestore_err_to_backoff)?;
+            transaction.commit().await.map_err(to_backoff)?;
 
             Ok(ret_val)
-        })
-        .await;
+        });
+
+        let retry_result = if options.max_attempts.map_or(false, |max| max <= 1) {
+            Err(initial_err)
+        } else {
+            retry_attempts.await
+        };
 
         if let Err(ref err) = retry_result {
             transaction_span.in_scope(|| {
@@ -311,4 +512,194 @@ impl FirestoreDb {
 
         retry_result
     }
+
+    /// Runs the transaction function and commits the transaction, retrying the retryable errors.
+    /// The function gets the database bound to the transaction for reads and the transaction
+    /// to add the writes to. The writes are committed when the function returns successfully,
+    /// unless the function has already committed or rolled back the transaction itself.
+    pub async fn run_transaction_fn<'a, T, FN, FT>(&'a self, func: FN) -> FirestoreResult<T>
+    where
+        FN: Fn(FirestoreDb, FirestoreTransaction<'a>) -> FT,
+        FT: Future<Output = FirestoreResult<T>>,
+    {
+        self.run_transaction_fn_with_options(func, FirestoreTransactionOptions::new())
+            .await
+    }
+
+    pub async fn run_transaction_fn_with_options<'a, T, FN, FT>(
+        &'a self,
+        func: FN,
+        options: FirestoreTransactionOptions,
+    ) -> FirestoreResult<T>
+    where
+        FN: Fn(FirestoreDb, FirestoreTransaction<'a>) -> FT,
+        FT: Future<Output = FirestoreResult<T>>,
+    {
+        let max_attempts = options
+            .max_attempts
+            .unwrap_or(FIRESTORE_TRANSACTION_MAX_ATTEMPTS);
+        let attempt_timeout = options.attempt_timeout.map(|v| v.to_std()).transpose()?;
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(options.max_elapsed_time.map(|v| v.to_std()).transpose()?)
+            .build();
+
+        let mut retry_transaction_id: Option<FirestoreTransactionId> = None;
+        let mut attempt = 1;
+
+        loop {
+            let attempt_options = match (&options.mode, &retry_transaction_id) {
+                (FirestoreTransactionMode::ReadWrite, Some(transaction_id)) => {
+                    FirestoreTransactionOptions {
+                        mode: FirestoreTransactionMode::ReadWriteRetry(transaction_id.clone()),
+                        ..options.clone()
+                    }
+                }
+                _ => options.clone(),
+            };
+
+            let (transaction_id, result) = match self
+                .begin_transaction_with_options(attempt_options)
+                .await
+            {
+                Ok(transaction) => {
+                    let transaction_id = transaction.transaction_id().clone();
+                    let cdb = self.clone_with_consistency_selector(
+                        FirestoreConsistencySelector::Transaction(transaction_id.clone()),
+                    );
+                    let (transaction, returned_writes) = transaction.hand_over();
+
+                    let result =
+                        match with_attempt_timeout(attempt_timeout, func(cdb, transaction)).await {
+                            Ok(Ok(ret_val)) => returned_writes.commit().await.map(|_| ret_val),
+                            Ok(Err(err)) | Err(err) => Err(err),
+                        };
+                    (Some(transaction_id), result)
+                }
+                Err(err) => (None, Err(err)),
+            };
+
+            let err = match result {
+                Ok(ret_val) => return Ok(ret_val),
+                Err(err) => err,
+            };
+
+            if let Some(ref transaction_id) = transaction_id {
+                // Release the locks of the failed attempt, this doesn't affect the result
+                if let Err(rollback_err) = self.rollback_transaction_id(transaction_id).await {
+                    debug!("Unable to roll back the transaction: {}", rollback_err);
+                }
+            }
+
+            let retry_delay = match err {
+                FirestoreError::DatabaseError(ref db_err)
+                    if db_err.retry_possible && attempt < max_attempts =>
+                {
+                    backoff
+                        .next_backoff()
+                        .map(|delay| db_err.retry_delay.unwrap_or(delay))
+                }
+                _ => None,
+            };
+
+            match retry_delay {
+                Some(delay) => {
+                    warn!(
+                        "[DB]: Transaction attempt {} failed with {}. Retrying in {:?}",
+                        attempt, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    if transaction_id.is_some() {
+                        retry_transaction_id = transaction_id;
+                    }
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
+    async fn rollback_transaction_id(
+        &self,
+        transaction_id: &FirestoreTransactionId,
+    ) -> FirestoreResult<()> {
+        let request = self.create_request(RollbackRequest {
+            database: self.get_database_path().clone(),
+            transaction: transaction_id.clone(),
+        });
+
+        self.client().get().rollback(request).await?;
+        Ok(())
+    }
+}
+
+async fn with_attempt_timeout<F>(
+    attempt_timeout: Option<Duration>,
+    future: F,
+) -> FirestoreResult<F::Output>
+where
+    F: Future,
+{
+    match attempt_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.map_err(|_| {
+            FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+                FirestoreErrorPublicGenericDetails::new("DeadlineExceeded".into()),
+                format!("Transaction attempt timed out after {timeout:?}"),
+                true,
+            ))
+        }),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::offline_test_db;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn reads_are_bound_to_transaction() -> FirestoreResult<()> {
+        let db = offline_test_db().await;
+        let transaction_id: FirestoreTransactionId = vec![1, 2, 3];
+        let mut transaction =
+            FirestoreTransaction::with_transaction_id(&db, transaction_id.clone(), Span::none());
+
+        assert_eq!(
+            transaction.read_db()?.session_params.consistency_selector,
+            Some(FirestoreConsistencySelector::Transaction(transaction_id))
+        );
+        assert!(transaction.fluent().is_ok());
+
+        transaction.delete_by_id("test", "test-0", None)?;
+
+        assert!(matches!(
+            transaction.fluent(),
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+        assert!(matches!(
+            transaction
+                .get_obj_if_exists::<HashMap<String, String>, _>("test", "test-1", None)
+                .await,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        transaction.finished = true;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attempt_timeout_is_retryable() {
+        let completed = with_attempt_timeout(Some(Duration::from_secs(1)), async { 42 }).await;
+        assert!(matches!(completed, Ok(42)));
+
+        let timed_out = with_attempt_timeout(
+            Some(Duration::from_millis(10)),
+            tokio::time::sleep(Duration::from_secs(10)),
+        )
+        .await;
+        assert!(matches!(
+            timed_out,
+            Err(FirestoreError::DatabaseError(ref db_err)) if db_err.retry_possible
+        ));
+    }
 }
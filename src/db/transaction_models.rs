@@ -9,6 +9,11 @@ pub struct FirestoreTransactionOptions {
     #[default = "FirestoreTransactionMode::ReadWrite"]
     pub mode: FirestoreTransactionMode,
     pub max_elapsed_time: Option<Duration>,
+    /// The maximum number of attempts including the first one.
+    /// `run_transaction_fn` makes 5 attempts by default, `run_transaction` isn't limited.
+    pub max_attempts: Option<usize>,
+    /// The time limit for the transaction function in every attempt
+    pub attempt_timeout: Option<Duration>,
 }
 
 impl Default for FirestoreTransactionOptions {
@@ -16,6 +21,8 @@ impl Default for FirestoreTransactionOptions {
         Self {
             mode: FirestoreTransactionMode::ReadWrite,
             max_elapsed_time: None,
+            max_attempts: None,
+            attempt_timeout: None,
         }
     }
 }
@@ -3,7 +3,7 @@ use crate::{
     FirestoreDb, FirestoreError, FirestoreFieldTransform, FirestoreResult, FirestoreTransaction,
     FirestoreWritePrecondition,
 };
-use gcloud_sdk::google::firestore::v1::Write;
+use gcloud_sdk::google::firestore::v1::{Document, Write};
 use serde::Serialize;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -57,6 +57,80 @@ where
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CreateObjectOperation<'a, T, S>
+where
+    T: Serialize + Sync + Send,
+    S: AsRef<str>,
+{
+    pub parent: String,
+    pub collection_id: String,
+    pub document_id: S,
+    pub obj: &'a T,
+}
+
+impl<'a, T, S> TryInto<Write> for CreateObjectOperation<'a, T, S>
+where
+    T: Serialize + Sync + Send,
+    S: AsRef<str>,
+{
+    type Error = FirestoreError;
+
+    fn try_into(self) -> Result<Write, Self::Error> {
+        CreateDocumentOperation {
+            document: FirestoreDb::serialize_to_doc(
+                &safe_document_path(
+                    &self.parent,
+                    self.collection_id.as_str(),
+                    self.document_id.as_ref(),
+                )?,
+                &self.obj,
+            )?,
+            parent: self.parent,
+            collection_id: self.collection_id,
+            document_id: self.document_id,
+        }
+        .try_into()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CreateDocumentOperation<S>
+where
+    S: AsRef<str>,
+{
+    pub parent: String,
+    pub collection_id: String,
+    pub document_id: S,
+    pub document: Document,
+}
+
+impl<S> TryInto<Write> for CreateDocumentOperation<S>
+where
+    S: AsRef<str>,
+{
+    type Error = FirestoreError;
+
+    fn try_into(self) -> Result<Write, Self::Error> {
+        // Creating is an update that requires the document to not exist
+        Ok(Write {
+            update_mask: None,
+            update_transforms: vec![],
+            current_document: Some(FirestoreWritePrecondition::Exists(false).try_into()?),
+            operation: Some(gcloud_sdk::google::firestore::v1::write::Operation::Update(
+                Document {
+                    name: safe_document_path(
+                        &self.parent,
+                        self.collection_id.as_str(),
+                        self.document_id.as_ref(),
+                    )?,
+                    ..self.document
+                },
+            )),
+        })
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub(crate) struct DeleteOperation<S>
 where
@@ -131,6 +205,78 @@ where
 }
 
 impl<'a> FirestoreTransaction<'a> {
+    pub fn create_object<T, S>(
+        &mut self,
+        collection_id: &str,
+        document_id: S,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.create_object_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            obj,
+        )
+    }
+
+    pub fn create_object_at<T, S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.add(CreateObjectOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id,
+            obj,
+        })
+    }
+
+    pub fn create_document<S>(
+        &mut self,
+        collection_id: &str,
+        document_id: S,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.create_document_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            document,
+        )
+    }
+
+    pub fn create_document_at<S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.add(CreateDocumentOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id,
+            document,
+        })
+    }
+
     pub fn update_object<T, S>(
         &mut self,
         collection_id: &str,
@@ -254,3 +400,39 @@ impl<'a> FirestoreTransaction<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::{precondition, write};
+
+    #[derive(Serialize)]
+    struct TestStructure {
+        some_num: u64,
+    }
+
+    #[test]
+    fn create_object_operation_to_write() -> FirestoreResult<()> {
+        let write: Write = CreateObjectOperation {
+            parent: "projects/test/databases/(default)/documents".to_string(),
+            collection_id: "test".to_string(),
+            document_id: "doc1",
+            obj: &TestStructure { some_num: 42 },
+        }
+        .try_into()?;
+
+        assert_eq!(
+            write
+                .current_document
+                .and_then(|precondition| precondition.condition_type),
+            Some(precondition::ConditionType::Exists(false))
+        );
+        assert!(matches!(
+            write.operation,
+            Some(write::Operation::Update(Document { ref name, ref fields, .. }))
+                if name == "projects/test/databases/(default)/documents/test/doc1"
+                    && fields.contains_key("some_num")
+        ));
+        Ok(())
+    }
+}
@@ -125,7 +125,7 @@ impl FirestoreUpdateSupport for FirestoreDb {
 
         let document_id = firestore_doc.name.clone();
 
-        let update_document_request = tonic::Request::new(UpdateDocumentRequest {
+        let update_document_request = UpdateDocumentRequest {
             update_mask: update_only.map({
                 |vf| DocumentMask {
                     field_paths: vf.iter().map(|f| f.to_string()).collect(),
@@ -136,13 +136,14 @@ impl FirestoreUpdateSupport for FirestoreDb {
                 field_paths: masks.clone(),
             }),
             current_document: precondition.map(|cond| cond.try_into()).transpose()?,
-        });
+        };
 
         let begin_query_utc: DateTime<Utc> = Utc::now();
         let update_response = self
-            .client()
-            .get()
-            .update_document(update_document_request)
+            .with_retries(|| {
+                let request = self.create_request(update_document_request.clone());
+                async move { Ok(self.client().get().update_document(request).await?) }
+            })
             .await?;
         let end_query_utc: DateTime<Utc> = Utc::now();
         let query_duration = end_query_utc.signed_duration_since(begin_query_utc);
@@ -1,5 +1,6 @@
 use crate::{FirestoreTransaction, FirestoreTransactionId};
 use gcloud_sdk::google::firestore::v1::WriteRequest;
+use prost::Message;
 use rsb_derive::Builder;
 use serde::*;
 use std::error::Error;
@@ -75,6 +76,8 @@ pub struct FirestoreDatabaseError {
     pub public: FirestoreErrorPublicGenericDetails,
     pub details: String,
     pub retry_possible: bool,
+    /// The delay before retrying requested by the server
+    pub retry_delay: Option<std::time::Duration>,
 }
 
 impl Display for FirestoreDatabaseError {
@@ -160,6 +163,18 @@ impl From<gcloud_sdk::error::Error> for FirestoreError {
     }
 }
 
+impl From<gcloud_sdk::google::rpc::Status> for FirestoreError {
+    fn from(status: gcloud_sdk::google::rpc::Status) -> Self {
+        // Keeping the details, so they are classified the same way as the request errors
+        let details = status.encode_to_vec();
+        FirestoreError::from(tonic::Status::with_details(
+            tonic::Code::from_i32(status.code),
+            status.message,
+            details.into(),
+        ))
+    }
+}
+
 impl From<tonic::Status> for FirestoreError {
     fn from(status: tonic::Status) -> Self {
         match status.code() {
@@ -175,15 +190,28 @@ impl From<tonic::Status> for FirestoreError {
                     format!("{status}"),
                 ))
             }
-            tonic::Code::Aborted
-            | tonic::Code::Cancelled
-            | tonic::Code::Unavailable
-            | tonic::Code::ResourceExhausted => {
-                FirestoreError::DatabaseError(FirestoreDatabaseError::new(
-                    FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
-                    format!("{status}"),
-                    true,
-                ))
+            tonic::Code::Aborted | tonic::Code::Cancelled | tonic::Code::Unavailable => {
+                FirestoreError::DatabaseError(
+                    FirestoreDatabaseError::new(
+                        FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
+                        format!("{status}"),
+                        true,
+                    )
+                    .opt_retry_delay(status_retry_delay(&status)),
+                )
+            }
+            tonic::Code::ResourceExhausted => {
+                let retry_delay = status_retry_delay(&status);
+                // Exhausted quotas aren't restored soon, unless the server asks to retry later
+                let retry_possible = retry_delay.is_some() || !status_has_quota_failure(&status);
+                FirestoreError::DatabaseError(
+                    FirestoreDatabaseError::new(
+                        FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
+                        format!("{status}"),
+                        retry_possible,
+                    )
+                    .opt_retry_delay(retry_delay),
+                )
             }
             tonic::Code::Unknown => check_hyper_errors(status),
             _ => FirestoreError::DatabaseError(FirestoreDatabaseError::new(
@@ -195,6 +223,42 @@ impl From<tonic::Status> for FirestoreError {
     }
 }
 
+const RETRY_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RetryInfo";
+const QUOTA_FAILURE_TYPE_URL: &str = "type.googleapis.com/google.rpc.QuotaFailure";
+
+fn status_error_details(status: &tonic::Status) -> Vec<prost_types::Any> {
+    if status.details().is_empty() {
+        vec![]
+    } else {
+        gcloud_sdk::google::rpc::Status::decode(status.details())
+            .map(|rpc_status| rpc_status.details)
+            .unwrap_or_default()
+    }
+}
+
+fn status_retry_delay(status: &tonic::Status) -> Option<std::time::Duration> {
+    status_error_details(status)
+        .into_iter()
+        .filter(|detail| detail.type_url == RETRY_INFO_TYPE_URL)
+        .find_map(|detail| {
+            gcloud_sdk::google::rpc::RetryInfo::decode(detail.value.as_slice())
+                .ok()
+                .and_then(|retry_info| retry_info.retry_delay)
+        })
+        .map(|delay| {
+            std::time::Duration::new(
+                delay.seconds.max(0) as u64,
+                delay.nanos.clamp(0, 999_999_999) as u32,
+            )
+        })
+}
+
+fn status_has_quota_failure(status: &tonic::Status) -> bool {
+    status_error_details(status)
+        .iter()
+        .any(|detail| detail.type_url == QUOTA_FAILURE_TYPE_URL)
+}
+
 fn check_hyper_errors(status: tonic::Status) -> FirestoreError {
     match status.source() {
         Some(hyper_error) => match hyper_error.downcast_ref::<hyper::Error>() {
@@ -364,6 +428,13 @@ impl std::error::Error for FirestoreErrorInTransaction {
 
 pub type BackoffError<E> = backoff::Error<E>;
 
+pub(crate) fn unsupported_operation_error(operation: &str) -> FirestoreError {
+    FirestoreError::SystemError(FirestoreSystemError::new(
+        FirestoreErrorPublicGenericDetails::new("UNSUPPORTED_OPERATION".into()),
+        format!("{operation} isn't supported by this implementation"),
+    ))
+}
+
 pub(crate) fn firestore_err_to_backoff(err: FirestoreError) -> BackoffError<FirestoreError> {
     match err {
         FirestoreError::DatabaseError(ref db_err) if db_err.retry_possible => {
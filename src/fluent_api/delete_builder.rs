@@ -1,6 +1,11 @@
+use crate::db::DeleteOperation;
+use crate::select_filter_builder::FirestoreQueryFilterBuilder;
 use crate::{
-    FirestoreBatch, FirestoreBatchWriter, FirestoreDeleteSupport, FirestoreResult,
-    FirestoreTransaction, FirestoreWritePrecondition,
+    FirestoreBatch, FirestoreBatchWriter, FirestoreDeleteSupport, FirestoreQueryFilter,
+    FirestoreQueryParams, FirestoreQueryWriteResult, FirestoreQueryWriteSupport,
+    FirestoreRecursiveDeleteProgress, FirestoreRecursiveDeleteResult,
+    FirestoreRecursiveDeleteSupport, FirestoreResult, FirestoreTransaction,
+    FirestoreWritePrecondition,
 };
 
 #[derive(Clone, Debug)]
@@ -70,6 +75,32 @@ where
         }
     }
 
+    /// Deletes the whole collection with the subcollections of its documents
+    #[inline]
+    pub fn recursive(self) -> FirestoreDeleteRecursiveExecuteBuilder<'a, D>
+    where
+        D: FirestoreRecursiveDeleteSupport,
+    {
+        FirestoreDeleteRecursiveExecuteBuilder::new(
+            self.db,
+            recursive_path(self.parent, vec![self.collection_id]),
+        )
+    }
+
+    /// Deletes all documents of the collection matching the filter
+    #[inline]
+    pub fn filter<FN>(self, filter: FN) -> FirestoreDeleteByQueryBuilder<'a, D>
+    where
+        D: FirestoreQueryWriteSupport,
+        FN: Fn(FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter>,
+    {
+        FirestoreDeleteByQueryBuilder::new(
+            self.db,
+            FirestoreQueryParams::new(self.collection_id.as_str().into()).opt_parent(self.parent),
+        )
+        .filter(filter)
+    }
+
     #[inline]
     pub fn document_id<S>(self, document_id: S) -> FirestoreDeleteExecuteBuilder<'a, D>
     where
@@ -137,6 +168,18 @@ where
         }
     }
 
+    /// Deletes the document with all its subcollections
+    #[inline]
+    pub fn recursive(self) -> FirestoreDeleteRecursiveExecuteBuilder<'a, D>
+    where
+        D: FirestoreRecursiveDeleteSupport,
+    {
+        FirestoreDeleteRecursiveExecuteBuilder::new(
+            self.db,
+            recursive_path(self.parent, vec![self.collection_id, self.document_id]),
+        )
+    }
+
     pub async fn execute(self) -> FirestoreResult<()> {
         if let Some(parent) = self.parent {
             self.db
@@ -187,19 +230,133 @@ where
     where
         W: FirestoreBatchWriter,
     {
-        if let Some(parent) = self.parent {
-            batch.delete_by_id_at(
-                parent.as_str(),
-                self.collection_id.as_str(),
-                self.document_id,
-                self.precondition,
-            )
-        } else {
-            batch.delete_by_id(
-                self.collection_id.as_str(),
-                self.document_id,
-                self.precondition,
-            )
+        let operation = self.delete_operation(batch.db.get_documents_path());
+        batch.add(operation)
+    }
+
+    /// Adds the delete to the batch with a key to find its outcome in the response
+    #[inline]
+    pub fn add_to_batch_with_key<'t, W, K>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+        key: K,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+        K: AsRef<str>,
+    {
+        let operation = self.delete_operation(batch.db.get_documents_path());
+        batch.add_with_key(operation, key)
+    }
+
+    fn delete_operation(self, documents_path: &str) -> DeleteOperation<String> {
+        DeleteOperation {
+            parent: self.parent.unwrap_or_else(|| documents_path.to_string()),
+            collection_id: self.collection_id,
+            document_id: self.document_id,
+            precondition: self.precondition,
+        }
+    }
+}
+
+fn recursive_path(parent: Option<String>, segments: Vec<String>) -> String {
+    parent
+        .into_iter()
+        .chain(segments)
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+#[derive(Clone, Debug)]
+pub struct FirestoreDeleteRecursiveExecuteBuilder<'a, D>
+where
+    D: FirestoreRecursiveDeleteSupport,
+{
+    db: &'a D,
+    path: String,
+}
+
+impl<'a, D> FirestoreDeleteRecursiveExecuteBuilder<'a, D>
+where
+    D: FirestoreRecursiveDeleteSupport,
+{
+    #[inline]
+    pub(crate) fn new(db: &'a D, path: String) -> Self {
+        Self { db, path }
+    }
+
+    pub async fn execute(self) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        D: Sync,
+    {
+        self.db.recursive_delete(self.path).await
+    }
+
+    pub async fn execute_with_progress<F>(
+        self,
+        on_progress: F,
+    ) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        F: Fn(&FirestoreRecursiveDeleteProgress) + Send + Sync,
+        D: Sync,
+    {
+        self.db
+            .recursive_delete_with_progress(self.path, on_progress)
+            .await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FirestoreDeleteByQueryBuilder<'a, D>
+where
+    D: FirestoreQueryWriteSupport,
+{
+    db: &'a D,
+    params: FirestoreQueryParams,
+    update_time_precondition: bool,
+}
+
+impl<'a, D> FirestoreDeleteByQueryBuilder<'a, D>
+where
+    D: FirestoreQueryWriteSupport,
+{
+    #[inline]
+    pub(crate) fn new(db: &'a D, params: FirestoreQueryParams) -> Self {
+        Self {
+            db,
+            params,
+            update_time_precondition: false,
         }
     }
+
+    #[inline]
+    pub fn filter<FN>(self, filter: FN) -> Self
+    where
+        FN: Fn(FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter>,
+    {
+        let filter_builder = FirestoreQueryFilterBuilder::new();
+
+        Self {
+            params: self.params.opt_filter(filter(filter_builder)),
+            ..self
+        }
+    }
+
+    /// Skips the documents changed after they were found by the query
+    #[inline]
+    pub fn update_time_precondition(self) -> Self {
+        Self {
+            update_time_precondition: true,
+            ..self
+        }
+    }
+
+    pub async fn execute(self) -> FirestoreResult<FirestoreQueryWriteResult>
+    where
+        D: Sync,
+    {
+        self.db
+            .delete_by_query(self.params, self.update_time_precondition)
+            .await
+    }
 }
@@ -1,4 +1,8 @@
-use crate::{FirestoreCreateSupport, FirestoreResult};
+use crate::db::{CreateDocumentOperation, CreateObjectOperation};
+use crate::{
+    FirestoreBatch, FirestoreBatchWriter, FirestoreCreateSupport, FirestoreDb, FirestoreResult,
+    FirestoreTransaction,
+};
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::{Deserialize, Serialize};
 
@@ -55,9 +59,14 @@ where
         )
     }
 
+    /// Generates the document ID on the client side, see `FirestoreDb::generate_document_id`.
     #[inline]
     pub fn generate_document_id(self) -> FirestoreInsertDocObjBuilder<'a, D> {
-        FirestoreInsertDocObjBuilder::new(self.db, self.collection_id, None)
+        FirestoreInsertDocObjBuilder::new(
+            self.db,
+            self.collection_id,
+            Some(FirestoreDb::generate_document_id()),
+        )
     }
 }
 
@@ -88,6 +97,12 @@ where
         }
     }
 
+    /// The ID of the document to be created
+    #[inline]
+    pub fn get_document_id(&self) -> Option<&String> {
+        self.document_id.as_ref()
+    }
+
     #[inline]
     pub fn parent<S>(self, parent: S) -> Self
     where
@@ -203,6 +218,61 @@ where
                 .await
         }
     }
+
+    /// Returns the ID of the document, generating it on the client side if it isn't specified,
+    /// so it is known before the document is added to a transaction or a batch
+    #[inline]
+    pub fn document_id_or_generate(&mut self) -> &str {
+        self.document_id
+            .get_or_insert_with(FirestoreDb::generate_document_id)
+    }
+
+    #[inline]
+    pub fn add_to_transaction<'t>(
+        self,
+        transaction: &'a mut FirestoreTransaction<'t>,
+    ) -> FirestoreResult<&'a mut FirestoreTransaction<'t>> {
+        let operation = self.create_operation(transaction.db.get_documents_path());
+        transaction.add(operation)
+    }
+
+    #[inline]
+    pub fn add_to_batch<'t, W>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+    {
+        let operation = self.create_operation(batch.db.get_documents_path());
+        batch.add(operation)
+    }
+
+    /// Adds the insert to the batch with a key to find its outcome in the response
+    #[inline]
+    pub fn add_to_batch_with_key<'t, W, K>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+        key: K,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+        K: AsRef<str>,
+    {
+        let operation = self.create_operation(batch.db.get_documents_path());
+        batch.add_with_key(operation, key)
+    }
+
+    fn create_operation(self, documents_path: &str) -> CreateDocumentOperation<String> {
+        CreateDocumentOperation {
+            parent: self.parent.unwrap_or_else(|| documents_path.to_string()),
+            collection_id: self.collection_id,
+            document_id: self
+                .document_id
+                .unwrap_or_else(FirestoreDb::generate_document_id),
+            document: self.document,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -268,4 +338,94 @@ where
                 .await
         }
     }
+
+    /// Returns the ID of the document, generating it on the client side if it isn't specified,
+    /// so it is known before the object is added to a transaction or a batch
+    #[inline]
+    pub fn document_id_or_generate(&mut self) -> &str {
+        self.document_id
+            .get_or_insert_with(FirestoreDb::generate_document_id)
+    }
+
+    #[inline]
+    pub fn add_to_transaction<'t>(
+        self,
+        transaction: &'a mut FirestoreTransaction<'t>,
+    ) -> FirestoreResult<&'a mut FirestoreTransaction<'t>> {
+        let operation = self.create_operation(transaction.db.get_documents_path());
+        transaction.add(operation)
+    }
+
+    #[inline]
+    pub fn add_to_batch<'t, W>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+    {
+        let operation = self.create_operation(batch.db.get_documents_path());
+        batch.add(operation)
+    }
+
+    /// Adds the insert to the batch with a key to find its outcome in the response
+    #[inline]
+    pub fn add_to_batch_with_key<'t, W, K>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+        key: K,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+        K: AsRef<str>,
+    {
+        let operation = self.create_operation(batch.db.get_documents_path());
+        batch.add_with_key(operation, key)
+    }
+
+    fn create_operation(self, documents_path: &str) -> CreateObjectOperation<'a, T, String> {
+        CreateObjectOperation {
+            parent: self.parent.unwrap_or_else(|| documents_path.to_string()),
+            collection_id: self.collection_id,
+            document_id: self
+                .document_id
+                .unwrap_or_else(FirestoreDb::generate_document_id),
+            obj: self.object,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::tests::offline_test_db;
+    use crate::*;
+    use gcloud_sdk::google::firestore::v1::write;
+
+    #[tokio::test]
+    async fn add_to_batch_with_generated_id() -> FirestoreResult<()> {
+        let db = offline_test_db().await;
+        let batch_writer = db.create_simple_batch_writer().await?;
+        let mut batch = batch_writer.new_batch();
+
+        let mut insert = db
+            .fluent()
+            .insert()
+            .into("test")
+            .generate_document_id()
+            .document(Default::default());
+        let document_id = insert.document_id_or_generate().to_string();
+        assert_eq!(insert.document_id_or_generate(), document_id);
+
+        insert
+            .add_to_batch(&mut batch)?
+            .delete_by_id("test", "other", None)?;
+
+        assert_eq!(batch.writes.len(), 2);
+        assert!(matches!(
+            &batch.writes[0].operation,
+            Some(write::Operation::Update(document))
+                if document.name == format!("{}/test/{}", db.get_documents_path(), document_id)
+        ));
+        Ok(())
+    }
 }
@@ -2,6 +2,7 @@ use crate::{
     FirestoreListCollectionIdsParams, FirestoreListCollectionIdsResult, FirestoreListDocParams,
     FirestoreListDocResult, FirestoreListingSupport, FirestoreQueryOrder, FirestoreResult,
 };
+use chrono::prelude::*;
 use futures::stream::BoxStream;
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::Deserialize;
@@ -118,6 +119,15 @@ where
         }
     }
 
+    /// Lists the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: self.params.with_read_time(read_time),
+            ..self
+        }
+    }
+
     pub async fn get_page(self) -> FirestoreResult<FirestoreListDocResult> {
         self.db.list_doc(self.params).await
     }
@@ -213,6 +223,15 @@ where
         }
     }
 
+    /// Lists the collections as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: self.params.with_read_time(read_time),
+            ..self
+        }
+    }
+
     pub async fn get_page(self) -> FirestoreResult<FirestoreListCollectionIdsResult> {
         self.db.list_collection_ids(self.params).await
     }
@@ -1,5 +1,6 @@
 use crate::{
-    FirestoreAggregation, FirestoreAggregationOperator, FirestoreAggregationOperatorCount,
+    FirestoreAggregation, FirestoreAggregationOperator, FirestoreAggregationOperatorAvg,
+    FirestoreAggregationOperatorCount, FirestoreAggregationOperatorSum,
 };
 
 pub struct FirestoreAggregationBuilder {}
@@ -49,6 +50,30 @@ impl FirestoreAggregationFieldExpr {
             FirestoreAggregationOperator::Count(FirestoreAggregationOperatorCount::new()),
         ))
     }
+
+    #[inline]
+    pub fn sum<S>(self, field_name: S) -> Option<FirestoreAggregation>
+    where
+        S: AsRef<str>,
+    {
+        Some(FirestoreAggregation::new(self.field_name).with_operator(
+            FirestoreAggregationOperator::Sum(FirestoreAggregationOperatorSum::new(
+                field_name.as_ref().to_string(),
+            )),
+        ))
+    }
+
+    #[inline]
+    pub fn avg<S>(self, field_name: S) -> Option<FirestoreAggregation>
+    where
+        S: AsRef<str>,
+    {
+        Some(FirestoreAggregation::new(self.field_name).with_operator(
+            FirestoreAggregationOperator::Avg(FirestoreAggregationOperatorAvg::new(
+                field_name.as_ref().to_string(),
+            )),
+        ))
+    }
 }
 
 impl FirestoreAggregationExpr for FirestoreAggregation {
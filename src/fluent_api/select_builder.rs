@@ -1,16 +1,21 @@
+use crate::db::query_doc_page;
+use crate::db::query_ordering::{document_cursor_values, effective_order_by};
 use crate::errors::FirestoreError;
 use crate::select_aggregation_builder::FirestoreAggregationBuilder;
 use crate::select_filter_builder::FirestoreQueryFilterBuilder;
 use crate::{
     FirestoreAggregatedQueryParams, FirestoreAggregatedQuerySupport, FirestoreAggregation,
-    FirestoreCollectionDocuments, FirestoreGetByIdSupport, FirestoreListenSupport,
-    FirestoreListener, FirestoreListenerParams, FirestoreListenerTarget,
+    FirestoreBatchGetParams, FirestoreCollectionDocuments, FirestoreDb, FirestoreGetByIdSupport,
+    FirestoreListenSupport, FirestoreListener, FirestoreListenerParams, FirestoreListenerTarget,
     FirestoreListenerTargetParams, FirestorePartition, FirestorePartitionQueryParams,
     FirestoreQueryCollection, FirestoreQueryCursor, FirestoreQueryFilter, FirestoreQueryOrder,
-    FirestoreQueryParams, FirestoreQuerySupport, FirestoreResult, FirestoreResumeStateStorage,
-    FirestoreTargetType,
+    FirestoreQueryPage, FirestoreQueryPageToken, FirestoreQueryParams, FirestoreQuerySupport,
+    FirestoreQueryView, FirestoreQueryViewParams, FirestoreResult, FirestoreResumeStateStorage,
+    FirestoreTargetType, FirestoreValue,
 };
+use chrono::prelude::*;
 use futures::stream::BoxStream;
+use futures::{future, StreamExt, TryStreamExt};
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -130,6 +135,15 @@ where
         }
     }
 
+    /// Returns the last documents in the `order_by` order
+    #[inline]
+    pub fn limit_to_last(self, value: u32) -> Self {
+        Self {
+            params: self.params.with_limit_to_last(value),
+            ..self
+        }
+    }
+
     #[inline]
     pub fn offset(self, value: u32) -> Self {
         Self {
@@ -168,6 +182,54 @@ where
         }
     }
 
+    /// Starts the query at the document, including it.
+    /// The ordering and the filter should be specified before the document cursors.
+    pub fn start_at_document(self, document: &Document) -> FirestoreResult<Self> {
+        let (builder, values) = self.with_document_ordering(document)?;
+        Ok(builder.start_at(FirestoreQueryCursor::BeforeValue(values)))
+    }
+
+    /// Starts the query after the document.
+    /// The ordering and the filter should be specified before the document cursors.
+    pub fn start_after_document(self, document: &Document) -> FirestoreResult<Self> {
+        let (builder, values) = self.with_document_ordering(document)?;
+        Ok(builder.start_at(FirestoreQueryCursor::AfterValue(values)))
+    }
+
+    /// Ends the query before the document.
+    /// The ordering and the filter should be specified before the document cursors.
+    pub fn end_before_document(self, document: &Document) -> FirestoreResult<Self> {
+        let (builder, values) = self.with_document_ordering(document)?;
+        Ok(builder.end_at(FirestoreQueryCursor::BeforeValue(values)))
+    }
+
+    /// Ends the query at the document, including it.
+    /// The ordering and the filter should be specified before the document cursors.
+    pub fn end_at_document(self, document: &Document) -> FirestoreResult<Self> {
+        let (builder, values) = self.with_document_ordering(document)?;
+        Ok(builder.end_at(FirestoreQueryCursor::AfterValue(values)))
+    }
+
+    // Makes the ordering explicit, including the document name, as the cursor values follow it
+    fn with_document_ordering(
+        self,
+        document: &Document,
+    ) -> FirestoreResult<(Self, Vec<FirestoreValue>)> {
+        let order_by = effective_order_by(&self.params);
+        let values = document_cursor_values(document, &order_by)?
+            .into_iter()
+            .map(FirestoreValue::from)
+            .collect();
+
+        Ok((
+            Self {
+                params: self.params.with_order_by(order_by),
+                ..self
+            },
+            values,
+        ))
+    }
+
     #[inline]
     pub fn all_descendants(self) -> Self {
         Self {
@@ -189,6 +251,15 @@ where
         }
     }
 
+    /// Reads the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: self.params.with_read_time(read_time),
+            ..self
+        }
+    }
+
     #[inline]
     pub fn obj<T>(self) -> FirestoreSelectObjBuilder<'a, D, T>
     where
@@ -238,6 +309,15 @@ where
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
         self.db.stream_query_doc_with_errors(self.params).await
     }
+
+    /// Reads a page of the query. Pass the `next_page_token` of the previous page to continue.
+    pub async fn page(
+        self,
+        page_size: u32,
+        page_token: Option<&FirestoreQueryPageToken>,
+    ) -> FirestoreResult<FirestoreQueryPage<Document>> {
+        query_doc_page(self.db, self.params, page_size, page_token).await
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -286,12 +366,48 @@ where
         self.db.stream_query_obj_with_errors(self.params).await
     }
 
+    /// Reads a page of the query. Pass the `next_page_token` of the previous page to continue.
+    pub async fn page(
+        self,
+        page_size: u32,
+        page_token: Option<&FirestoreQueryPageToken>,
+    ) -> FirestoreResult<FirestoreQueryPage<T>> {
+        let page = query_doc_page(self.db, self.params, page_size, page_token).await?;
+        Ok(FirestoreQueryPage {
+            items: page
+                .items
+                .iter()
+                .map(FirestoreDb::deserialize_doc_to)
+                .collect::<FirestoreResult<Vec<T>>>()?,
+            next_page_token: page.next_page_token,
+        })
+    }
+
     pub fn partition_query(self) -> FirestorePartitionQueryObjBuilder<'a, D, T>
     where
         T: 'a,
     {
         FirestorePartitionQueryObjBuilder::new(self.db, self.params.with_all_descendants(true))
     }
+
+    pub async fn view(self) -> FirestoreResult<FirestoreQueryView<T>>
+    where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+        T: Clone + Sync + 'static,
+    {
+        FirestoreQueryView::new(self.db.clone(), self.params).await
+    }
+
+    pub async fn view_with_params(
+        self,
+        view_params: FirestoreQueryViewParams,
+    ) -> FirestoreResult<FirestoreQueryView<T>>
+    where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+        T: Clone + Sync + 'static,
+    {
+        FirestoreQueryView::with_params(self.db.clone(), self.params, view_params).await
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -303,6 +419,7 @@ where
     collection: String,
     parent: Option<String>,
     return_only_fields: Option<Vec<String>>,
+    read_time: Option<DateTime<Utc>>,
 }
 
 impl<'a, D> FirestoreSelectByIdBuilder<'a, D>
@@ -319,6 +436,7 @@ where
             collection,
             parent: None,
             return_only_fields,
+            read_time: None,
         }
     }
 
@@ -333,6 +451,15 @@ where
         }
     }
 
+    /// Reads the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            read_time: Some(read_time),
+            ..self
+        }
+    }
+
     #[inline]
     pub fn obj<T>(self) -> FirestoreSelectObjByIdBuilder<'a, D, T>
     where
@@ -345,12 +472,27 @@ where
             self.parent,
             self.return_only_fields,
         )
+        .opt_read_time(self.read_time)
     }
 
     pub async fn one<S>(self, document_id: S) -> FirestoreResult<Option<Document>>
     where
         S: AsRef<str> + Send,
     {
+        if let Some(read_time) = self.read_time {
+            let mut docs = self
+                .db
+                .batch_stream_get_docs_with_params(batch_get_params(
+                    self.collection,
+                    [document_id],
+                    self.parent,
+                    self.return_only_fields,
+                    read_time,
+                ))
+                .await?;
+            return Ok(docs.try_next().await?.and_then(|(_, doc)| doc));
+        }
+
         if let Some(parent) = self.parent {
             match self
                 .db
@@ -395,6 +537,12 @@ where
         S: AsRef<str> + Send,
         I: IntoIterator<Item = S> + Send,
     {
+        if self.read_time.is_some() {
+            return Ok(skip_batch_errors(
+                self.batch_with_errors(document_ids).await?,
+            ));
+        }
+
         if let Some(parent) = self.parent {
             self.db
                 .batch_stream_get_docs_at::<S, I>(
@@ -423,6 +571,19 @@ where
         S: AsRef<str> + Send,
         I: IntoIterator<Item = S> + Send,
     {
+        if let Some(read_time) = self.read_time {
+            return self
+                .db
+                .batch_stream_get_docs_with_params(batch_get_params(
+                    self.collection,
+                    document_ids,
+                    self.parent,
+                    self.return_only_fields,
+                    read_time,
+                ))
+                .await;
+        }
+
         if let Some(parent) = self.parent {
             self.db
                 .batch_stream_get_docs_at_with_errors::<S, I>(
@@ -478,12 +639,13 @@ where
     collection: String,
     parent: Option<String>,
     return_only_fields: Option<Vec<String>>,
+    read_time: Option<DateTime<Utc>>,
     _pd: PhantomData<T>,
 }
 
 impl<'a, D, T> FirestoreSelectObjByIdBuilder<'a, D, T>
 where
-    D: FirestoreGetByIdSupport,
+    D: FirestoreGetByIdSupport + Sync,
     T: Send,
     for<'de> T: Deserialize<'de>,
 {
@@ -498,14 +660,45 @@ where
             collection,
             parent,
             return_only_fields,
+            read_time: None,
             _pd: PhantomData::default(),
         }
     }
 
+    /// Reads the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        self.opt_read_time(Some(read_time))
+    }
+
+    #[inline]
+    fn opt_read_time(self, read_time: Option<DateTime<Utc>>) -> Self {
+        Self { read_time, ..self }
+    }
+
     pub async fn one<S>(self, document_id: S) -> FirestoreResult<Option<T>>
     where
         S: AsRef<str> + Send,
     {
+        if let Some(read_time) = self.read_time {
+            let mut docs = self
+                .db
+                .batch_stream_get_docs_with_params(batch_get_params(
+                    self.collection,
+                    [document_id],
+                    self.parent,
+                    self.return_only_fields,
+                    read_time,
+                ))
+                .await?;
+            return docs
+                .try_next()
+                .await?
+                .and_then(|(_, doc)| doc)
+                .map(|doc| FirestoreDb::deserialize_doc_to::<T>(&doc))
+                .transpose();
+        }
+
         if let Some(parent) = self.parent {
             match self
                 .db
@@ -551,6 +744,12 @@ where
         I: IntoIterator<Item = S> + Send,
         T: Send + 'a,
     {
+        if self.read_time.is_some() {
+            return Ok(skip_batch_errors(
+                self.batch_with_errors(document_ids).await?,
+            ));
+        }
+
         if let Some(parent) = self.parent {
             self.db
                 .batch_stream_get_objects_at::<T, S, I>(
@@ -580,6 +779,30 @@ where
         I: IntoIterator<Item = S> + Send,
         T: Send + 'a,
     {
+        if let Some(read_time) = self.read_time {
+            let doc_stream = self
+                .db
+                .batch_stream_get_docs_with_params(batch_get_params(
+                    self.collection,
+                    document_ids,
+                    self.parent,
+                    self.return_only_fields,
+                    read_time,
+                ))
+                .await?;
+
+            return Ok(doc_stream
+                .and_then(|(doc_id, maybe_doc)| {
+                    future::ready(
+                        maybe_doc
+                            .map(|doc| FirestoreDb::deserialize_doc_to::<T>(&doc))
+                            .transpose()
+                            .map(|obj| (doc_id, obj)),
+                    )
+                })
+                .boxed());
+        }
+
         if let Some(parent) = self.parent {
             self.db
                 .batch_stream_get_objects_at_with_errors::<T, S, I>(
@@ -601,6 +824,51 @@ where
     }
 }
 
+fn batch_get_params<S, I>(
+    collection: String,
+    document_ids: I,
+    parent: Option<String>,
+    return_only_fields: Option<Vec<String>>,
+    read_time: DateTime<Utc>,
+) -> FirestoreBatchGetParams
+where
+    S: AsRef<str>,
+    I: IntoIterator<Item = S>,
+{
+    FirestoreBatchGetParams::new(
+        collection,
+        document_ids
+            .into_iter()
+            .map(|document_id| document_id.as_ref().to_string())
+            .collect(),
+    )
+    .opt_parent(parent)
+    .opt_return_only_fields(return_only_fields)
+    .with_read_time(read_time)
+}
+
+fn skip_batch_errors<'a, T>(
+    stream: BoxStream<'a, FirestoreResult<(String, Option<T>)>>,
+) -> BoxStream<'a, (String, Option<T>)>
+where
+    T: Send + 'a,
+{
+    stream
+        .filter_map(|doc_res| {
+            future::ready(match doc_res {
+                Ok(doc_pair) => Some(doc_pair),
+                Err(err) => {
+                    tracing::error!(
+                        "[DB] Error occurred while consuming batch get as a stream: {}",
+                        err
+                    );
+                    None
+                }
+            })
+        })
+        .boxed()
+}
+
 #[derive(Clone, Debug)]
 pub struct FirestorePartitionQueryDocBuilder<'a, D>
 where
@@ -652,6 +920,15 @@ where
         }
     }
 
+    /// Reads the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: self.params.with_read_time(read_time),
+            ..self
+        }
+    }
+
     pub async fn stream_partitions_with_errors(
         self,
     ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(FirestorePartition, Document)>>> {
@@ -725,6 +1002,15 @@ where
         }
     }
 
+    /// Reads the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: self.params.with_read_time(read_time),
+            ..self
+        }
+    }
+
     pub async fn stream_partitions_with_errors(
         self,
     ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(FirestorePartition, T)>>> {
@@ -798,6 +1084,16 @@ where
     }
 }
 
+fn aggregated_params_at(
+    params: FirestoreAggregatedQueryParams,
+    read_time: DateTime<Utc>,
+) -> FirestoreAggregatedQueryParams {
+    FirestoreAggregatedQueryParams {
+        query_params: params.query_params.with_read_time(read_time),
+        ..params
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FirestoreAggregatedQueryDocBuilder<'a, D>
 where
@@ -816,6 +1112,15 @@ where
         Self { db, params }
     }
 
+    /// Aggregates the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: aggregated_params_at(self.params, read_time),
+            ..self
+        }
+    }
+
     #[inline]
     pub fn obj<T>(self) -> FirestoreAggregatedQueryObjBuilder<'a, D, T>
     where
@@ -869,6 +1174,15 @@ where
         }
     }
 
+    /// Aggregates the documents as they were at the specified time
+    #[inline]
+    pub fn read_time(self, read_time: DateTime<Utc>) -> Self {
+        Self {
+            params: aggregated_params_at(self.params, read_time),
+            ..self
+        }
+    }
+
     pub async fn query(self) -> FirestoreResult<Vec<T>> {
         self.db.aggregated_query_obj(self.params).await
     }
@@ -893,7 +1207,10 @@ where
 mod tests {
     use crate::fluent_api::tests::*;
     use crate::fluent_api::FirestoreExprBuilder;
-    use crate::{path, paths, FirestoreQueryCollection};
+    use crate::{
+        path, paths, FirestoreQueryCollection, FirestoreQueryCursor, FirestoreQueryDirection,
+        FirestoreQueryFilter, FirestoreQueryFilterCompositeOperator, FirestoreQueryOrder,
+    };
 
     #[test]
     fn select_query_builder_test_fields() {
@@ -923,4 +1240,101 @@ mod tests {
             FirestoreQueryCollection::Single("test".to_string())
         )
     }
+
+    #[test]
+    fn select_query_builder_for_any_filter() {
+        let select_filter = FirestoreExprBuilder::new(&mockdb::MockDatabase {})
+            .select()
+            .from("test")
+            .filter(|q| {
+                q.for_any([
+                    q.field(path!(TestStructure::some_id)).eq("test"),
+                    q.for_all([
+                        q.field(path!(TestStructure::some_num)).greater_than(10),
+                        q.field(path!(TestStructure::one_more_string)).eq("test"),
+                    ]),
+                ])
+            })
+            .params
+            .filter;
+
+        match select_filter {
+            Some(FirestoreQueryFilter::Composite(composite)) => {
+                assert_eq!(
+                    composite.operator,
+                    FirestoreQueryFilterCompositeOperator::Or
+                );
+                assert_eq!(composite.for_all_filters.len(), 2);
+                assert!(matches!(
+                    composite.for_all_filters.last(),
+                    Some(FirestoreQueryFilter::Composite(nested))
+                        if nested.operator == FirestoreQueryFilterCompositeOperator::And
+                ));
+            }
+            _ => panic!("Unexpected filter: {select_filter:?}"),
+        }
+    }
+
+    #[test]
+    fn select_query_builder_document_cursor() -> crate::FirestoreResult<()> {
+        let document = gcloud_sdk::google::firestore::v1::Document {
+            name: "projects/test/databases/(default)/documents/test/doc-1".to_string(),
+            fields: [(
+                "some_num".to_string(),
+                gcloud_sdk::google::firestore::v1::Value {
+                    value_type: Some(
+                        gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(10),
+                    ),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            create_time: None,
+            update_time: None,
+        };
+
+        let select_builder = FirestoreExprBuilder::new(&mockdb::MockDatabase {})
+            .select()
+            .from("test")
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Descending,
+            )])
+            .start_after_document(&document)?;
+
+        assert_eq!(
+            select_builder.params.order_by,
+            Some(vec![
+                FirestoreQueryOrder::new(
+                    "some_num".to_string(),
+                    FirestoreQueryDirection::Descending
+                ),
+                FirestoreQueryOrder::new(
+                    "__name__".to_string(),
+                    FirestoreQueryDirection::Descending
+                ),
+            ])
+        );
+        assert!(matches!(
+            select_builder.params.start_at,
+            Some(FirestoreQueryCursor::AfterValue(ref values)) if values.len() == 2
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn select_query_builder_read_time() {
+        let read_time = chrono::Utc::now() - chrono::Duration::hours(1);
+        let select_builder = FirestoreExprBuilder::new(&mockdb::MockDatabase {})
+            .select()
+            .from("test")
+            .read_time(read_time);
+        assert_eq!(select_builder.params.read_time, Some(read_time));
+
+        let aggregated_params = select_builder
+            .aggregate(|a| a.fields([a.field("count").count()]))
+            .params;
+        assert_eq!(aggregated_params.query_params.read_time, Some(read_time));
+    }
 }
@@ -1,6 +1,6 @@
 use crate::{
     FirestoreQueryFilter, FirestoreQueryFilterCompare, FirestoreQueryFilterComposite,
-    FirestoreQueryFilterUnary, FirestoreValue,
+    FirestoreQueryFilterCompositeOperator, FirestoreQueryFilterUnary, FirestoreValue,
 };
 
 #[derive(Clone, Debug)]
@@ -13,6 +13,34 @@ impl FirestoreQueryFilterBuilder {
 
     #[inline]
     pub fn for_all<I>(&self, filter_expressions: I) -> Option<FirestoreQueryFilter>
+    where
+        I: IntoIterator,
+        I::Item: FirestoreQueryFilterExpr,
+    {
+        self.build_composite(
+            filter_expressions,
+            FirestoreQueryFilterCompositeOperator::And,
+        )
+    }
+
+    #[inline]
+    pub fn for_any<I>(&self, filter_expressions: I) -> Option<FirestoreQueryFilter>
+    where
+        I: IntoIterator,
+        I::Item: FirestoreQueryFilterExpr,
+    {
+        self.build_composite(
+            filter_expressions,
+            FirestoreQueryFilterCompositeOperator::Or,
+        )
+    }
+
+    #[inline]
+    fn build_composite<I>(
+        &self,
+        filter_expressions: I,
+        operator: FirestoreQueryFilterCompositeOperator,
+    ) -> Option<FirestoreQueryFilter>
     where
         I: IntoIterator,
         I::Item: FirestoreQueryFilterExpr,
@@ -28,7 +56,7 @@ impl FirestoreQueryFilterBuilder {
             filters.pop()
         } else {
             Some(FirestoreQueryFilter::Composite(
-                FirestoreQueryFilterComposite::new(filters),
+                FirestoreQueryFilterComposite::new(filters).with_operator(operator),
             ))
         }
     }
@@ -1,7 +1,11 @@
+use crate::db::{TransformObjectOperation, UpdateObjectOperation};
 use crate::document_transform_builder::FirestoreTransformBuilder;
+use crate::select_filter_builder::FirestoreQueryFilterBuilder;
 use crate::{
-    FirestoreBatch, FirestoreBatchWriter, FirestoreFieldTransform, FirestoreResult,
-    FirestoreTransaction, FirestoreUpdateSupport, FirestoreWritePrecondition,
+    FirestoreBatch, FirestoreBatchWriter, FirestoreDb, FirestoreFieldTransform,
+    FirestoreQueryFilter, FirestoreQueryParams, FirestoreQueryUpdate, FirestoreQueryWriteResult,
+    FirestoreQueryWriteSupport, FirestoreResult, FirestoreTransaction, FirestoreUpdateSupport,
+    FirestoreWritePrecondition,
 };
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::{Deserialize, Serialize};
@@ -137,6 +141,22 @@ where
         )
     }
 
+    /// Updates all documents of the collection matching the filter
+    #[inline]
+    pub fn filter<FN>(self, filter: FN) -> FirestoreUpdateByQueryBuilder<'a, D>
+    where
+        D: FirestoreQueryWriteSupport,
+        FN: Fn(FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter>,
+    {
+        FirestoreUpdateByQueryBuilder::new(
+            self.db,
+            FirestoreQueryParams::new(self.collection_id.as_str().into()).opt_parent(self.parent),
+            self.update_only_fields,
+            self.transforms,
+        )
+        .filter(filter)
+    }
+
     #[inline]
     pub fn document_id<S>(self, document_id: S) -> FirestoreUpdateObjInitExecuteBuilder<'a, D>
     where
@@ -423,25 +443,34 @@ where
     where
         W: FirestoreBatchWriter,
     {
-        if let Some(parent) = self.parent {
-            batch.update_object_at(
-                parent.as_str(),
-                self.collection_id.as_str(),
-                self.document_id,
-                self.object,
-                self.update_only_fields,
-                self.precondition,
-                self.transforms,
-            )
-        } else {
-            batch.update_object(
-                self.collection_id.as_str(),
-                self.document_id,
-                self.object,
-                self.update_only_fields,
-                self.precondition,
-                self.transforms,
-            )
+        let operation = self.update_operation(batch.db.get_documents_path());
+        batch.add(operation)
+    }
+
+    /// Adds the update to the batch with a key to find its outcome in the response
+    #[inline]
+    pub fn add_to_batch_with_key<'t, W, K>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+        key: K,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+        K: AsRef<str>,
+    {
+        let operation = self.update_operation(batch.db.get_documents_path());
+        batch.add_with_key(operation, key)
+    }
+
+    fn update_operation(self, documents_path: &str) -> UpdateObjectOperation<'a, T, String> {
+        UpdateObjectOperation {
+            parent: self.parent.unwrap_or_else(|| documents_path.to_string()),
+            collection_id: self.collection_id,
+            document_id: self.document_id,
+            obj: self.object,
+            update_only: self.update_only_fields,
+            precondition: self.precondition,
+            update_transforms: self.transforms,
         }
     }
 }
@@ -513,21 +542,212 @@ where
     where
         W: FirestoreBatchWriter,
     {
-        if let Some(parent) = self.parent {
-            batch.transform_at(
-                parent.as_str(),
-                self.collection_id.as_str(),
-                self.document_id,
-                self.precondition,
-                self.transforms,
-            )
-        } else {
-            batch.transform(
-                self.collection_id.as_str(),
-                self.document_id,
-                self.precondition,
-                self.transforms,
+        let operation = self.transform_operation(batch.db.get_documents_path());
+        batch.add(operation)
+    }
+
+    /// Adds the transform to the batch with a key to find its outcome in the response
+    #[inline]
+    pub fn add_to_batch_with_key<'t, W, K>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+        key: K,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+        K: AsRef<str>,
+    {
+        let operation = self.transform_operation(batch.db.get_documents_path());
+        batch.add_with_key(operation, key)
+    }
+
+    fn transform_operation(self, documents_path: &str) -> TransformObjectOperation<String> {
+        TransformObjectOperation {
+            parent: self.parent.unwrap_or_else(|| documents_path.to_string()),
+            collection_id: self.collection_id,
+            document_id: self.document_id,
+            precondition: self.precondition,
+            transforms: self.transforms,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FirestoreUpdateByQueryBuilder<'a, D>
+where
+    D: FirestoreQueryWriteSupport,
+{
+    db: &'a D,
+    params: FirestoreQueryParams,
+    update_only_fields: Option<Vec<String>>,
+    transforms: Vec<FirestoreFieldTransform>,
+    update_time_precondition: bool,
+}
+
+impl<'a, D> FirestoreUpdateByQueryBuilder<'a, D>
+where
+    D: FirestoreQueryWriteSupport,
+{
+    #[inline]
+    pub(crate) fn new(
+        db: &'a D,
+        params: FirestoreQueryParams,
+        update_only_fields: Option<Vec<String>>,
+        transforms: Vec<FirestoreFieldTransform>,
+    ) -> Self {
+        Self {
+            db,
+            params,
+            update_only_fields,
+            transforms,
+            update_time_precondition: false,
+        }
+    }
+
+    #[inline]
+    pub fn parent<S>(self, parent: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self {
+            params: self.params.with_parent(parent.as_ref().to_string()),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn filter<FN>(self, filter: FN) -> Self
+    where
+        FN: Fn(FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter>,
+    {
+        let filter_builder = FirestoreQueryFilterBuilder::new();
+
+        Self {
+            params: self.params.opt_filter(filter(filter_builder)),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn transforms<FN>(self, doc_transform: FN) -> Self
+    where
+        FN: Fn(FirestoreTransformBuilder) -> Vec<FirestoreFieldTransform>,
+    {
+        Self {
+            transforms: doc_transform(FirestoreTransformBuilder::new()),
+            ..self
+        }
+    }
+
+    /// Skips the documents changed after they were found by the query
+    #[inline]
+    pub fn update_time_precondition(self) -> Self {
+        Self {
+            update_time_precondition: true,
+            ..self
+        }
+    }
+
+    /// Sets the fields of the object on every matching document
+    #[inline]
+    pub fn object<T>(self, object: &'a T) -> FirestoreUpdateByQueryObjBuilder<'a, D, T>
+    where
+        T: Serialize + Sync + Send,
+    {
+        FirestoreUpdateByQueryObjBuilder::new(
+            self.db,
+            self.params,
+            self.update_only_fields,
+            object,
+            self.transforms,
+            self.update_time_precondition,
+        )
+    }
+
+    /// Applies only the transforms to every matching document
+    pub async fn execute(self) -> FirestoreResult<FirestoreQueryWriteResult>
+    where
+        D: Sync,
+    {
+        self.db
+            .update_by_query(
+                self.params,
+                FirestoreQueryUpdate::new().with_transforms(self.transforms),
+                self.update_time_precondition,
             )
+            .await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FirestoreUpdateByQueryObjBuilder<'a, D, T>
+where
+    D: FirestoreQueryWriteSupport,
+    T: Serialize + Sync + Send,
+{
+    db: &'a D,
+    params: FirestoreQueryParams,
+    update_only_fields: Option<Vec<String>>,
+    object: &'a T,
+    transforms: Vec<FirestoreFieldTransform>,
+    update_time_precondition: bool,
+}
+
+impl<'a, D, T> FirestoreUpdateByQueryObjBuilder<'a, D, T>
+where
+    D: FirestoreQueryWriteSupport,
+    T: Serialize + Sync + Send,
+{
+    #[inline]
+    pub(crate) fn new(
+        db: &'a D,
+        params: FirestoreQueryParams,
+        update_only_fields: Option<Vec<String>>,
+        object: &'a T,
+        transforms: Vec<FirestoreFieldTransform>,
+        update_time_precondition: bool,
+    ) -> Self {
+        Self {
+            db,
+            params,
+            update_only_fields,
+            object,
+            transforms,
+            update_time_precondition,
         }
     }
+
+    #[inline]
+    pub fn transforms<FN>(self, transforms_builder: FN) -> Self
+    where
+        FN: Fn(FirestoreTransformBuilder) -> Vec<FirestoreFieldTransform>,
+    {
+        Self {
+            transforms: transforms_builder(FirestoreTransformBuilder::new()),
+            ..self
+        }
+    }
+
+    /// Skips the documents changed after they were found by the query
+    #[inline]
+    pub fn update_time_precondition(self) -> Self {
+        Self {
+            update_time_precondition: true,
+            ..self
+        }
+    }
+
+    pub async fn execute(self) -> FirestoreResult<FirestoreQueryWriteResult>
+    where
+        D: Sync,
+    {
+        let update = FirestoreQueryUpdate::new()
+            .with_document(FirestoreDb::serialize_to_doc("", self.object)?)
+            .opt_update_only(self.update_only_fields)
+            .with_transforms(self.transforms);
+
+        self.db
+            .update_by_query(self.params, update, self.update_time_precondition)
+            .await
+    }
 }
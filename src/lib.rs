@@ -147,4 +147,9 @@ pub type FirestoreDocument = gcloud_sdk::google::firestore::v1::Document;
 mod fluent_api;
 pub use fluent_api::*;
 
+#[cfg(feature = "memory-db")]
+mod memory_db;
+#[cfg(feature = "memory-db")]
+pub use memory_db::*;
+
 pub extern crate struct_path;
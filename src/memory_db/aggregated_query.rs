@@ -0,0 +1,185 @@
+use crate::db::query_ordering::reject_limit_to_last;
+use crate::memory_db::values::{get_document_field, integer_value};
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{
+    FirestoreAggregatedQueryParams, FirestoreAggregatedQuerySupport, FirestoreAggregationOperator,
+    FirestoreDb, FirestoreResult,
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use futures::{future, StreamExt};
+use gcloud_sdk::google::firestore::v1::*;
+use serde::Deserialize;
+use tracing::error;
+
+impl FirestoreMemoryDb {
+    fn run_aggregated_query_doc(
+        &self,
+        params: &FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<Document> {
+        params.query_params.validate()?;
+        reject_limit_to_last(&params.query_params, "aggregations")?;
+        Self::check_read_time(params.query_params.read_time)?;
+        let docs = self.run_query_doc(&params.query_params);
+
+        Ok(Document {
+            name: "".to_string(),
+            fields: params
+                .aggregations
+                .iter()
+                .filter_map(|aggregation| {
+                    aggregation.operator.as_ref().map(|operator| {
+                        let value = match operator {
+                            FirestoreAggregationOperator::Count(count) => integer_value(
+                                count
+                                    .up_to
+                                    .map(|up_to| docs.len().min(up_to))
+                                    .unwrap_or(docs.len()) as i64,
+                            ),
+                            FirestoreAggregationOperator::Sum(sum) => {
+                                Self::sum_values(&docs, &sum.field_name)
+                            }
+                            FirestoreAggregationOperator::Avg(avg) => {
+                                Self::avg_values(&docs, &avg.field_name)
+                            }
+                        };
+                        (aggregation.alias.clone(), value)
+                    })
+                })
+                .collect(),
+            create_time: None,
+            update_time: None,
+        })
+    }
+
+    // Non-numeric values are ignored, the same way Firestore does it
+    fn numeric_values(docs: &[Document], field_name: &str) -> Vec<value::ValueType> {
+        docs.iter()
+            .filter_map(|doc| get_document_field(doc, field_name))
+            .filter_map(|value| match value.value_type {
+                Some(value_type @ value::ValueType::IntegerValue(_))
+                | Some(value_type @ value::ValueType::DoubleValue(_)) => Some(value_type),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The sum stays an integer until it overflows or meets a double
+    fn sum_values(docs: &[Document], field_name: &str) -> Value {
+        let numeric_values = Self::numeric_values(docs, field_name);
+        let int_sum = numeric_values
+            .iter()
+            .try_fold(0i64, |acc, value_type| match value_type {
+                value::ValueType::IntegerValue(v) => acc.checked_add(*v),
+                _ => None,
+            });
+
+        match int_sum {
+            Some(int_sum) => integer_value(int_sum),
+            None => Value {
+                value_type: Some(value::ValueType::DoubleValue(
+                    numeric_values.iter().map(Self::numeric_as_f64).sum(),
+                )),
+            },
+        }
+    }
+
+    fn avg_values(docs: &[Document], field_name: &str) -> Value {
+        let numeric_values = Self::numeric_values(docs, field_name);
+        if numeric_values.is_empty() {
+            Value {
+                value_type: Some(value::ValueType::NullValue(0)),
+            }
+        } else {
+            Value {
+                value_type: Some(value::ValueType::DoubleValue(
+                    numeric_values.iter().map(Self::numeric_as_f64).sum::<f64>()
+                        / numeric_values.len() as f64,
+                )),
+            }
+        }
+    }
+
+    fn numeric_as_f64(value_type: &value::ValueType) -> f64 {
+        match value_type {
+            value::ValueType::IntegerValue(v) => *v as f64,
+            value::ValueType::DoubleValue(v) => *v,
+            _ => 0.0,
+        }
+    }
+}
+
+#[async_trait]
+impl FirestoreAggregatedQuerySupport for FirestoreMemoryDb {
+    async fn aggregated_query_doc(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<Vec<Document>> {
+        Ok(vec![self.run_aggregated_query_doc(&params)?])
+    }
+
+    async fn stream_aggregated_query_doc<'b>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, Document>> {
+        Ok(futures::stream::iter(vec![self.run_aggregated_query_doc(&params)?]).boxed())
+    }
+
+    async fn stream_aggregated_query_doc_with_errors<'b>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        Ok(futures::stream::iter(vec![self.run_aggregated_query_doc(&params)]).boxed())
+    }
+
+    async fn aggregated_query_obj<T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_vec = self.aggregated_query_doc(params).await?;
+        doc_vec
+            .iter()
+            .map(|doc| FirestoreDb::deserialize_doc_to(doc))
+            .collect()
+    }
+
+    async fn stream_aggregated_query_obj<'b, T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_stream = self.stream_aggregated_query_doc(params).await?;
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        "[MemoryDB] Error occurred while consuming query document as a stream: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_aggregated_query_obj_with_errors<'b, T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_aggregated_query_doc_with_errors(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc))
+        })))
+    }
+}
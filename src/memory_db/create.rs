@@ -0,0 +1,117 @@
+use crate::db::safe_document_path;
+use crate::memory_db::values::project_document;
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{FirestoreCreateSupport, FirestoreDb, FirestoreResult, FirestoreWritePrecondition};
+use async_trait::async_trait;
+use gcloud_sdk::google::firestore::v1::*;
+use serde::{Deserialize, Serialize};
+
+#[async_trait]
+impl FirestoreCreateSupport for FirestoreMemoryDb {
+    async fn create_doc<S>(
+        &self,
+        collection_id: &str,
+        document_id: Option<S>,
+        input_doc: Document,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.create_doc_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            input_doc,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn create_doc_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        input_doc: Document,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        let document_path = match document_id {
+            Some(document_id) => safe_document_path(parent, collection_id, document_id)?,
+            None => safe_document_path(parent, collection_id, FirestoreDb::generate_document_id())?,
+        };
+
+        let write = Write {
+            update_mask: None,
+            update_transforms: vec![],
+            current_document: Some(FirestoreWritePrecondition::Exists(false).try_into()?),
+            operation: Some(write::Operation::Update(Document {
+                name: document_path,
+                fields: input_doc.fields,
+                create_time: None,
+                update_time: None,
+            })),
+        };
+
+        let created_doc = self
+            .commit_writes(vec![write])?
+            .pop()
+            .and_then(|outcome| outcome.document)
+            .unwrap_or_default();
+
+        Ok(project_document(created_doc, return_only_fields.as_ref()))
+    }
+
+    async fn create_obj<I, O, S>(
+        &self,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.create_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            obj,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn create_obj_at<I, O, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let input_doc = FirestoreDb::serialize_to_doc("", obj)?;
+
+        let doc = self
+            .create_doc_at(
+                parent,
+                collection_id,
+                document_id,
+                input_doc,
+                return_only_fields,
+            )
+            .await?;
+
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+}
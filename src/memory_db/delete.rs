@@ -0,0 +1,99 @@
+use crate::db::{recursive_delete_path, safe_document_path};
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{
+    FirestoreDeleteSupport, FirestoreRecursiveDeleteProgress, FirestoreRecursiveDeleteResult,
+    FirestoreRecursiveDeleteSupport, FirestoreResult, FirestoreWritePrecondition,
+};
+use async_trait::async_trait;
+use gcloud_sdk::google::firestore::v1::*;
+
+#[async_trait]
+impl FirestoreDeleteSupport for FirestoreMemoryDb {
+    async fn delete_by_id<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<()>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.delete_by_id_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            precondition,
+        )
+        .await
+    }
+
+    async fn delete_by_id_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<()>
+    where
+        S: AsRef<str> + Send,
+    {
+        let write = Write {
+            update_mask: None,
+            update_transforms: vec![],
+            current_document: precondition.map(|cond| cond.try_into()).transpose()?,
+            operation: Some(write::Operation::Delete(safe_document_path(
+                parent,
+                collection_id,
+                document_id.as_ref(),
+            )?)),
+        };
+
+        self.commit_writes(vec![write])?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FirestoreRecursiveDeleteSupport for FirestoreMemoryDb {
+    async fn recursive_delete<S>(&self, path: S) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.recursive_delete_with_progress(path, |_| {}).await
+    }
+
+    async fn recursive_delete_with_progress<S, F>(
+        &self,
+        path: S,
+        on_progress: F,
+    ) -> FirestoreResult<FirestoreRecursiveDeleteResult>
+    where
+        S: AsRef<str> + Send,
+        F: Fn(&FirestoreRecursiveDeleteProgress) + Send + Sync,
+    {
+        let (full_path, _) = recursive_delete_path(self.get_documents_path(), path.as_ref())?;
+        let descendants_prefix = format!("{full_path}/");
+
+        let writes: Vec<Write> = self
+            .read_state()
+            .documents
+            .keys()
+            .filter(|name| **name == full_path || name.starts_with(&descendants_prefix))
+            .map(|name| Write {
+                update_mask: None,
+                update_transforms: vec![],
+                current_document: None,
+                operation: Some(write::Operation::Delete(name.clone())),
+            })
+            .collect();
+        let deleted_count = writes.len();
+
+        if !writes.is_empty() {
+            self.commit_writes(writes)?;
+        }
+
+        on_progress(&FirestoreRecursiveDeleteProgress::new(deleted_count, 0));
+        Ok(FirestoreRecursiveDeleteResult::new(deleted_count, vec![]))
+    }
+}
@@ -0,0 +1,395 @@
+use crate::db::safe_document_path;
+use crate::errors::*;
+use crate::memory_db::values::project_document;
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{FirestoreBatchGetParams, FirestoreDb, FirestoreGetByIdSupport, FirestoreResult};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{future, StreamExt, TryStreamExt};
+use gcloud_sdk::google::firestore::v1::*;
+use serde::Deserialize;
+use tracing::*;
+
+impl FirestoreMemoryDb {
+    pub(crate) fn get_doc_by_path(
+        &self,
+        document_path: &str,
+        return_only_fields: Option<&Vec<String>>,
+    ) -> Option<Document> {
+        self.read_state()
+            .documents
+            .get(document_path)
+            .cloned()
+            .map(|document| project_document(document, return_only_fields))
+    }
+}
+
+#[async_trait]
+impl FirestoreGetByIdSupport for FirestoreMemoryDb {
+    async fn get_doc<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.get_doc_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_doc_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
+        self.get_doc_by_path(&document_path, return_only_fields.as_ref())
+            .ok_or_else(|| FirestoreMemoryDb::document_not_found_error(&document_path))
+    }
+
+    async fn get_obj<T, S>(&self, collection_id: &str, document_id: S) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+        )
+        .await
+    }
+
+    async fn get_obj_return_fields<T, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_return_fields(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_obj_at<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_return_fields(parent, collection_id, document_id, None)
+            .await
+    }
+
+    async fn get_obj_at_return_fields<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let doc: Document = self
+            .get_doc_at(parent, collection_id, document_id, return_only_fields)
+            .await?;
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+
+    async fn get_obj_if_exists<T, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_if_exists(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_obj_at_if_exists<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        match self
+            .get_obj_at_return_fields::<T, S>(
+                parent,
+                collection_id,
+                document_id,
+                return_only_fields,
+            )
+            .await
+        {
+            Ok(obj) => Ok(Some(obj)),
+            Err(err) => match err {
+                FirestoreError::DataNotFoundError(_) => Ok(None),
+                _ => Err(err),
+            },
+        }
+    }
+
+    async fn batch_stream_get_docs<S, I>(
+        &self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<(String, Option<Document>)>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_docs_at(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_docs_with_errors<S, I>(
+        &self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_docs_at_with_errors(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_docs_at<S, I>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<(String, Option<Document>)>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at_with_errors(
+                parent,
+                collection_id,
+                document_ids,
+                return_only_fields,
+            )
+            .await?;
+
+        Ok(Box::pin(doc_stream.filter_map(|doc_res| {
+            future::ready(match doc_res {
+                Ok(doc_pair) => Some(doc_pair),
+                Err(err) => {
+                    error!(
+                        "[MemoryDB] Error occurred while consuming batch get as a stream: {}",
+                        err
+                    );
+                    None
+                }
+            })
+        })))
+    }
+
+    async fn batch_stream_get_docs_at_with_errors<S, I>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let results: Vec<FirestoreResult<(String, Option<Document>)>> = document_ids
+            .into_iter()
+            .map(|document_id| {
+                let document_path =
+                    safe_document_path(parent, collection_id, document_id.as_ref())?;
+                Ok((
+                    document_id.as_ref().to_string(),
+                    self.get_doc_by_path(&document_path, return_only_fields.as_ref()),
+                ))
+            })
+            .collect::<FirestoreResult<Vec<_>>>()?
+            .into_iter()
+            .map(Ok)
+            .collect();
+
+        Ok(futures::stream::iter(results).boxed())
+    }
+
+    async fn batch_stream_get_docs_with_params(
+        &self,
+        params: FirestoreBatchGetParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>> {
+        Self::check_read_time(params.read_time)?;
+        self.batch_stream_get_docs_at_with_errors(
+            params
+                .parent
+                .as_deref()
+                .unwrap_or_else(|| self.get_documents_path().as_str()),
+            params.collection_id.as_str(),
+            params.document_ids,
+            params.return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_objects<'a, T, S, I>(
+        &'a self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, (String, Option<T>)>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_objects_at(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_objects_with_errors<'a, T, S, I>(
+        &'a self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(String, Option<T>)>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_objects_at_with_errors(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_objects_at<'a, T, S, I>(
+        &'a self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, (String, Option<T>)>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at(parent, collection_id, document_ids, return_only_fields)
+            .await?;
+
+        Ok(Box::pin(doc_stream.filter_map(
+            |(doc_id, maybe_doc)| async move {
+                match maybe_doc {
+                    Some(doc) => match FirestoreDb::deserialize_doc_to(&doc) {
+                        Ok(obj) => Some((doc_id, Some(obj))),
+                        Err(err) => {
+                            error!(
+                                "[MemoryDB] Error occurred while consuming batch documents as a stream: {}",
+                                err
+                            );
+                            None
+                        }
+                    },
+                    None => Some((doc_id, None)),
+                }
+            },
+        )))
+    }
+
+    async fn batch_stream_get_objects_at_with_errors<'a, T, S, I>(
+        &'a self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(String, Option<T>)>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at_with_errors(
+                parent,
+                collection_id,
+                document_ids,
+                return_only_fields,
+            )
+            .await?;
+
+        Ok(Box::pin(doc_stream.and_then(|(doc_id, maybe_doc)| {
+            future::ready({
+                maybe_doc
+                    .map(|doc| FirestoreDb::deserialize_doc_to::<T>(&doc))
+                    .transpose()
+                    .map(|obj| (doc_id, obj))
+            })
+        })))
+    }
+}
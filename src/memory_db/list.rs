@@ -0,0 +1,217 @@
+use crate::memory_db::query_eval::document_in_collection;
+use crate::memory_db::values::{compare_values, get_document_field, project_document};
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{
+    FirestoreDb, FirestoreListCollectionIdsParams, FirestoreListCollectionIdsResult,
+    FirestoreListDocParams, FirestoreListDocResult, FirestoreListingSupport,
+    FirestoreQueryDirection, FirestoreResult,
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use gcloud_sdk::google::firestore::v1::*;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use tracing::*;
+
+impl FirestoreMemoryDb {
+    fn list_all_docs(&self, params: &FirestoreListDocParams) -> FirestoreResult<Vec<Document>> {
+        Self::check_read_time(params.read_time)?;
+        let parent = params
+            .parent
+            .as_deref()
+            .unwrap_or_else(|| self.get_documents_path().as_str());
+        let collection_ids = vec![params.collection_id.clone()];
+
+        let mut docs: Vec<Document> = self
+            .read_state()
+            .documents
+            .values()
+            .filter(|doc| document_in_collection(&doc.name, parent, &collection_ids, false))
+            .cloned()
+            .collect();
+
+        if let Some(order_by) = &params.order_by {
+            // Listing doesn't skip documents with missing fields, they are ordered first
+            docs.sort_by(|left, right| {
+                order_by
+                    .iter()
+                    .map(|order| {
+                        let ordering = match (
+                            get_document_field(left, &order.field_name),
+                            get_document_field(right, &order.field_name),
+                        ) {
+                            (Some(l), Some(r)) => compare_values(&l, &r),
+                            (l, r) => l.is_some().cmp(&r.is_some()),
+                        };
+                        match order.direction {
+                            FirestoreQueryDirection::Ascending => ordering,
+                            FirestoreQueryDirection::Descending => ordering.reverse(),
+                        }
+                    })
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+
+        Ok(docs
+            .into_iter()
+            .map(|doc| project_document(doc, params.return_only_fields.as_ref()))
+            .collect())
+    }
+
+    fn list_all_collection_ids(
+        &self,
+        params: &FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<Vec<String>> {
+        Self::check_read_time(params.read_time)?;
+        let parent = format!(
+            "{}/",
+            params
+                .parent
+                .as_deref()
+                .unwrap_or_else(|| self.get_documents_path().as_str())
+        );
+
+        Ok(self
+            .read_state()
+            .documents
+            .keys()
+            .filter_map(|name| {
+                name.strip_prefix(parent.as_str())
+                    .and_then(|relative_path| relative_path.split('/').next())
+                    .map(|collection_id| collection_id.to_string())
+            })
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn page_offset(page_token: Option<&String>) -> FirestoreResult<usize> {
+        page_token
+            .map(|token| {
+                token.parse::<usize>().map_err(|_| {
+                    FirestoreMemoryDb::invalid_parameters_error(
+                        "page_token",
+                        format!("Invalid page token: {token}"),
+                    )
+                })
+            })
+            .transpose()
+            .map(|offset| offset.unwrap_or(0))
+    }
+
+    fn next_page_token(offset: usize, page_size: usize, total: usize) -> Option<String> {
+        if offset + page_size < total {
+            Some((offset + page_size).to_string())
+        } else {
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl FirestoreListingSupport for FirestoreMemoryDb {
+    async fn list_doc(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<FirestoreListDocResult> {
+        let offset = Self::page_offset(params.page_token.as_ref())?;
+        let docs = self.list_all_docs(&params)?;
+        let page_token = Self::next_page_token(offset, params.page_size, docs.len());
+
+        Ok(FirestoreListDocResult::new(
+            docs.into_iter()
+                .skip(offset)
+                .take(params.page_size)
+                .collect(),
+        )
+        .opt_page_token(page_token))
+    }
+
+    async fn stream_list_doc(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<Document>> {
+        Ok(futures::stream::iter(self.list_all_docs(&params)?).boxed())
+    }
+
+    async fn stream_list_doc_with_errors(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<Document>>> {
+        Ok(futures::stream::iter(self.list_all_docs(&params)?.into_iter().map(Ok)).boxed())
+    }
+
+    async fn stream_list_obj<T>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_stream = self.stream_list_doc(params).await?;
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        "[MemoryDB] Error occurred while consuming list document as a stream: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_list_obj_with_errors<T>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_stream = self.stream_list_doc_with_errors(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc| async move {
+            FirestoreDb::deserialize_doc_to::<T>(&doc)
+        })))
+    }
+
+    async fn list_collection_ids(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<FirestoreListCollectionIdsResult> {
+        let offset = Self::page_offset(params.page_token.as_ref())?;
+        let collection_ids = self.list_all_collection_ids(&params)?;
+        let page_token = Self::next_page_token(offset, params.page_size, collection_ids.len());
+
+        Ok(FirestoreListCollectionIdsResult::new(
+            collection_ids
+                .into_iter()
+                .skip(offset)
+                .take(params.page_size)
+                .collect(),
+        )
+        .opt_page_token(page_token))
+    }
+
+    async fn stream_list_collection_ids_with_errors(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<String>>> {
+        Ok(
+            futures::stream::iter(self.list_all_collection_ids(&params)?.into_iter().map(Ok))
+                .boxed(),
+        )
+    }
+
+    async fn stream_list_collection_ids(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<BoxStream<String>> {
+        Ok(futures::stream::iter(self.list_all_collection_ids(&params)?).boxed())
+    }
+}
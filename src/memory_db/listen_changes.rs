@@ -0,0 +1,372 @@
+use crate::db::safe_document_path;
+use crate::memory_db::query_eval::query_matches;
+use crate::memory_db::{FirestoreMemoryChangeSet, FirestoreMemoryDb, FirestoreMemoryDbState};
+use crate::timestamp_utils::to_timestamp;
+use crate::{
+    FirestoreListenSupport, FirestoreListener, FirestoreListenerParams,
+    FirestoreListenerTargetChange, FirestoreListenerTargetParams, FirestoreQueryParams,
+    FirestoreResult, FirestoreResumeStateStorage, FirestoreTargetType,
+};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::*;
+use rvstruct::ValueStruct;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+enum FirestoreMemoryListenEvent {
+    TargetChange(FirestoreListenerTargetChange),
+    Changes(Arc<FirestoreMemoryChangeSet>),
+}
+
+enum FirestoreMemoryListenTargetType {
+    Query(Box<FirestoreQueryParams>),
+    Documents(HashSet<String>),
+}
+
+struct FirestoreMemoryListenTarget {
+    target_id: i32,
+    target_type: FirestoreMemoryListenTargetType,
+    once: bool,
+}
+
+impl FirestoreMemoryListenTarget {
+    fn matches(&self, default_parent: &str, document: &Document) -> bool {
+        match &self.target_type {
+            FirestoreMemoryListenTargetType::Query(query_params) => {
+                query_matches(query_params, default_parent, document)
+            }
+            FirestoreMemoryListenTargetType::Documents(document_names) => {
+                document_names.contains(&document.name)
+            }
+        }
+    }
+}
+
+fn target_change_response(
+    target_change_type: target_change::TargetChangeType,
+    target_ids: Vec<i32>,
+    version: u64,
+    read_time: Option<DateTime<Utc>>,
+) -> ListenResponse {
+    ListenResponse {
+        response_type: Some(listen_response::ResponseType::TargetChange(TargetChange {
+            target_change_type: target_change_type.into(),
+            target_ids,
+            cause: None,
+            resume_token: version.to_be_bytes().to_vec(),
+            read_time: read_time.map(to_timestamp),
+        })),
+    }
+}
+
+fn remove_target_response(
+    target_id: i32,
+    cause: Option<gcloud_sdk::google::rpc::Status>,
+) -> ListenResponse {
+    ListenResponse {
+        response_type: Some(listen_response::ResponseType::TargetChange(TargetChange {
+            target_change_type: target_change::TargetChangeType::Remove.into(),
+            target_ids: vec![target_id],
+            cause,
+            resume_token: vec![],
+            read_time: None,
+        })),
+    }
+}
+
+impl FirestoreMemoryDb {
+    pub async fn create_listener<S>(
+        &self,
+        storage: S,
+    ) -> FirestoreResult<FirestoreListener<FirestoreMemoryDb, S>>
+    where
+        S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+    {
+        self.create_listener_with_params(storage, FirestoreListenerParams::new())
+            .await
+    }
+
+    pub async fn create_listener_with_params<S>(
+        &self,
+        storage: S,
+        params: FirestoreListenerParams,
+    ) -> FirestoreResult<FirestoreListener<FirestoreMemoryDb, S>>
+    where
+        S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+    {
+        FirestoreListener::new(self.clone(), storage, params).await
+    }
+
+    fn create_listen_target(
+        &self,
+        target_params: FirestoreListenerTargetParams,
+    ) -> FirestoreResult<FirestoreMemoryListenTarget> {
+        target_params.validate()?;
+        Ok(FirestoreMemoryListenTarget {
+            target_id: *target_params.target.value(),
+            target_type: match target_params.target_type {
+                FirestoreTargetType::Query(query_params) => {
+                    FirestoreMemoryListenTargetType::Query(Box::new(query_params))
+                }
+                FirestoreTargetType::Documents(collection_documents) => {
+                    FirestoreMemoryListenTargetType::Documents(
+                        collection_documents
+                            .documents
+                            .iter()
+                            .map(|doc_id| {
+                                safe_document_path(
+                                    collection_documents
+                                        .parent
+                                        .as_deref()
+                                        .unwrap_or_else(|| self.get_documents_path()),
+                                    collection_documents.collection.as_str(),
+                                    doc_id,
+                                )
+                            })
+                            .collect::<FirestoreResult<HashSet<String>>>()?,
+                    )
+                }
+            },
+            once: target_params.add_target_once.unwrap_or(false),
+        })
+    }
+
+    fn target_snapshot_responses(
+        &self,
+        state: &FirestoreMemoryDbState,
+        target: &FirestoreMemoryListenTarget,
+    ) -> Vec<ListenResponse> {
+        let mut responses = vec![target_change_response(
+            target_change::TargetChangeType::Add,
+            vec![target.target_id],
+            state.version,
+            None,
+        )];
+
+        responses.extend(
+            state
+                .documents
+                .values()
+                .filter(|document| target.matches(self.get_documents_path(), document))
+                .map(|document| ListenResponse {
+                    response_type: Some(listen_response::ResponseType::DocumentChange(
+                        DocumentChange {
+                            document: Some(document.clone()),
+                            target_ids: vec![target.target_id],
+                            removed_target_ids: vec![],
+                        },
+                    )),
+                }),
+        );
+
+        responses.push(target_change_response(
+            target_change::TargetChangeType::Current,
+            vec![target.target_id],
+            state.version,
+            state.last_commit_time,
+        ));
+
+        if target.once {
+            responses.push(target_change_response(
+                target_change::TargetChangeType::Remove,
+                vec![target.target_id],
+                state.version,
+                None,
+            ));
+        }
+
+        responses
+    }
+
+    fn apply_target_change(
+        &self,
+        live_targets: &mut Vec<FirestoreMemoryListenTarget>,
+        target_change: FirestoreListenerTargetChange,
+    ) -> Vec<ListenResponse> {
+        match target_change {
+            FirestoreListenerTargetChange::Add(target_params) => {
+                let target_id = *target_params.target.value();
+                // Firestore doesn't allow adding a target ID that is already listened
+                if live_targets
+                    .iter()
+                    .any(|live_target| live_target.target_id == target_id)
+                {
+                    live_targets.retain(|live_target| live_target.target_id != target_id);
+                    return vec![remove_target_response(
+                        target_id,
+                        Some(gcloud_sdk::google::rpc::Status {
+                            code: tonic::Code::AlreadyExists as i32,
+                            message: format!("Target ID {target_id} already exists"),
+                            details: vec![],
+                        }),
+                    )];
+                }
+                match self.create_listen_target(*target_params) {
+                    Ok(target) => {
+                        let responses = self.target_snapshot_responses(&self.read_state(), &target);
+                        if !target.once {
+                            live_targets.push(target);
+                        }
+                        responses
+                    }
+                    // Firestore removes invalid targets providing the cause
+                    Err(err) => vec![remove_target_response(
+                        target_id,
+                        Some(gcloud_sdk::google::rpc::Status {
+                            code: tonic::Code::InvalidArgument as i32,
+                            message: err.to_string(),
+                            details: vec![],
+                        }),
+                    )],
+                }
+            }
+            FirestoreListenerTargetChange::Remove(target) => {
+                live_targets.retain(|live_target| live_target.target_id != *target.value());
+                vec![remove_target_response(*target.value(), None)]
+            }
+        }
+    }
+
+    fn change_set_to_responses(
+        default_parent: &str,
+        targets: &[FirestoreMemoryListenTarget],
+        change_set: &FirestoreMemoryChangeSet,
+    ) -> Vec<ListenResponse> {
+        let read_time = Some(to_timestamp(change_set.commit_time));
+        let mut responses = Vec::new();
+
+        for change in &change_set.changes {
+            let (target_ids, removed_target_ids): (Vec<i32>, Vec<i32>) = targets.iter().fold(
+                (Vec::new(), Vec::new()),
+                |(mut target_ids, mut removed_target_ids), target| {
+                    let was_matched = change
+                        .old_document
+                        .as_ref()
+                        .map(|doc| target.matches(default_parent, doc))
+                        .unwrap_or(false);
+                    let is_matched = change
+                        .new_document
+                        .as_ref()
+                        .map(|doc| target.matches(default_parent, doc))
+                        .unwrap_or(false);
+                    if is_matched {
+                        target_ids.push(target.target_id);
+                    } else if was_matched {
+                        removed_target_ids.push(target.target_id);
+                    }
+                    (target_ids, removed_target_ids)
+                },
+            );
+
+            if !target_ids.is_empty() {
+                responses.push(ListenResponse {
+                    response_type: Some(listen_response::ResponseType::DocumentChange(
+                        DocumentChange {
+                            document: change.new_document.clone(),
+                            target_ids,
+                            removed_target_ids: removed_target_ids.clone(),
+                        },
+                    )),
+                });
+            } else if !removed_target_ids.is_empty() {
+                responses.push(ListenResponse {
+                    response_type: Some(if change.new_document.is_none() {
+                        listen_response::ResponseType::DocumentDelete(DocumentDelete {
+                            document: change.name.clone(),
+                            removed_target_ids,
+                            read_time: read_time.clone(),
+                        })
+                    } else {
+                        listen_response::ResponseType::DocumentRemove(DocumentRemove {
+                            document: change.name.clone(),
+                            removed_target_ids,
+                            read_time: read_time.clone(),
+                        })
+                    }),
+                });
+            }
+        }
+
+        if !targets.is_empty() {
+            responses.push(target_change_response(
+                target_change::TargetChangeType::NoChange,
+                targets.iter().map(|target| target.target_id).collect(),
+                change_set.version,
+                Some(change_set.commit_time),
+            ));
+        }
+
+        responses
+    }
+}
+
+#[async_trait]
+impl FirestoreListenSupport for FirestoreMemoryDb {
+    async fn listen_doc_changes<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        let (_, target_changes) = tokio::sync::mpsc::unbounded_channel();
+        self.listen_doc_changes_with_target_changes(targets, target_changes)
+            .await
+    }
+
+    // Resuming isn't incremental: a new stream always starts with the current snapshot of the targets.
+    // Limits, offsets and cursors of query targets are ignored for the changes.
+    async fn listen_doc_changes_with_target_changes<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        target_changes: UnboundedReceiver<FirestoreListenerTargetChange>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        let targets = targets
+            .into_iter()
+            .map(|target_params| self.create_listen_target(target_params))
+            .collect::<FirestoreResult<Vec<FirestoreMemoryListenTarget>>>()?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Arc<FirestoreMemoryChangeSet>>();
+
+        let initial_responses: Vec<ListenResponse> = {
+            let mut state = self.write_state();
+            state.listeners.push(tx);
+            targets
+                .iter()
+                .flat_map(|target| self.target_snapshot_responses(&state, target))
+                .collect()
+        };
+
+        let mut live_targets: Vec<FirestoreMemoryListenTarget> =
+            targets.into_iter().filter(|target| !target.once).collect();
+
+        let db = self.clone();
+        let events = futures::stream::select(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(target_changes)
+                .map(FirestoreMemoryListenEvent::TargetChange),
+            tokio_stream::wrappers::UnboundedReceiverStream::new(rx)
+                .map(FirestoreMemoryListenEvent::Changes),
+        );
+
+        let changes_stream = events.flat_map(move |event| {
+            futures::stream::iter(match event {
+                FirestoreMemoryListenEvent::Changes(change_set) => {
+                    FirestoreMemoryDb::change_set_to_responses(
+                        db.get_documents_path(),
+                        &live_targets,
+                        &change_set,
+                    )
+                }
+                FirestoreMemoryListenEvent::TargetChange(target_change) => {
+                    db.apply_target_change(&mut live_targets, target_change)
+                }
+            })
+        });
+
+        Ok(futures::stream::iter(initial_responses)
+            .chain(changes_stream)
+            .map(Ok)
+            .boxed())
+    }
+}
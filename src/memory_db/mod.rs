@@ -0,0 +1,566 @@
+//! An in-memory implementation of the Firestore support traits.
+//!
+//! Documents are kept in the process memory, so it is intended for unit tests
+//! and local development where running the Firestore emulator isn't convenient.
+//! Available with the `memory-db` feature.
+//!
+//! No history of the documents is kept, so the point-in-time reads return an unsupported operation error.
+
+mod values;
+
+mod query_eval;
+
+mod aggregated_query;
+mod create;
+mod delete;
+mod get;
+mod list;
+mod listen_changes;
+mod query;
+mod update;
+mod write;
+
+use crate::db::safe_document_path;
+use crate::errors::*;
+use crate::fluent_api::FirestoreExprBuilder;
+use crate::{FirestoreDbOptions, FirestoreResult, ParentPathBuilder};
+use chrono::prelude::*;
+use gcloud_sdk::google::firestore::v1::*;
+use std::collections::BTreeMap;
+use std::fmt::Formatter;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::mpsc::UnboundedSender;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FirestoreMemoryDocumentChange {
+    pub name: String,
+    pub old_document: Option<Document>,
+    pub new_document: Option<Document>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FirestoreMemoryChangeSet {
+    pub version: u64,
+    pub commit_time: DateTime<Utc>,
+    pub changes: Vec<FirestoreMemoryDocumentChange>,
+}
+
+struct FirestoreMemoryDbState {
+    documents: BTreeMap<String, Document>,
+    version: u64,
+    last_commit_time: Option<DateTime<Utc>>,
+    listeners: Vec<UnboundedSender<Arc<FirestoreMemoryChangeSet>>>,
+}
+
+struct FirestoreMemoryDbInner {
+    database_path: String,
+    doc_path: String,
+    state: RwLock<FirestoreMemoryDbState>,
+}
+
+#[derive(Clone)]
+pub struct FirestoreMemoryDb {
+    inner: Arc<FirestoreMemoryDbInner>,
+}
+
+impl FirestoreMemoryDb {
+    pub fn new<S>(google_project_id: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self::with_options(FirestoreDbOptions::new(
+            google_project_id.as_ref().to_string(),
+        ))
+    }
+
+    /// Only the project and the database IDs of the options are used,
+    /// so the document names match the ones of `FirestoreDb` with the same options.
+    pub fn with_options(options: FirestoreDbOptions) -> Self {
+        let database_path = format!(
+            "projects/{}/databases/{}",
+            options.google_project_id, options.database_id
+        );
+        let doc_path = format!("{database_path}/documents");
+
+        Self {
+            inner: Arc::new(FirestoreMemoryDbInner {
+                database_path,
+                doc_path,
+                state: RwLock::new(FirestoreMemoryDbState {
+                    documents: BTreeMap::new(),
+                    version: 0,
+                    last_commit_time: None,
+                    listeners: Vec::new(),
+                }),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn get_database_path(&self) -> &String {
+        &self.inner.database_path
+    }
+
+    #[inline]
+    pub fn get_documents_path(&self) -> &String {
+        &self.inner.doc_path
+    }
+
+    #[inline]
+    pub fn parent_path<S>(
+        &self,
+        parent_collection_name: &str,
+        parent_document_id: S,
+    ) -> FirestoreResult<ParentPathBuilder>
+    where
+        S: AsRef<str>,
+    {
+        Ok(ParentPathBuilder::new(safe_document_path(
+            self.inner.doc_path.as_str(),
+            parent_collection_name,
+            parent_document_id.as_ref(),
+        )?))
+    }
+
+    #[inline]
+    pub fn fluent(&self) -> FirestoreExprBuilder<'_, FirestoreMemoryDb> {
+        FirestoreExprBuilder::new(self)
+    }
+
+    /// Removes all documents from the database.
+    pub fn clear(&self) {
+        let mut state = self.write_state();
+        let documents = std::mem::take(&mut state.documents);
+        let changes = documents
+            .into_iter()
+            .map(|(name, document)| FirestoreMemoryDocumentChange {
+                name,
+                old_document: Some(document),
+                new_document: None,
+            })
+            .collect();
+        let commit_time = Self::next_commit_time(&mut state);
+        Self::notify_listeners(&mut state, commit_time, changes);
+    }
+
+    fn read_state(&self) -> RwLockReadGuard<'_, FirestoreMemoryDbState> {
+        // The state is always consistent, since the writes are applied only after all checks
+        self.inner
+            .state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_state(&self) -> RwLockWriteGuard<'_, FirestoreMemoryDbState> {
+        self.inner
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Commit timestamps are strictly increasing and microsecond aligned as in Firestore
+    fn next_commit_time(state: &mut FirestoreMemoryDbState) -> DateTime<Utc> {
+        let now = Utc::now();
+        let now = now
+            .with_nanosecond(now.nanosecond() / 1000 * 1000)
+            .unwrap_or(now);
+        let commit_time = match state.last_commit_time {
+            Some(last_commit_time) if last_commit_time >= now => {
+                last_commit_time + chrono::Duration::microseconds(1)
+            }
+            _ => now,
+        };
+        state.last_commit_time = Some(commit_time);
+        commit_time
+    }
+
+    fn notify_listeners(
+        state: &mut FirestoreMemoryDbState,
+        commit_time: DateTime<Utc>,
+        changes: Vec<FirestoreMemoryDocumentChange>,
+    ) {
+        state.version += 1;
+        let change_set = Arc::new(FirestoreMemoryChangeSet {
+            version: state.version,
+            commit_time,
+            changes,
+        });
+        state
+            .listeners
+            .retain(|listener| listener.send(change_set.clone()).is_ok());
+    }
+
+    pub(crate) fn document_not_found_error(document_path: &str) -> FirestoreError {
+        FirestoreError::from(tonic::Status::not_found(format!(
+            "Document not found: {document_path}"
+        )))
+    }
+
+    pub(crate) fn check_read_time(read_time: Option<DateTime<Utc>>) -> FirestoreResult<()> {
+        match read_time {
+            Some(_) => Err(unsupported_operation_error("Reading at a read time")),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn invalid_parameters_error(field: &str, error: String) -> FirestoreError {
+        FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+            FirestoreInvalidParametersPublicDetails::new(field.to_string(), error),
+        ))
+    }
+}
+
+impl std::fmt::Debug for FirestoreMemoryDb {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirestoreMemoryDb")
+            .field("database_path", &self.inner.database_path)
+            .field("doc_path", &self.inner.doc_path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::*;
+    use futures::StreamExt;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub(crate) struct TestStructure {
+        pub some_id: String,
+        pub some_string: String,
+        pub some_num: u64,
+    }
+
+    pub(crate) const TEST_COLLECTION_NAME: &str = "test";
+
+    /// Inserts the documents `test-0`..`test-4` with the numbers 0..4, which are odd or even
+    pub(crate) async fn populate_db(db: &FirestoreMemoryDb) -> FirestoreResult<Vec<TestStructure>> {
+        let mut inserted = Vec::new();
+        for i in 0..5 {
+            let obj = TestStructure {
+                some_id: format!("test-{i}"),
+                some_string: if i % 2 == 0 { "even" } else { "odd" }.to_string(),
+                some_num: i,
+            };
+            let created: TestStructure = db
+                .fluent()
+                .insert()
+                .into(TEST_COLLECTION_NAME)
+                .document_id(&obj.some_id)
+                .object(&obj)
+                .execute()
+                .await?;
+            inserted.push(created);
+        }
+        Ok(inserted)
+    }
+
+    #[tokio::test]
+    async fn crud_operations_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let duplicate: FirestoreResult<TestStructure> = db
+            .fluent()
+            .insert()
+            .into(TEST_COLLECTION_NAME)
+            .document_id(&inserted[0].some_id)
+            .object(&inserted[0])
+            .execute()
+            .await;
+        assert!(matches!(
+            duplicate,
+            Err(FirestoreError::DataConflictError(_))
+        ));
+
+        let updated: TestStructure = db
+            .fluent()
+            .update()
+            .fields(paths!(TestStructure::{some_num}))
+            .in_col(TEST_COLLECTION_NAME)
+            .document_id(&inserted[0].some_id)
+            .object(&TestStructure {
+                some_string: "ignored".to_string(),
+                some_num: 42,
+                ..inserted[0].clone()
+            })
+            .execute()
+            .await?;
+        assert_eq!(updated.some_num, 42);
+        assert_eq!(updated.some_string, inserted[0].some_string);
+
+        db.fluent()
+            .delete()
+            .from(TEST_COLLECTION_NAME)
+            .document_id(&inserted[1].some_id)
+            .execute()
+            .await?;
+
+        let found: Option<TestStructure> = db
+            .fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .obj()
+            .one(&inserted[1].some_id)
+            .await?;
+        assert_eq!(found, None);
+
+        let found: Option<TestStructure> = db
+            .fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .obj()
+            .one(&inserted[0].some_id)
+            .await?;
+        assert_eq!(found, Some(updated));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn named_database_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::with_options(
+            FirestoreDbOptions::new("test-project".to_string())
+                .with_database_id("test-database".to_string()),
+        );
+        populate_db(&db).await?;
+
+        let doc = db
+            .fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .one("test-1")
+            .await?
+            .unwrap();
+        assert_eq!(
+            doc.name,
+            "projects/test-project/databases/test-database/documents/test/test-1"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_time_unsupported_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+        let read_time = Utc::now();
+
+        let queried = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .read_time(read_time)
+            .query()
+            .await;
+        assert!(matches!(queried, Err(FirestoreError::SystemError(_))));
+
+        let found = db
+            .fluent()
+            .select()
+            .by_id_in(TEST_COLLECTION_NAME)
+            .read_time(read_time)
+            .one("test-1")
+            .await;
+        assert!(matches!(found, Err(FirestoreError::SystemError(_))));
+
+        let listed = db
+            .fluent()
+            .list()
+            .from(TEST_COLLECTION_NAME)
+            .read_time(read_time)
+            .get_page()
+            .await;
+        assert!(matches!(listed, Err(FirestoreError::SystemError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let results: Vec<TestStructure> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| {
+                q.for_all([
+                    q.field(path!(TestStructure::some_string)).eq("even"),
+                    q.field(path!(TestStructure::some_num)).greater_than(0),
+                ])
+            })
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Descending,
+            )])
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(results, vec![inserted[4].clone(), inserted[2].clone()]);
+
+        let results: Vec<TestStructure> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .order_by([(
+                path!(TestStructure::some_num),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .start_at(FirestoreQueryCursor::AfterValue(vec![1.into()]))
+            .limit(2)
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(results, vec![inserted[2].clone(), inserted[3].clone()]);
+
+        let results: Vec<TestStructure> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| {
+                q.for_any([
+                    q.field(path!(TestStructure::some_num)).eq(0),
+                    q.field(path!(TestStructure::some_string)).eq("odd"),
+                ])
+            })
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(
+            results,
+            vec![
+                inserted[0].clone(),
+                inserted[1].clone(),
+                inserted[3].clone()
+            ]
+        );
+
+        let counts = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_string)).eq("odd")]))
+            .aggregate(|a| a.fields([a.field("count").count()]))
+            .query()
+            .await?;
+        assert_eq!(
+            counts
+                .first()
+                .and_then(|doc| doc.fields.get("count"))
+                .cloned(),
+            Some(values::integer_value(2))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aggregations_test() -> FirestoreResult<()> {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct AggregationResult {
+            count: usize,
+            total: u64,
+            average: f64,
+        }
+
+        let db = FirestoreMemoryDb::new("test-project");
+        populate_db(&db).await?;
+
+        let results: Vec<AggregationResult> = db
+            .fluent()
+            .select()
+            .from(TEST_COLLECTION_NAME)
+            .filter(|q| q.for_all([q.field(path!(TestStructure::some_string)).eq("even")]))
+            .aggregate(|a| {
+                a.fields([
+                    a.field(path!(AggregationResult::count)).count(),
+                    a.field(path!(AggregationResult::total))
+                        .sum(path!(TestStructure::some_num)),
+                    a.field(path!(AggregationResult::average))
+                        .avg(path!(TestStructure::some_num)),
+                ])
+            })
+            .obj()
+            .query()
+            .await?;
+
+        assert_eq!(
+            results,
+            vec![AggregationResult {
+                count: 3,
+                total: 6,
+                average: 2.0,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_changes_test() -> FirestoreResult<()> {
+        let db = FirestoreMemoryDb::new("test-project");
+        let inserted = populate_db(&db).await?;
+
+        let mut listen_stream = db
+            .listen_doc_changes(vec![FirestoreListenerTargetParams::new(
+                FirestoreListenerTarget::new(1),
+                FirestoreTargetType::Query(
+                    FirestoreQueryParams::new(TEST_COLLECTION_NAME.into()).with_filter(
+                        FirestoreQueryFilter::Compare(Some(FirestoreQueryFilterCompare::Equal(
+                            path!(TestStructure::some_string),
+                            "odd".into(),
+                        ))),
+                    ),
+                ),
+                HashMap::new(),
+            )])
+            .await?;
+
+        let mut initial_docs = Vec::new();
+        while let Some(response) = listen_stream.next().await {
+            match response?.response_type {
+                Some(listen_response::ResponseType::DocumentChange(change)) => {
+                    initial_docs.extend(change.document)
+                }
+                Some(listen_response::ResponseType::TargetChange(target_change))
+                    if target_change.target_change_type
+                        == target_change::TargetChangeType::Current as i32 =>
+                {
+                    break
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(initial_docs.len(), 2);
+
+        db.fluent()
+            .delete()
+            .from(TEST_COLLECTION_NAME)
+            .document_id(&inserted[1].some_id)
+            .execute()
+            .await?;
+
+        let mut next_event = None;
+        while let Some(response) = listen_stream.next().await {
+            match response?.response_type {
+                Some(listen_response::ResponseType::TargetChange(_)) => {}
+                response_type => {
+                    next_event = response_type;
+                    break;
+                }
+            }
+        }
+        assert!(matches!(
+            next_event,
+            Some(listen_response::ResponseType::DocumentDelete(DocumentDelete { ref document, .. }))
+                if document.ends_with(&inserted[1].some_id)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::db::query_ordering::{limit_to_last_query, reject_limit_to_last};
+use crate::memory_db::query_eval::run_query;
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{
+    FirestoreDb, FirestorePartition, FirestorePartitionQueryParams, FirestoreQueryCursor,
+    FirestoreQueryParams, FirestoreQuerySupport, FirestoreResult, PeekableBoxStream,
+};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use futures::{future, StreamExt};
+use gcloud_sdk::google::firestore::v1::*;
+use serde::Deserialize;
+use tracing::*;
+
+impl FirestoreMemoryDb {
+    pub(crate) fn run_query_doc(&self, params: &FirestoreQueryParams) -> Vec<Document> {
+        let state = self.read_state();
+        run_query(
+            state.documents.values(),
+            params,
+            self.get_documents_path().as_str(),
+        )
+    }
+
+    fn query_doc_vec(&self, params: &FirestoreQueryParams) -> FirestoreResult<Vec<Document>> {
+        params.validate()?;
+        Self::check_read_time(params.read_time)?;
+        match limit_to_last_query(params)? {
+            Some(reversed_params) => {
+                let mut docs = self.run_query_doc(&reversed_params);
+                docs.reverse();
+                Ok(docs)
+            }
+            None => Ok(self.run_query_doc(params)),
+        }
+    }
+}
+
+#[async_trait]
+impl FirestoreQuerySupport for FirestoreMemoryDb {
+    async fn query_doc(&self, params: FirestoreQueryParams) -> FirestoreResult<Vec<Document>> {
+        self.query_doc_vec(&params)
+    }
+
+    async fn stream_query_doc<'b>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, Document>> {
+        Ok(futures::stream::iter(self.query_doc_vec(&params)?).boxed())
+    }
+
+    async fn stream_query_doc_with_errors<'b>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        Ok(futures::stream::iter(self.query_doc_vec(&params)?.into_iter().map(Ok)).boxed())
+    }
+
+    async fn query_obj<T>(&self, params: FirestoreQueryParams) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_vec = self.query_doc(params).await?;
+        doc_vec
+            .iter()
+            .map(|doc| FirestoreDb::deserialize_doc_to(doc))
+            .collect()
+    }
+
+    async fn stream_query_obj<'b, T>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_stream = self.stream_query_doc(params).await?;
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        "[MemoryDB] Error occurred while consuming query document as a stream: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_query_obj_with_errors<'b, T>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_query_doc_with_errors(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc))
+        })))
+    }
+
+    fn stream_partition_cursors_with_errors(
+        &self,
+        params: FirestorePartitionQueryParams,
+    ) -> BoxFuture<'_, FirestoreResult<PeekableBoxStream<'_, FirestoreResult<FirestoreQueryCursor>>>>
+    {
+        // The in-memory database doesn't partition queries, so it behaves as Firestore does for small queries
+        Box::pin(async move {
+            params.query_params.validate()?;
+            reject_limit_to_last(&params.query_params, "partition queries")?;
+            Self::check_read_time(params.query_params.read_time)?;
+            Ok(futures::stream::empty().boxed().peekable())
+        })
+    }
+
+    async fn stream_partition_query_doc_with_errors(
+        &self,
+        _parallelism: usize,
+        partition_params: FirestorePartitionQueryParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(FirestorePartition, Document)>>> {
+        reject_limit_to_last(&partition_params.query_params, "partition queries")?;
+        let doc_stream = self
+            .stream_query_doc_with_errors(partition_params.query_params)
+            .await?;
+
+        Ok(doc_stream
+            .and_then(|doc| future::ready(Ok((FirestorePartition::new(), doc))))
+            .boxed())
+    }
+
+    async fn stream_partition_query_obj_with_errors<'a, T>(
+        &'a self,
+        parallelism: usize,
+        partition_params: FirestorePartitionQueryParams,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(FirestorePartition, T)>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'a,
+    {
+        let doc_stream = self
+            .stream_partition_query_doc_with_errors(parallelism, partition_params)
+            .await?;
+
+        Ok(Box::pin(doc_stream.and_then(|(partition, doc)| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc).map(|obj| (partition, obj)))
+        })))
+    }
+}
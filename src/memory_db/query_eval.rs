@@ -0,0 +1,236 @@
+use crate::db::query_ordering::{
+    compare_with_direction, document_order_values, effective_order_by,
+};
+use crate::memory_db::values::*;
+use crate::{
+    FirestoreQueryCollection, FirestoreQueryCursor, FirestoreQueryFilter,
+    FirestoreQueryFilterCompare, FirestoreQueryFilterCompositeOperator, FirestoreQueryFilterUnary,
+    FirestoreQueryOrder, FirestoreQueryParams,
+};
+use gcloud_sdk::google::firestore::v1::{Document, Value};
+use std::cmp::Ordering;
+
+pub(crate) fn document_in_collection(
+    document_name: &str,
+    parent: &str,
+    collection_ids: &[String],
+    all_descendants: bool,
+) -> bool {
+    let relative_path = match document_name
+        .strip_prefix(parent)
+        .and_then(|path| path.strip_prefix('/'))
+    {
+        Some(relative_path) => relative_path,
+        None => return false,
+    };
+
+    let segments: Vec<&str> = relative_path.split('/').collect();
+    if segments.len() < 2 || segments.len() % 2 != 0 || (!all_descendants && segments.len() != 2) {
+        return false;
+    }
+
+    let collection_id = segments[segments.len() - 2];
+    collection_ids.iter().any(|id| id == collection_id)
+}
+
+fn query_collection_ids(collection: &FirestoreQueryCollection) -> Vec<String> {
+    match collection {
+        FirestoreQueryCollection::Single(collection_id) => vec![collection_id.clone()],
+        FirestoreQueryCollection::Group(collection_ids) => collection_ids.clone(),
+    }
+}
+
+fn compare_filter_matches(compare: &FirestoreQueryFilterCompare, document: &Document) -> bool {
+    let field_value = |field_name: &str| get_document_field(document, field_name);
+    let range_matches = |field_name: &str, value: &Value, expected: &[Ordering]| {
+        field_value(field_name)
+            .and_then(|fv| compare_comparable(&fv, value))
+            .map(|ordering| expected.contains(&ordering))
+            .unwrap_or(false)
+    };
+
+    match compare {
+        FirestoreQueryFilterCompare::LessThan(field_name, value) => {
+            range_matches(field_name, &value.value, &[Ordering::Less])
+        }
+        FirestoreQueryFilterCompare::LessThanOrEqual(field_name, value) => {
+            range_matches(field_name, &value.value, &[Ordering::Less, Ordering::Equal])
+        }
+        FirestoreQueryFilterCompare::GreaterThan(field_name, value) => {
+            range_matches(field_name, &value.value, &[Ordering::Greater])
+        }
+        FirestoreQueryFilterCompare::GreaterThanOrEqual(field_name, value) => range_matches(
+            field_name,
+            &value.value,
+            &[Ordering::Greater, Ordering::Equal],
+        ),
+        FirestoreQueryFilterCompare::Equal(field_name, value) => field_value(field_name)
+            .map(|fv| values_equal(&fv, &value.value))
+            .unwrap_or(false),
+        FirestoreQueryFilterCompare::NotEqual(field_name, value) => field_value(field_name)
+            .map(|fv| !is_null(&fv) && !values_equal(&fv, &value.value))
+            .unwrap_or(false),
+        FirestoreQueryFilterCompare::ArrayContains(field_name, value) => field_value(field_name)
+            .and_then(|fv| {
+                array_values(&fv).map(|values| values.iter().any(|v| values_equal(v, &value.value)))
+            })
+            .unwrap_or(false),
+        FirestoreQueryFilterCompare::In(field_name, value) => {
+            match (field_value(field_name), array_values(&value.value)) {
+                (Some(fv), Some(values)) => values.iter().any(|v| values_equal(v, &fv)),
+                _ => false,
+            }
+        }
+        FirestoreQueryFilterCompare::ArrayContainsAny(field_name, value) => {
+            match (field_value(field_name), array_values(&value.value)) {
+                (Some(fv), Some(values)) => array_values(&fv)
+                    .map(|field_values| {
+                        field_values
+                            .iter()
+                            .any(|fv| values.iter().any(|v| values_equal(v, fv)))
+                    })
+                    .unwrap_or(false),
+                _ => false,
+            }
+        }
+        FirestoreQueryFilterCompare::NotIn(field_name, value) => {
+            match (field_value(field_name), array_values(&value.value)) {
+                (Some(fv), Some(values)) => {
+                    !is_null(&fv) && !values.iter().any(|v| values_equal(v, &fv))
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+pub(crate) fn filter_matches(filter: &FirestoreQueryFilter, document: &Document) -> bool {
+    match filter {
+        FirestoreQueryFilter::Composite(composite) => match composite.operator {
+            FirestoreQueryFilterCompositeOperator::And => composite
+                .for_all_filters
+                .iter()
+                .all(|filter| filter_matches(filter, document)),
+            FirestoreQueryFilterCompositeOperator::Or => composite
+                .for_all_filters
+                .iter()
+                .any(|filter| filter_matches(filter, document)),
+        },
+        FirestoreQueryFilter::Unary(FirestoreQueryFilterUnary::IsNan(field_name)) => {
+            get_document_field(document, field_name)
+                .map(|fv| is_nan(&fv))
+                .unwrap_or(false)
+        }
+        FirestoreQueryFilter::Unary(FirestoreQueryFilterUnary::IsNull(field_name)) => {
+            get_document_field(document, field_name)
+                .map(|fv| is_null(&fv))
+                .unwrap_or(false)
+        }
+        FirestoreQueryFilter::Unary(FirestoreQueryFilterUnary::IsNotNan(field_name)) => {
+            get_document_field(document, field_name)
+                .map(|fv| !is_nan(&fv) && !is_null(&fv))
+                .unwrap_or(false)
+        }
+        FirestoreQueryFilter::Unary(FirestoreQueryFilterUnary::IsNotNull(field_name)) => {
+            get_document_field(document, field_name)
+                .map(|fv| !is_null(&fv))
+                .unwrap_or(false)
+        }
+        FirestoreQueryFilter::Compare(Some(compare)) => compare_filter_matches(compare, document),
+        FirestoreQueryFilter::Compare(None) => true,
+    }
+}
+
+fn cursor_values(cursor: &FirestoreQueryCursor) -> Vec<Value> {
+    match cursor {
+        FirestoreQueryCursor::BeforeValue(values) | FirestoreQueryCursor::AfterValue(values) => {
+            values.iter().map(|v| v.value.clone()).collect()
+        }
+    }
+}
+
+fn after_start_cursor(
+    order_values: &[Value],
+    cursor: &FirestoreQueryCursor,
+    order_by: &[FirestoreQueryOrder],
+) -> bool {
+    let position = compare_with_direction(order_values, &cursor_values(cursor), order_by);
+    match cursor {
+        FirestoreQueryCursor::BeforeValue(_) => position != Ordering::Less,
+        FirestoreQueryCursor::AfterValue(_) => position == Ordering::Greater,
+    }
+}
+
+fn before_end_cursor(
+    order_values: &[Value],
+    cursor: &FirestoreQueryCursor,
+    order_by: &[FirestoreQueryOrder],
+) -> bool {
+    let position = compare_with_direction(order_values, &cursor_values(cursor), order_by);
+    match cursor {
+        FirestoreQueryCursor::BeforeValue(_) => position == Ordering::Less,
+        FirestoreQueryCursor::AfterValue(_) => position != Ordering::Greater,
+    }
+}
+
+/// Checks if a document belongs to the query ignoring ordering, cursors and limits.
+pub(crate) fn query_matches(
+    params: &FirestoreQueryParams,
+    default_parent: &str,
+    document: &Document,
+) -> bool {
+    document_in_collection(
+        &document.name,
+        params.parent.as_deref().unwrap_or(default_parent),
+        &query_collection_ids(&params.collection_id),
+        params.all_descendants.unwrap_or(false),
+    ) && params
+        .filter
+        .as_ref()
+        .map(|filter| filter_matches(filter, document))
+        .unwrap_or(true)
+}
+
+pub(crate) fn run_query<'a, I>(
+    documents: I,
+    params: &FirestoreQueryParams,
+    default_parent: &str,
+) -> Vec<Document>
+where
+    I: Iterator<Item = &'a Document>,
+{
+    let order_by = effective_order_by(params);
+
+    let mut matched: Vec<(Vec<Value>, &Document)> = documents
+        .filter(|document| query_matches(params, default_parent, document))
+        .filter_map(|document| {
+            document_order_values(document, &order_by).map(|order_values| (order_values, document))
+        })
+        .collect();
+
+    matched.sort_by(|(left, _), (right, _)| compare_with_direction(left, right, &order_by));
+
+    matched
+        .into_iter()
+        .filter(|(order_values, _)| {
+            params
+                .start_at
+                .as_ref()
+                .map(|cursor| after_start_cursor(order_values, cursor, &order_by))
+                .unwrap_or(true)
+                && params
+                    .end_at
+                    .as_ref()
+                    .map(|cursor| before_end_cursor(order_values, cursor, &order_by))
+                    .unwrap_or(true)
+        })
+        .skip(params.offset.unwrap_or(0) as usize)
+        .take(
+            params
+                .limit
+                .map(|limit| limit as usize)
+                .unwrap_or(usize::MAX),
+        )
+        .map(|(_, document)| project_document(document.clone(), params.return_only_fields.as_ref()))
+        .collect()
+}
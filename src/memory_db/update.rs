@@ -0,0 +1,93 @@
+use crate::db::safe_document_path;
+use crate::memory_db::values::project_document;
+use crate::memory_db::FirestoreMemoryDb;
+use crate::{FirestoreDb, FirestoreResult, FirestoreUpdateSupport, FirestoreWritePrecondition};
+use async_trait::async_trait;
+use gcloud_sdk::google::firestore::v1::*;
+use serde::{Deserialize, Serialize};
+
+#[async_trait]
+impl FirestoreUpdateSupport for FirestoreMemoryDb {
+    async fn update_obj<I, O, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        obj: &I,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.update_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            obj,
+            update_only,
+            return_only_fields,
+            precondition,
+        )
+        .await
+    }
+
+    async fn update_obj_at<I, O, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        obj: &I,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let firestore_doc = FirestoreDb::serialize_to_doc(
+            safe_document_path(parent, collection_id, document_id.as_ref())?.as_str(),
+            obj,
+        )?;
+
+        let doc = self
+            .update_doc(
+                collection_id,
+                firestore_doc,
+                update_only,
+                return_only_fields,
+                precondition,
+            )
+            .await?;
+
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+
+    async fn update_doc(
+        &self,
+        _collection_id: &str,
+        firestore_doc: Document,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<Document> {
+        let write = Write {
+            update_mask: update_only.map(|field_paths| DocumentMask { field_paths }),
+            update_transforms: vec![],
+            current_document: precondition.map(|cond| cond.try_into()).transpose()?,
+            operation: Some(write::Operation::Update(firestore_doc)),
+        };
+
+        let updated_doc = self
+            .commit_writes(vec![write])?
+            .pop()
+            .and_then(|outcome| outcome.document)
+            .unwrap_or_default();
+
+        Ok(project_document(updated_doc, return_only_fields.as_ref()))
+    }
+}
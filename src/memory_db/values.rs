@@ -0,0 +1,147 @@
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use gcloud_sdk::google::firestore::v1::*;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub(crate) use crate::db::query_ordering::{
+    compare_values, get_document_field, get_field, parse_field_path, type_order,
+};
+
+pub(crate) fn values_equal(left: &Value, right: &Value) -> bool {
+    compare_values(left, right) == Ordering::Equal
+}
+
+// Range filters only match values of the same type (numbers are a single type) and never NaN
+pub(crate) fn compare_comparable(left: &Value, right: &Value) -> Option<Ordering> {
+    if type_order(left) != type_order(right) || is_nan(left) || is_nan(right) {
+        None
+    } else {
+        Some(compare_values(left, right))
+    }
+}
+
+pub(crate) fn is_null(value: &Value) -> bool {
+    matches!(value.value_type, None | Some(ValueType::NullValue(_)))
+}
+
+pub(crate) fn is_nan(value: &Value) -> bool {
+    matches!(value.value_type, Some(ValueType::DoubleValue(v)) if v.is_nan())
+}
+
+pub(crate) fn array_values(value: &Value) -> Option<&Vec<Value>> {
+    match &value.value_type {
+        Some(ValueType::ArrayValue(array)) => Some(&array.values),
+        _ => None,
+    }
+}
+
+pub(crate) fn integer_value(value: i64) -> Value {
+    Value {
+        value_type: Some(ValueType::IntegerValue(value)),
+    }
+}
+
+pub(crate) fn array_value(values: Vec<Value>) -> Value {
+    Value {
+        value_type: Some(ValueType::ArrayValue(ArrayValue { values })),
+    }
+}
+
+pub(crate) fn set_field(fields: &mut HashMap<String, Value>, field_path: &str, value: Value) {
+    let segments = parse_field_path(field_path);
+    set_field_segments(fields, &segments, value)
+}
+
+fn set_field_segments(fields: &mut HashMap<String, Value>, segments: &[String], value: Value) {
+    match segments.split_first() {
+        Some((last, [])) => {
+            fields.insert(last.clone(), value);
+        }
+        Some((head, rest)) => {
+            let entry = fields.entry(head.clone()).or_insert_with(|| Value {
+                value_type: Some(ValueType::MapValue(MapValue::default())),
+            });
+            if !matches!(entry.value_type, Some(ValueType::MapValue(_))) {
+                entry.value_type = Some(ValueType::MapValue(MapValue::default()));
+            }
+            if let Some(ValueType::MapValue(map)) = &mut entry.value_type {
+                set_field_segments(&mut map.fields, rest, value)
+            }
+        }
+        None => {}
+    }
+}
+
+pub(crate) fn remove_field(fields: &mut HashMap<String, Value>, field_path: &str) {
+    let segments = parse_field_path(field_path);
+    remove_field_segments(fields, &segments)
+}
+
+fn remove_field_segments(fields: &mut HashMap<String, Value>, segments: &[String]) {
+    match segments.split_first() {
+        Some((last, [])) => {
+            fields.remove(last);
+        }
+        Some((head, rest)) => {
+            if let Some(Value {
+                value_type: Some(ValueType::MapValue(map)),
+            }) = fields.get_mut(head)
+            {
+                remove_field_segments(&mut map.fields, rest)
+            }
+        }
+        None => {}
+    }
+}
+
+pub(crate) fn project_fields(
+    fields: &HashMap<String, Value>,
+    field_paths: &[String],
+) -> HashMap<String, Value> {
+    let mut projected = HashMap::new();
+    for field_path in field_paths {
+        if let Some(value) = get_field(fields, field_path) {
+            set_field(&mut projected, field_path, value.clone());
+        }
+    }
+    projected
+}
+
+pub(crate) fn project_document(document: Document, field_paths: Option<&Vec<String>>) -> Document {
+    match field_paths {
+        Some(field_paths) => Document {
+            fields: project_fields(&document.fields, field_paths),
+            ..document
+        },
+        None => document,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_values() {
+        let int_value = integer_value(1);
+        let double_value = Value {
+            value_type: Some(ValueType::DoubleValue(1.0)),
+        };
+        let nan_value = Value {
+            value_type: Some(ValueType::DoubleValue(f64::NAN)),
+        };
+        let str_value = Value {
+            value_type: Some(ValueType::StringValue("a".into())),
+        };
+        let null_value = Value {
+            value_type: Some(ValueType::NullValue(0)),
+        };
+
+        assert!(values_equal(&int_value, &double_value));
+        assert_eq!(compare_values(&nan_value, &int_value), Ordering::Less);
+        assert_eq!(compare_values(&null_value, &nan_value), Ordering::Less);
+        assert_eq!(compare_values(&int_value, &str_value), Ordering::Less);
+        assert_eq!(compare_comparable(&int_value, &str_value), None);
+        assert_eq!(compare_comparable(&nan_value, &int_value), None);
+    }
+}
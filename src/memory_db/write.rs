@@ -0,0 +1,417 @@
+use crate::memory_db::values::{
+    array_value, array_values, compare_comparable, get_field, remove_field, set_field, values_equal,
+};
+use crate::memory_db::{FirestoreMemoryDb, FirestoreMemoryDbState, FirestoreMemoryDocumentChange};
+use crate::timestamp_utils::{from_timestamp, to_timestamp};
+use crate::{
+    FirestoreBatchWriteResponse, FirestoreBatchWriter, FirestoreError, FirestoreQueryParams,
+    FirestoreQueryUpdate, FirestoreQueryWriteOperation, FirestoreQueryWriteResult,
+    FirestoreQueryWriteSupport, FirestoreResult, FirestoreValue, FirestoreWriteResult,
+};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use gcloud_sdk::google::firestore::v1::document_transform::field_transform::{
+    ServerValue, TransformType,
+};
+use gcloud_sdk::google::firestore::v1::document_transform::FieldTransform;
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use gcloud_sdk::google::firestore::v1::*;
+use std::collections::HashMap;
+use tracing::debug;
+
+pub(crate) struct FirestoreMemoryWriteOutcome {
+    pub write_result: FirestoreWriteResult,
+    pub document: Option<Document>,
+}
+
+impl FirestoreMemoryDb {
+    /// Applies the writes atomically: either all of them are applied or none.
+    pub async fn commit(&self, writes: Vec<Write>) -> FirestoreResult<Vec<FirestoreWriteResult>> {
+        Ok(self
+            .commit_writes(writes)?
+            .into_iter()
+            .map(|outcome| outcome.write_result)
+            .collect())
+    }
+
+    /// Writes every document matching the query separately, as the bulk writer does.
+    pub(crate) fn write_by_query(
+        &self,
+        params: &FirestoreQueryParams,
+        operation: FirestoreQueryWriteOperation,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        let mut result = FirestoreQueryWriteResult::new(0, 0);
+        for document in self.run_query_doc(params) {
+            let write = operation.to_write(&document, update_time_precondition)?;
+            match self.commit_writes(vec![write]) {
+                Ok(_) => result.affected_count += 1,
+                Err(_) => result.failed_count += 1,
+            }
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn commit_writes(
+        &self,
+        writes: Vec<Write>,
+    ) -> FirestoreResult<Vec<FirestoreMemoryWriteOutcome>> {
+        let mut state = self.write_state();
+        let commit_time = FirestoreMemoryDb::next_commit_time(&mut state);
+
+        let mut staged: HashMap<String, Option<Document>> = HashMap::new();
+        let mut staged_order: Vec<String> = Vec::new();
+        let mut outcomes = Vec::with_capacity(writes.len());
+
+        for write in writes {
+            let (document_name, outcome) = self.apply_write(&state, &staged, write, commit_time)?;
+            if !staged.contains_key(&document_name) {
+                staged_order.push(document_name.clone());
+            }
+            staged.insert(document_name, outcome.document.clone());
+            outcomes.push(outcome);
+        }
+
+        let changes: Vec<FirestoreMemoryDocumentChange> = staged_order
+            .into_iter()
+            .map(|name| {
+                let new_document = staged.remove(&name).flatten();
+                let old_document = match &new_document {
+                    Some(document) => state.documents.insert(name.clone(), document.clone()),
+                    None => state.documents.remove(&name),
+                };
+                FirestoreMemoryDocumentChange {
+                    name,
+                    old_document,
+                    new_document,
+                }
+            })
+            .collect();
+
+        debug!(
+            "[MemoryDB]: Committed {} writes at {}",
+            outcomes.len(),
+            commit_time
+        );
+
+        FirestoreMemoryDb::notify_listeners(&mut state, commit_time, changes);
+
+        Ok(outcomes)
+    }
+
+    fn apply_write(
+        &self,
+        state: &FirestoreMemoryDbState,
+        staged: &HashMap<String, Option<Document>>,
+        write: Write,
+        commit_time: DateTime<Utc>,
+    ) -> FirestoreResult<(String, FirestoreMemoryWriteOutcome)> {
+        let document_name = match &write.operation {
+            Some(write::Operation::Update(document)) => document.name.clone(),
+            Some(write::Operation::Delete(document_name)) => document_name.clone(),
+            Some(write::Operation::Transform(transform)) => transform.document.clone(),
+            None => {
+                return Err(FirestoreMemoryDb::invalid_parameters_error(
+                    "operation",
+                    "Write operation is not specified".to_string(),
+                ))
+            }
+        };
+
+        if !document_name.starts_with(self.get_documents_path().as_str()) {
+            return Err(FirestoreMemoryDb::invalid_parameters_error(
+                "document",
+                format!("Invalid document path: {document_name}"),
+            ));
+        }
+
+        let current_document = match staged.get(&document_name) {
+            Some(staged_document) => staged_document.clone(),
+            None => state.documents.get(&document_name).cloned(),
+        };
+
+        if let Some(precondition) = &write.current_document {
+            Self::check_precondition(&document_name, current_document.as_ref(), precondition)?;
+        }
+
+        let commit_timestamp = to_timestamp(commit_time);
+
+        let (new_document, field_transforms) = match write.operation {
+            Some(write::Operation::Update(document)) => {
+                let fields = match (&write.update_mask, &current_document) {
+                    (Some(update_mask), current) => {
+                        let mut fields = current
+                            .as_ref()
+                            .map(|current| current.fields.clone())
+                            .unwrap_or_default();
+                        for field_path in &update_mask.field_paths {
+                            match get_field(&document.fields, field_path) {
+                                Some(value) => set_field(&mut fields, field_path, value.clone()),
+                                None => remove_field(&mut fields, field_path),
+                            }
+                        }
+                        fields
+                    }
+                    (None, _) => document.fields,
+                };
+                (
+                    Some(Document {
+                        name: document_name.clone(),
+                        fields,
+                        create_time: current_document
+                            .as_ref()
+                            .and_then(|current| current.create_time.clone())
+                            .or_else(|| Some(commit_timestamp.clone())),
+                        update_time: Some(commit_timestamp.clone()),
+                    }),
+                    write.update_transforms,
+                )
+            }
+            Some(write::Operation::Transform(transform)) => (
+                Some(Document {
+                    name: document_name.clone(),
+                    fields: current_document
+                        .as_ref()
+                        .map(|current| current.fields.clone())
+                        .unwrap_or_default(),
+                    create_time: current_document
+                        .as_ref()
+                        .and_then(|current| current.create_time.clone())
+                        .or_else(|| Some(commit_timestamp.clone())),
+                    update_time: Some(commit_timestamp.clone()),
+                }),
+                transform.field_transforms,
+            ),
+            _ => (None, write.update_transforms),
+        };
+
+        let (new_document, transform_results) = match new_document {
+            Some(mut document) => {
+                let transform_results = field_transforms
+                    .into_iter()
+                    .map(|field_transform| {
+                        Self::apply_field_transform(
+                            &mut document.fields,
+                            field_transform,
+                            &commit_timestamp,
+                        )
+                    })
+                    .collect::<FirestoreResult<Vec<Value>>>()?;
+                (Some(document), transform_results)
+            }
+            None => (None, Vec::new()),
+        };
+
+        Ok((
+            document_name,
+            FirestoreMemoryWriteOutcome {
+                write_result: FirestoreWriteResult::new(
+                    transform_results
+                        .into_iter()
+                        .map(FirestoreValue::from)
+                        .collect(),
+                )
+                .with_update_time(commit_time),
+                document: new_document,
+            },
+        ))
+    }
+
+    fn check_precondition(
+        document_name: &str,
+        current_document: Option<&Document>,
+        precondition: &Precondition,
+    ) -> FirestoreResult<()> {
+        match &precondition.condition_type {
+            Some(precondition::ConditionType::Exists(true)) if current_document.is_none() => {
+                Err(FirestoreMemoryDb::document_not_found_error(document_name))
+            }
+            Some(precondition::ConditionType::Exists(false)) if current_document.is_some() => {
+                Err(FirestoreError::from(tonic::Status::already_exists(
+                    format!("Document already exists: {document_name}"),
+                )))
+            }
+            Some(precondition::ConditionType::UpdateTime(update_time)) => {
+                let expected_time = from_timestamp(update_time.clone())?;
+                let current_time = current_document
+                    .and_then(|document| document.update_time.clone())
+                    .map(from_timestamp)
+                    .transpose()?;
+                if current_time == Some(expected_time) {
+                    Ok(())
+                } else {
+                    Err(FirestoreError::from(tonic::Status::failed_precondition(
+                        format!("The document update time doesn't match: {document_name}"),
+                    )))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_field_transform(
+        fields: &mut HashMap<String, Value>,
+        field_transform: FieldTransform,
+        commit_timestamp: &prost_types::Timestamp,
+    ) -> FirestoreResult<Value> {
+        let field_path = field_transform.field_path.as_str();
+        let current_value = get_field(fields, field_path).cloned();
+        let null_value = Value {
+            value_type: Some(ValueType::NullValue(0)),
+        };
+
+        let (new_value, transform_result) = match field_transform.transform_type {
+            Some(TransformType::SetToServerValue(server_value))
+                if server_value == ServerValue::RequestTime as i32 =>
+            {
+                let value = Value {
+                    value_type: Some(ValueType::TimestampValue(commit_timestamp.clone())),
+                };
+                (value.clone(), value)
+            }
+            Some(TransformType::Increment(operand)) => {
+                let value = match (
+                    current_value.and_then(|v| v.value_type),
+                    operand.value_type.clone(),
+                ) {
+                    (
+                        Some(ValueType::IntegerValue(current)),
+                        Some(ValueType::IntegerValue(inc)),
+                    ) => ValueType::IntegerValue(current.saturating_add(inc)),
+                    (Some(ValueType::IntegerValue(current)), Some(ValueType::DoubleValue(inc))) => {
+                        ValueType::DoubleValue(current as f64 + inc)
+                    }
+                    (Some(ValueType::DoubleValue(current)), Some(ValueType::IntegerValue(inc))) => {
+                        ValueType::DoubleValue(current + inc as f64)
+                    }
+                    (Some(ValueType::DoubleValue(current)), Some(ValueType::DoubleValue(inc))) => {
+                        ValueType::DoubleValue(current + inc)
+                    }
+                    (_, Some(operand_value)) => operand_value,
+                    (_, None) => {
+                        return Err(FirestoreMemoryDb::invalid_parameters_error(
+                            field_path,
+                            "Increment operand is not specified".to_string(),
+                        ))
+                    }
+                };
+                let value = Value {
+                    value_type: Some(value),
+                };
+                (value.clone(), value)
+            }
+            Some(TransformType::Maximum(operand)) => {
+                let value = match current_value {
+                    Some(current)
+                        if compare_comparable(&current, &operand)
+                            .map(|ordering| ordering.is_ge())
+                            .unwrap_or(false) =>
+                    {
+                        current
+                    }
+                    _ => operand,
+                };
+                (value.clone(), value)
+            }
+            Some(TransformType::Minimum(operand)) => {
+                let value = match current_value {
+                    Some(current)
+                        if compare_comparable(&current, &operand)
+                            .map(|ordering| ordering.is_le())
+                            .unwrap_or(false) =>
+                    {
+                        current
+                    }
+                    _ => operand,
+                };
+                (value.clone(), value)
+            }
+            Some(TransformType::AppendMissingElements(elements)) => {
+                let mut values = current_value
+                    .as_ref()
+                    .and_then(array_values)
+                    .cloned()
+                    .unwrap_or_default();
+                for element in elements.values {
+                    if !values.iter().any(|v| values_equal(v, &element)) {
+                        values.push(element);
+                    }
+                }
+                (array_value(values), null_value)
+            }
+            Some(TransformType::RemoveAllFromArray(elements)) => {
+                let values = current_value
+                    .as_ref()
+                    .and_then(array_values)
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|v| !elements.values.iter().any(|e| values_equal(v, e)))
+                    .collect();
+                (array_value(values), null_value)
+            }
+            _ => {
+                return Err(FirestoreMemoryDb::invalid_parameters_error(
+                    field_path,
+                    "Unsupported field transform".to_string(),
+                ))
+            }
+        };
+
+        set_field(fields, field_path, new_value);
+        Ok(transform_result)
+    }
+}
+
+#[async_trait]
+impl FirestoreBatchWriter for FirestoreMemoryDb {
+    type WriteResult = FirestoreBatchWriteResponse;
+
+    async fn write(&self, writes: Vec<Write>) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        let write_results = self.commit(writes).await?;
+        let commit_time = write_results
+            .first()
+            .and_then(|write_result| write_result.update_time);
+
+        Ok(
+            FirestoreBatchWriteResponse::new(0, write_results, Vec::new())
+                .opt_commit_time(commit_time),
+        )
+    }
+
+    async fn write_with_keys(
+        &self,
+        writes: Vec<Write>,
+        write_keys: Vec<Option<String>>,
+    ) -> FirestoreResult<FirestoreBatchWriteResponse> {
+        Ok(self.write(writes).await?.with_write_keys(write_keys))
+    }
+}
+
+#[async_trait]
+impl FirestoreQueryWriteSupport for FirestoreMemoryDb {
+    async fn delete_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        self.write_by_query(
+            &params,
+            FirestoreQueryWriteOperation::Delete,
+            update_time_precondition,
+        )
+    }
+
+    async fn update_by_query(
+        &self,
+        params: FirestoreQueryParams,
+        update: FirestoreQueryUpdate,
+        update_time_precondition: bool,
+    ) -> FirestoreResult<FirestoreQueryWriteResult> {
+        self.write_by_query(
+            &params,
+            FirestoreQueryWriteOperation::Update(update),
+            update_time_precondition,
+        )
+    }
+}